@@ -0,0 +1,7 @@
+CREATE TABLE sessions (
+    -- two random uuids' worth, as it's all a client needs to carry on the conversation
+    token TEXT PRIMARY KEY DEFAULT replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', ''),
+    conversation_id UUID NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ended_at TIMESTAMPTZ
+);
@@ -0,0 +1,4 @@
+-- older messages get folded into the summary, which is sent in their place
+ALTER TABLE conversations
+    ADD COLUMN summary TEXT,
+    ADD COLUMN summarized_through BIGINT NOT NULL DEFAULT 0;
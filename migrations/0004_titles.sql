@@ -0,0 +1,4 @@
+ALTER TABLE conversations ADD COLUMN title TEXT;
+
+-- for listing the most recent conversations first
+CREATE INDEX conversations_updated_at_idx ON conversations (updated_at DESC);
@@ -0,0 +1,2 @@
+-- the generation parameters each reply was written with
+ALTER TABLE messages ADD COLUMN params JSONB;
@@ -0,0 +1,2 @@
+-- sent on every turn of the conversation, in place of the deployment's system prompt
+ALTER TABLE conversations ADD COLUMN system_prompt TEXT;
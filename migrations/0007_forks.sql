@@ -0,0 +1,2 @@
+ALTER TABLE conversations
+    ADD COLUMN forked_from UUID REFERENCES conversations (id) ON DELETE SET NULL;
@@ -0,0 +1,10 @@
+-- one rating per reply, which can be changed later
+CREATE TABLE feedback (
+    message_id BIGINT PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+    rating TEXT NOT NULL CHECK (rating IN ('up', 'down')),
+    comment TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX feedback_rating_idx ON feedback (rating, updated_at DESC);
@@ -0,0 +1,4 @@
+ALTER TABLE messages
+    ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (to_tsvector('english', content)) STORED;
+
+CREATE INDEX messages_search_idx ON messages USING GIN (search);
@@ -0,0 +1,7 @@
+ALTER TABLE conversations
+    ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}',
+    ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- for filtering with @>
+CREATE INDEX conversations_metadata_idx ON conversations USING GIN (metadata);
+CREATE INDEX conversations_tags_idx ON conversations USING GIN (tags);
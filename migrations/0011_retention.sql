@@ -0,0 +1,5 @@
+-- how long after its last message the conversation is deleted, in place of the deployment's
+-- retention period
+ALTER TABLE conversations ADD COLUMN retention_hours INTEGER;
+
+CREATE INDEX conversations_retention_idx ON conversations (retention_hours, updated_at);
@@ -0,0 +1,15 @@
+-- whoever the client says the conversation is with, eg. their own user id
+ALTER TABLE conversations ADD COLUMN user_id TEXT;
+
+CREATE INDEX conversations_user_id_idx ON conversations (user_id);
+
+-- facts about a user that every conversation with them should know
+CREATE TABLE memories (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    fact TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX memories_user_id_idx ON memories (user_id);
@@ -0,0 +1,5 @@
+-- a hash of the API key the conversation was started with, so callers only list their own
+ALTER TABLE conversations ADD COLUMN owner TEXT;
+
+CREATE INDEX conversations_owner_updated_at_idx ON conversations (owner, updated_at, id);
+CREATE INDEX conversations_owner_created_at_idx ON conversations (owner, created_at, id);
@@ -0,0 +1,14 @@
+-- embeddings for BEDROCK_VECTOR_STORE=pgvector, for anyone who'd rather not run Qdrant too
+CREATE EXTENSION IF NOT EXISTS vector;
+
+-- HNSW indexes need a fixed number of dimensions, 1024 is what the default Titan model gives
+CREATE TABLE vectors (
+    collection TEXT NOT NULL,
+    id UUID NOT NULL,
+    embedding vector(1024) NOT NULL,
+    payload JSONB NOT NULL DEFAULT '{}',
+    PRIMARY KEY (collection, id)
+);
+
+CREATE INDEX vectors_embedding_idx ON vectors USING hnsw (embedding vector_cosine_ops);
+CREATE INDEX vectors_payload_idx ON vectors USING gin (payload jsonb_path_ops);
@@ -0,0 +1,9 @@
+-- documents ingested for retrieval, their chunks and embeddings are in the vector store
+CREATE TABLE documents (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    title TEXT,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    chunks INTEGER NOT NULL,
+    embedding_model TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
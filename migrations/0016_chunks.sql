@@ -0,0 +1,12 @@
+-- the chunks of each document, kept here as well as in the vector store for keyword search
+CREATE TABLE chunks (
+    id UUID PRIMARY KEY,
+    document_id UUID NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+    -- the same payload as the chunk's point in the vector store, including its text
+    payload JSONB NOT NULL,
+    search TSVECTOR GENERATED ALWAYS AS (to_tsvector('english', payload ->> 'text')) STORED
+);
+
+CREATE INDEX chunks_document_id_idx ON chunks (document_id);
+CREATE INDEX chunks_search_idx ON chunks USING GIN (search);
+CREATE INDEX chunks_payload_idx ON chunks USING GIN (payload jsonb_path_ops);
@@ -0,0 +1,3 @@
+-- where synced documents came from, so a sync knows what's changed since the last one
+ALTER TABLE documents ADD COLUMN source TEXT UNIQUE;
+ALTER TABLE documents ADD COLUMN source_etag TEXT;
@@ -0,0 +1,4 @@
+-- so unchanged documents aren't ingested again, and unchanged chunks aren't embedded again
+ALTER TABLE documents ADD COLUMN content_hash TEXT;
+ALTER TABLE chunks ADD COLUMN content_hash TEXT;
+CREATE INDEX chunks_content_hash_idx ON chunks (content_hash);
@@ -0,0 +1,8 @@
+-- whose documents these are, so each API key has its own namespace. Everything from before
+-- goes in the namespace for requests without a key.
+ALTER TABLE documents ADD COLUMN tenant TEXT NOT NULL DEFAULT '';
+ALTER TABLE documents DROP CONSTRAINT documents_source_key;
+ALTER TABLE documents ADD CONSTRAINT documents_tenant_source_key UNIQUE (tenant, source);
+
+UPDATE chunks SET payload = payload || '{"tenant": ""}' WHERE NOT payload ? 'tenant';
+UPDATE vectors SET payload = payload || '{"tenant": ""}' WHERE NOT payload ? 'tenant';
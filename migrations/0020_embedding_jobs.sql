@@ -0,0 +1,17 @@
+-- bulk embeddings, made in the background and polled for
+CREATE TABLE embedding_jobs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- a hash of the API key that submitted it, like a conversation's owner
+    owner TEXT,
+    model TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued',
+    total INT NOT NULL,
+    completed INT NOT NULL DEFAULT 0,
+    input_tokens INT NOT NULL DEFAULT 0,
+    estimated BOOLEAN NOT NULL DEFAULT false,
+    -- appended to a batch at a time, in the same shape as /embeddings returns them
+    results JSONB NOT NULL DEFAULT '[]',
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
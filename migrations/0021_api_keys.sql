@@ -0,0 +1,9 @@
+-- the API keys callers can use, once keys are required. Only a hash of each key is kept, the
+-- same one conversations' owners are.
+CREATE TABLE api_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    key_hash TEXT NOT NULL UNIQUE,
+    label TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
@@ -0,0 +1,6 @@
+-- a hash of the API key that added the memory, like a conversation's owner, so one key's users
+-- are separate from another's
+ALTER TABLE memories ADD COLUMN owner TEXT;
+
+DROP INDEX memories_user_id_idx;
+CREATE INDEX memories_owner_user_id_idx ON memories (owner, user_id);
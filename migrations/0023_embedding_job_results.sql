@@ -0,0 +1,10 @@
+-- a row per embedding rather than one ever-growing array, which large jobs outgrow
+CREATE TABLE embedding_job_results (
+    job_id UUID NOT NULL REFERENCES embedding_jobs (id) ON DELETE CASCADE,
+    index INT NOT NULL,
+    -- in the same shape as /embeddings returns it
+    data JSONB NOT NULL,
+    PRIMARY KEY (job_id, index)
+);
+
+ALTER TABLE embedding_jobs DROP COLUMN results;
@@ -0,0 +1,90 @@
+//! Endpoints for whoever runs the deployment rather than its users, which need the admin key.
+
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{error::ApiError, limits, vectors::Condition, AppState};
+
+/// Lets the request through if it has the admin key in place of an API key. Without an admin key
+/// configured, nobody can use these endpoints.
+pub async fn require(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(admin_key) = state.admin_key.as_deref() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if limits::api_key(req.headers()) != Some(admin_key) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Deletes everything stored about a user for a right-to-erasure request, and reports how much
+/// of each there was. Usage isn't stored, so there's nothing to delete for it. Documents with the
+/// user's id in their metadata go too, along with their chunks, and so do stored embeddings with
+/// it in their payload, though the vector store can't say how many.
+pub async fn purge(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut tx = state.db.begin().await?;
+    // everything else goes along with the conversations, so it has to be counted first
+    let (messages, sessions, feedback): (i64, i64, i64) = sqlx::query_as(
+        "SELECT \
+         (SELECT count(*) FROM messages JOIN conversations \
+          ON conversations.id = messages.conversation_id WHERE conversations.user_id = $1), \
+         (SELECT count(*) FROM sessions JOIN conversations \
+          ON conversations.id = sessions.conversation_id WHERE conversations.user_id = $1), \
+         (SELECT count(*) FROM feedback JOIN messages ON messages.id = feedback.message_id \
+          JOIN conversations ON conversations.id = messages.conversation_id \
+          WHERE conversations.user_id = $1)",
+    )
+    .bind(&user_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    let conversations = sqlx::query("DELETE FROM conversations WHERE user_id = $1")
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    let memories = sqlx::query("DELETE FROM memories WHERE user_id = $1")
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    // a document's metadata ends up in each of its chunks' payloads
+    let chunks =
+        sqlx::query("DELETE FROM chunks WHERE payload @> jsonb_build_object('user_id', $1::text)")
+            .bind(&user_id)
+            .execute(&mut *tx)
+            .await?;
+    let documents = sqlx::query("DELETE FROM documents WHERE metadata ->> 'user_id' = $1")
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    let filter = [("user_id".to_string(), Condition::Equals(user_id.clone()))].into();
+    state.vectors.delete(&filter).await?;
+
+    println!("Purged everything stored about user {user_id}");
+
+    Ok(Json(json!({
+        "user_id": user_id,
+        "deleted": {
+            "conversations": conversations.rows_affected(),
+            "messages": messages,
+            "sessions": sessions,
+            "feedback": feedback,
+            "memories": memories.rows_affected(),
+            "documents": documents.rows_affected(),
+            "chunks": chunks.rows_affected(),
+        },
+    })))
+}
@@ -0,0 +1,425 @@
+//! Answering questions from ingested documents: the chunks closest to the question are found,
+//! and the model is asked to answer from them rather than from whatever it remembers.
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::HeaderMap,
+    response::{sse::Event, IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    chat::{self, ChatMessage, Role},
+    complete,
+    error::{ApiError, Violation},
+    limits::MaxTokensLimit,
+    models::{self, params::GenerationParams, registry::ModelInfo},
+    prepare_prompt,
+    search::{self, rewrite, Chunk, SearchOptions},
+    streaming,
+    vectors::{self, Filter},
+    AppState, Completion, PreparedPrompt, Prompt, ResponseFormat,
+};
+
+/// Goes before the retrieved chunks, in place of the deployment's system prompt.
+const GROUNDING_PROMPT: &str = "Answer the user's question using only the context below. If \
+    the context doesn't contain the answer, say that you don't know rather than guessing.";
+/// Added to the grounding prompt when the answer should say where each part of it came from.
+const CITATION_PROMPT: &str = "Each part of the context starts with its number, eg. [1]. After \
+    each sentence that uses the context, cite the parts it came from by their numbers in square \
+    brackets, eg. [1] or [2][3].";
+const CONTEXT_SEPARATOR: &str = "\n\n---\n\n";
+/// A chunk that doesn't fit is cut short to fit, unless there's less room left than this.
+const MIN_TRIMMED_TOKENS: i32 = 100;
+
+#[derive(Deserialize)]
+pub struct AskRequest {
+    question: String,
+    /// Earlier turns, oldest first, for a follow-up question. They're sent to the model along
+    /// with the question.
+    #[serde(default)]
+    history: Vec<ChatMessage>,
+    /// Rewrites the question from `history` into one that makes sense on its own before
+    /// searching for it, see [`rewrite`].
+    #[serde(default)]
+    rewrite: bool,
+    top_k: Option<usize>,
+    score_threshold: Option<f32>,
+    #[serde(default)]
+    filter: Filter,
+    /// The model the documents were embedded with, see [`SearchOptions`].
+    embedding_model: Option<String>,
+    #[serde(default)]
+    rerank: bool,
+    #[serde(default)]
+    hybrid: bool,
+    #[serde(default)]
+    multi_query: bool,
+    /// The most tokens of context the model is given, when that's less than what fits in its
+    /// context window.
+    context_tokens: Option<i32>,
+    /// The model that answers, like for a prompt.
+    model: Option<String>,
+    preset: Option<String>,
+    #[serde(flatten)]
+    params: GenerationParams,
+    /// Takes priority over the `Accept` header when given.
+    #[serde(default)]
+    response_format: Option<ResponseFormat>,
+    /// Asks the model to cite its sources inline, eg. `[1]`, and only the cited ones are
+    /// returned as `sources`.
+    #[serde(default)]
+    citations: bool,
+    /// Streams the answer as SSE events: `sources` first, with every chunk the model was given,
+    /// then `delta`s and `done` like `/prompt/sse`.
+    #[serde(default)]
+    stream: bool,
+}
+
+/// The JSON response, a [`Completion`] along with the chunks it was given.
+#[derive(Serialize)]
+struct Answer {
+    #[serde(flatten)]
+    completion: Completion,
+    /// What was searched for, when the question was rewritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rewritten_query: Option<String>,
+    /// The chunks the answer used. Without citations that's every chunk it was given.
+    sources: Vec<Source>,
+    /// The chunks the model was given, the last one cut short if it only partly fit.
+    chunks: Vec<Chunk>,
+    /// How many of the retrieved chunks didn't fit in the context budget, see
+    /// [`context_budget`].
+    left_out: usize,
+}
+
+#[derive(Serialize)]
+struct Source {
+    /// What the answer cites the chunk as, eg. `1` for `[1]`.
+    marker: usize,
+    chunk_id: Uuid,
+    document_id: Uuid,
+    title: Option<String>,
+}
+
+pub async fn ask(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    headers: HeaderMap,
+    Json(AskRequest {
+        question,
+        history,
+        rewrite,
+        top_k,
+        score_threshold,
+        filter,
+        embedding_model,
+        rerank,
+        hybrid,
+        multi_query,
+        context_tokens,
+        model,
+        preset,
+        params,
+        response_format,
+        citations,
+        stream,
+    }): Json<AskRequest>,
+) -> Result<Response, ApiError> {
+    let mut violations = Vec::new();
+    if question.trim().is_empty() {
+        violations.push(Violation::new("question", "must not be empty"));
+    } else {
+        // the question is the user's next turn, so the history has to lead up to it like /chat's
+        let mut turns = history.clone();
+        turns.push(ChatMessage {
+            role: Role::User,
+            content: question.clone(),
+        });
+        violations.extend(
+            chat::violations(&turns)
+                .into_iter()
+                .map(|violation| Violation::new("history", violation.message)),
+        );
+    }
+    if context_tokens.is_some_and(|tokens| tokens < 1) {
+        violations.push(Violation::new("context_tokens", "must be at least 1"));
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+    let options = SearchOptions {
+        top_k,
+        score_threshold,
+        filter,
+        model: embedding_model,
+        rerank,
+        hybrid,
+        multi_query,
+    };
+    let rewritten_query = if rewrite {
+        Some(rewrite::rewrite(&state, &history, &question).await)
+    } else {
+        None
+    };
+    let tenant = vectors::tenant(&headers);
+    let query = rewritten_query.as_deref().unwrap_or(&question);
+    let chunks = search::retrieve(&state, &tenant, query, &options).await?;
+
+    let response_format = response_format.unwrap_or_else(|| ResponseFormat::from_accept(&headers));
+    let prompt = Prompt {
+        prompt: question,
+        model,
+        preset,
+        // the chunks are only added once it's known how many fit
+        system: Some(grounded_system_prompt(&[], citations)),
+        images: Vec::new(),
+        params,
+        response_format: Some(response_format),
+        n: None,
+        logprobs: false,
+    };
+    let mut prepared = prepare_prompt(&state, &route, max_tokens_limit, prompt)?;
+    // the grounding prompt takes the place of any system messages
+    prepared.history = history
+        .into_iter()
+        .filter(|message| message.role != Role::System)
+        .collect();
+    let budget = context_budget(&state, &prepared, context_tokens);
+    let (chunks, left_out) = within_budget(chunks, budget);
+    prepared.system = Some(grounded_system_prompt(&chunks, citations));
+
+    if stream {
+        // which ones are cited isn't known until the end, so this is all of them
+        let first = Event::default()
+            .event("sources")
+            .json_data(json!({
+                "rewritten_query": rewritten_query,
+                "sources": sources(&chunks, None),
+                "left_out": left_out,
+            }))
+            .unwrap();
+
+        return streaming::sse_after(&state, prepared, first).await;
+    }
+    let completion = complete(&state, &prepared, &prepared.params).await?;
+
+    if response_format == ResponseFormat::Text {
+        return Ok(completion.into_response(response_format));
+    }
+
+    let cited = citations.then(|| markers(&completion.text));
+    let sources = sources(&chunks, cited.as_deref());
+
+    Ok(Json(Answer {
+        completion,
+        rewritten_query,
+        sources,
+        chunks,
+        left_out,
+    })
+    .into_response())
+}
+
+/// Each chunk's marker and where it's from, only for the `cited` markers if there are some.
+fn sources(chunks: &[Chunk], cited: Option<&[usize]>) -> Vec<Source> {
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| (index + 1, chunk))
+        .filter(|(marker, _)| cited.is_none_or(|cited| cited.contains(marker)))
+        .map(|(marker, chunk)| Source {
+            marker,
+            chunk_id: chunk.id,
+            document_id: chunk.document_id,
+            title: title(chunk).map(str::to_string),
+        })
+        .collect()
+}
+
+/// How many tokens the chunks can take up: the model's context window, less the question, the
+/// history, the instructions and room for the answer, and then only the deployment's share of
+/// that so the rough token estimates have some slack. `None` for models whose context window
+/// isn't known, unless the request gave a budget.
+fn context_budget(
+    state: &AppState,
+    prepared: &PreparedPrompt,
+    requested: Option<i32>,
+) -> Option<i32> {
+    let available = ModelInfo::lookup(&prepared.model.id).map(|info| {
+        let reply_tokens = prepared
+            .model
+            .provider
+            .max_tokens(&prepared.prompt, prepared.params.max_tokens)
+            .unwrap_or(0);
+        let used = models::estimate_tokens(&prepared.prompt)
+            + prepared
+                .system
+                .as_deref()
+                .map_or(0, models::estimate_tokens)
+            + prepared
+                .history
+                .iter()
+                .map(|message| models::estimate_tokens(&message.content))
+                .sum::<i32>()
+            + reply_tokens;
+
+        ((info.context_window - used).max(0) as f32 * state.documents.context_share) as i32
+    });
+
+    match (available, requested) {
+        (Some(available), Some(requested)) => Some(available.min(requested)),
+        (available, requested) => available.or(requested),
+    }
+}
+
+/// The most relevant chunks that fit in the budget, in the order they were retrieved, and how
+/// many were left out. The first one that doesn't fit is cut short if there's enough room left
+/// for it to be worth it.
+fn within_budget(chunks: Vec<Chunk>, budget: Option<i32>) -> (Vec<Chunk>, usize) {
+    let Some(mut remaining) = budget else {
+        return (chunks, 0);
+    };
+
+    let retrieved = chunks.len();
+    let mut kept = Vec::new();
+    for mut chunk in chunks {
+        let part = context_part(kept.len() + 1, &chunk);
+        let tokens = models::estimate_tokens(&part) + models::estimate_tokens(CONTEXT_SEPARATOR);
+        if tokens <= remaining {
+            remaining -= tokens;
+            kept.push(chunk);
+            continue;
+        }
+
+        if remaining >= MIN_TRIMMED_TOKENS {
+            let overhead = tokens - models::estimate_tokens(&chunk.text);
+            // estimate_tokens counts 4 bytes to a token
+            let mut length = ((remaining - overhead).max(0) * 4) as usize;
+            while !chunk.text.is_char_boundary(length) {
+                length -= 1;
+            }
+            chunk.text.truncate(length);
+            kept.push(chunk);
+        }
+        break;
+    }
+    let left_out = retrieved - kept.len();
+
+    (kept, left_out)
+}
+
+/// The instructions, then each chunk numbered and under its title if its document has one.
+fn grounded_system_prompt(chunks: &[Chunk], citations: bool) -> String {
+    let instructions = if citations {
+        format!("{GROUNDING_PROMPT} {CITATION_PROMPT}")
+    } else {
+        GROUNDING_PROMPT.to_string()
+    };
+    if chunks.is_empty() {
+        return format!("{instructions}\n\nContext:\n(nothing relevant was found)");
+    }
+
+    let context: Vec<String> = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| context_part(index + 1, chunk))
+        .collect();
+
+    format!(
+        "{instructions}\n\nContext:\n\n{}",
+        context.join(CONTEXT_SEPARATOR)
+    )
+}
+
+/// eg. `[1] From Pricing:` and then the chunk's text.
+fn context_part(marker: usize, chunk: &Chunk) -> String {
+    match title(chunk) {
+        Some(title) => format!("[{marker}] From {title}:\n{}", chunk.text),
+        None => format!("[{marker}]\n{}", chunk.text),
+    }
+}
+
+fn title(chunk: &Chunk) -> Option<&str> {
+    chunk.metadata.get("title").and_then(|title| title.as_str())
+}
+
+/// Every number in square brackets in the answer, eg. 1 and 3 in `... [1][3].`
+fn markers(answer: &str) -> Vec<usize> {
+    answer
+        .split('[')
+        .skip(1)
+        .filter_map(|rest| rest.split_once(']')?.0.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Map;
+
+    use super::*;
+
+    fn chunk(text: &str) -> Chunk {
+        Chunk {
+            id: Uuid::new_v4(),
+            score: 1.0,
+            rerank_score: None,
+            document_id: Uuid::new_v4(),
+            text: text.to_string(),
+            metadata: Map::new(),
+        }
+    }
+
+    #[test]
+    fn everything_is_kept_without_a_budget() {
+        let (kept, left_out) = within_budget(vec![chunk("a"), chunk("b")], None);
+
+        assert_eq!(kept.len(), 2);
+        assert_eq!(left_out, 0);
+    }
+
+    #[test]
+    fn chunks_that_dont_fit_are_left_out() {
+        // each chunk is 103 tokens with its marker and separator
+        let chunks = vec![chunk(&"a".repeat(400)), chunk(&"b".repeat(400))];
+
+        let (kept, left_out) = within_budget(chunks, Some(153));
+
+        assert_eq!(kept.len(), 1);
+        assert_eq!(left_out, 1);
+    }
+
+    #[test]
+    fn the_last_chunk_is_cut_short_if_theres_enough_room() {
+        let chunks = vec![chunk(&"a".repeat(400)), chunk(&"b".repeat(2000))];
+
+        let (kept, left_out) = within_budget(chunks, Some(253));
+
+        assert_eq!(kept.len(), 2);
+        assert_eq!(left_out, 0);
+        assert_eq!(kept[1].text.len(), 588);
+    }
+
+    #[test]
+    fn chunks_are_cut_on_char_boundaries() {
+        let chunks = vec![chunk(&"é".repeat(1000))];
+
+        let (kept, _) = within_budget(chunks, Some(150));
+
+        assert!(kept[0].text.chars().all(|c| c == 'é'));
+        assert!(kept[0].text.len() < 2000);
+    }
+
+    #[test]
+    fn markers_are_the_numbers_in_brackets() {
+        assert_eq!(
+            markers("Yes [1], but see [3][ 2 ]. Not [a] or [ this"),
+            [1, 3, 2]
+        );
+        assert!(markers("no citations").is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+//! Images attached to a prompt, either as base64 in a JSON body or as files in a multipart form.
+
+use aws_sdk_bedrockruntime::{
+    primitives::Blob,
+    types::{ContentBlock, ImageBlock, ImageFormat, ImageSource},
+};
+use axum::{
+    async_trait,
+    extract::{FromRequest, Multipart, Request},
+    http::{header, StatusCode},
+    Json,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::Deserialize;
+
+use crate::{models::params::GenerationParams, Prompt};
+
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "Base64Image")]
+pub struct Image {
+    format: ImageFormat,
+    bytes: Vec<u8>,
+}
+
+impl Image {
+    /// Returns `None` for anything other than the PNG, JPEG, GIF and WebP images Bedrock accepts.
+    fn new(media_type: &str, bytes: Vec<u8>) -> Option<Self> {
+        let format = match media_type {
+            "image/png" => ImageFormat::Png,
+            "image/jpeg" => ImageFormat::Jpeg,
+            "image/gif" => ImageFormat::Gif,
+            "image/webp" => ImageFormat::Webp,
+            _ => return None,
+        };
+
+        Some(Self { format, bytes })
+    }
+
+    pub fn content_block(&self) -> Option<ContentBlock> {
+        let image = ImageBlock::builder()
+            .format(self.format.clone())
+            .source(ImageSource::Bytes(Blob::new(self.bytes.clone())))
+            .build()
+            .ok()?;
+
+        Some(ContentBlock::Image(image))
+    }
+}
+
+/// How images are sent in a JSON body, eg. `{"media_type": "image/png", "data": "iVBORw0..."}`.
+#[derive(Deserialize)]
+struct Base64Image {
+    media_type: String,
+    data: String,
+}
+
+impl TryFrom<Base64Image> for Image {
+    type Error = String;
+
+    fn try_from(Base64Image { media_type, data }: Base64Image) -> Result<Self, Self::Error> {
+        let bytes = BASE64_STANDARD
+            .decode(data)
+            .map_err(|err| format!("image data is not valid base64: {err}"))?;
+
+        Image::new(&media_type, bytes).ok_or_else(|| format!("{media_type} is not supported"))
+    }
+}
+
+/// A [`Prompt`] from either a JSON body or a multipart form with the same fields as the JSON,
+/// and any number of `image` files. Each stop sequence is its own `stop_sequences` field.
+pub struct PromptBody(pub Prompt);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for PromptBody {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_multipart = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+
+        if !is_multipart {
+            let Ok(Json(prompt)) = Json::<Prompt>::from_request(req, state).await else {
+                return Err(StatusCode::BAD_REQUEST);
+            };
+
+            return Ok(Self(prompt));
+        }
+
+        let Ok(multipart) = Multipart::from_request(req, state).await else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        from_multipart(multipart)
+            .await
+            .map(Self)
+            .ok_or(StatusCode::BAD_REQUEST)
+    }
+}
+
+async fn from_multipart(mut multipart: Multipart) -> Option<Prompt> {
+    let mut prompt = None;
+    let mut model = None;
+    let mut preset = None;
+    let mut system = None;
+    let mut images = Vec::new();
+    let mut params = GenerationParams::default();
+    let mut response_format = None;
+    let mut n = None;
+    let mut logprobs = false;
+
+    while let Some(field) = multipart.next_field().await.ok()? {
+        match field.name() {
+            Some("prompt") => prompt = Some(field.text().await.ok()?),
+            Some("model") => model = Some(field.text().await.ok()?),
+            Some("preset") => preset = Some(field.text().await.ok()?),
+            Some("system") => system = Some(field.text().await.ok()?),
+            Some("image") => {
+                let media_type = field.content_type()?.to_string();
+                let bytes = field.bytes().await.ok()?;
+
+                images.push(Image::new(&media_type, bytes.to_vec())?);
+            }
+            Some("temperature") => {
+                params.temperature = Some(field.text().await.ok()?.parse().ok()?)
+            }
+            Some("top_p") => params.top_p = Some(field.text().await.ok()?.parse().ok()?),
+            Some("max_tokens") => params.max_tokens = Some(field.text().await.ok()?.parse().ok()?),
+            Some("response_format") => {
+                response_format =
+                    Some(serde_json::from_value(field.text().await.ok()?.into()).ok()?)
+            }
+            Some("logprobs") => logprobs = field.text().await.ok()?.parse().ok()?,
+            Some("n") => n = Some(field.text().await.ok()?.parse().ok()?),
+            Some("seed") => params.seed = Some(field.text().await.ok()?.parse().ok()?),
+            Some("top_k") => params.top_k = Some(field.text().await.ok()?.parse().ok()?),
+            Some("presence_penalty") => {
+                params.presence_penalty = Some(field.text().await.ok()?.parse().ok()?)
+            }
+            Some("frequency_penalty") => {
+                params.frequency_penalty = Some(field.text().await.ok()?.parse().ok()?)
+            }
+            Some("stop_sequences") => params
+                .stop_sequences
+                .get_or_insert_with(Vec::new)
+                .push(field.text().await.ok()?),
+            _ => {}
+        }
+    }
+
+    Some(Prompt {
+        prompt: prompt?,
+        model,
+        preset,
+        system,
+        images,
+        params,
+        response_format,
+        n,
+        logprobs,
+    })
+}
@@ -0,0 +1,216 @@
+//! Only letting in callers with an API key that was issued to them, so finding the URL isn't
+//! enough to run up the Bedrock bill. Keys are issued and revoked through `/admin/keys`.
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    conversations::{self, default_page_size, page_violations},
+    error::{ApiError, Violation},
+    limits, AppState,
+};
+
+const MAX_LABEL_LENGTH: usize = 256;
+const KEY_COLUMNS: &str = "id, label, created_at, revoked_at";
+
+/// An issued key, without the key itself, which is only ever returned when it's created.
+#[derive(Serialize, FromRow)]
+pub struct ApiKey {
+    id: Uuid,
+    /// Who or what the key is for, eg. `support-bot`.
+    label: Option<String>,
+    created_at: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct KeyLabel {
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct KeysQuery {
+    /// Whether to list revoked keys as well.
+    #[serde(default)]
+    revoked: bool,
+    #[serde(default = "default_page_size")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+/// Lets the request through if its API key is in `api_keys` and hasn't been revoked. The admin
+/// key works too, so there's a way in before any keys have been issued.
+pub async fn require(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if !state.limits.require_api_keys {
+        return next.run(req).await;
+    }
+    let Some(key) = limits::api_key(req.headers()) else {
+        return unauthorized();
+    };
+    if state.admin_key.as_deref() == Some(key) {
+        return next.run(req).await;
+    }
+
+    let valid = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL)",
+    )
+    .bind(conversations::key_hash(key))
+    .fetch_one(&state.db)
+    .await;
+    match valid {
+        Ok(true) => next.run(req).await,
+        Ok(false) => unauthorized(),
+        Err(err) => {
+            println!("Couldn't check an API key: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Issues a new key. This is the only time the key can be seen, so it has to be handed over now.
+pub async fn create(
+    State(state): State<AppState>,
+    Json(KeyLabel { label }): Json<KeyLabel>,
+) -> Result<impl IntoResponse, ApiError> {
+    let label = tidy_label(label)?;
+
+    // two random uuids' worth, like a session's token
+    let key: String = sqlx::query_scalar(
+        "SELECT 'key-' || replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '')",
+    )
+    .fetch_one(&state.db)
+    .await?;
+    let api_key: ApiKey = sqlx::query_as(&format!(
+        "INSERT INTO api_keys (key_hash, label) VALUES ($1, $2) RETURNING {KEY_COLUMNS}"
+    ))
+    .bind(conversations::key_hash(&key))
+    .bind(label)
+    .fetch_one(&state.db)
+    .await?;
+
+    println!("Issued API key {}", api_key.id);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "key": key,
+            "id": api_key.id,
+            "label": api_key.label,
+            "created_at": api_key.created_at,
+        })),
+    ))
+}
+
+/// The newest keys first, leaving out revoked ones unless `?revoked=true`.
+pub async fn list(
+    State(state): State<AppState>,
+    Query(KeysQuery {
+        revoked,
+        limit,
+        offset,
+    }): Query<KeysQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let violations = page_violations(limit, offset);
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    // one more than asked for, to find out if there's another page
+    let mut keys: Vec<ApiKey> = sqlx::query_as(&format!(
+        "SELECT {KEY_COLUMNS} FROM api_keys WHERE $1 OR revoked_at IS NULL \
+         ORDER BY created_at DESC, id LIMIT $2 OFFSET $3"
+    ))
+    .bind(revoked)
+    .bind(limit + 1)
+    .bind(offset)
+    .fetch_all(&state.db)
+    .await?;
+    let has_more = keys.len() as i64 > limit;
+    keys.truncate(limit as usize);
+
+    Ok(Json(json!({
+        "keys": keys,
+        "has_more": has_more,
+    })))
+}
+
+/// Relabels a key, or takes its label away with `null`.
+pub async fn update(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(KeyLabel { label }): Json<KeyLabel>,
+) -> Result<Json<ApiKey>, ApiError> {
+    let label = tidy_label(label)?;
+
+    let api_key: Option<ApiKey> = sqlx::query_as(&format!(
+        "UPDATE api_keys SET label = $2 WHERE id = $1 RETURNING {KEY_COLUMNS}"
+    ))
+    .bind(id)
+    .bind(label)
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(api_key) = api_key else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    Ok(Json(api_key))
+}
+
+/// Revokes a key, so requests with it are turned away from then on. It's kept, and listed with
+/// `?revoked=true`, so there's a record of it. Revoking it again changes nothing.
+pub async fn revoke(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<ApiKey>, ApiError> {
+    let api_key: Option<ApiKey> = sqlx::query_as(&format!(
+        "UPDATE api_keys SET revoked_at = coalesce(revoked_at, now()) WHERE id = $1 \
+         RETURNING {KEY_COLUMNS}"
+    ))
+    .bind(id)
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(api_key) = api_key else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    println!("Revoked API key {id}");
+
+    Ok(Json(api_key))
+}
+
+/// Blank labels are no label at all.
+fn tidy_label(label: Option<String>) -> Result<Option<String>, ApiError> {
+    let label = label
+        .map(|label| label.trim().to_string())
+        .filter(|label| !label.is_empty());
+    if label
+        .as_ref()
+        .is_some_and(|label| label.len() > MAX_LABEL_LENGTH)
+    {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "label",
+            format!("must be at most {MAX_LABEL_LENGTH} bytes"),
+        )]));
+    }
+
+    Ok(label)
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+    )
+        .into_response()
+}
@@ -0,0 +1,466 @@
+//! Multi-turn conversations, where the client sends every message so far rather than one prompt.
+
+use aws_sdk_bedrockruntime::types::ConversationRole;
+use axum::{
+    extract::{MatchedPath, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    complete,
+    config::Truncation,
+    conversations::{self, Replacing},
+    error::{ApiError, Violation},
+    limits::MaxTokensLimit,
+    memories,
+    models::{self, params::GenerationParams, registry::ModelInfo},
+    prepare_prompt, summaries, titles, AppState, PreparedPrompt, Prompt, ResponseFormat,
+};
+
+#[derive(Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn conversation_role(self) -> ConversationRole {
+        match self {
+            // system messages never make it into the history, they're the system prompt
+            Role::System | Role::User => ConversationRole::User,
+            Role::Assistant => ConversationRole::Assistant,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow, Clone, Debug)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    /// Oldest first, ending with the user's latest message.
+    messages: Vec<ChatMessage>,
+    /// A conversation from `POST /conversations`, in which case `messages` only needs the new
+    /// ones. They're added to the conversation along with the reply.
+    conversation_id: Option<Uuid>,
+    /// Who the chat is with, for their memories. Stored conversations already know.
+    user_id: Option<String>,
+    #[serde(flatten)]
+    options: TurnOptions,
+}
+
+/// How to generate the reply, the same as for a single prompt.
+#[derive(Deserialize)]
+pub struct TurnOptions {
+    model: Option<String>,
+    preset: Option<String>,
+    #[serde(flatten)]
+    params: GenerationParams,
+    /// Takes priority over the `Accept` header when given.
+    #[serde(default)]
+    response_format: Option<ResponseFormat>,
+}
+
+/// System messages are joined together into the system prompt, the rest go to the model as
+/// they are (or as a transcript, for models the Converse API doesn't support).
+pub async fn chat(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    headers: HeaderMap,
+    Json(ChatRequest {
+        messages,
+        conversation_id,
+        user_id,
+        options,
+    }): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let turn = Turn {
+        conversation_id,
+        user_id,
+        messages,
+        replacing: None,
+        options,
+    };
+
+    respond(&state, &route, max_tokens_limit, &headers, turn).await
+}
+
+/// New messages for the model to reply to, after any already in the conversation.
+pub struct Turn {
+    pub conversation_id: Option<Uuid>,
+    pub user_id: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    /// What in the conversation the reply takes the place of, which is only changed once
+    /// there's a reply to take its place.
+    pub replacing: Option<Replacing>,
+    pub options: TurnOptions,
+}
+
+pub async fn respond(
+    state: &AppState,
+    route: &MatchedPath,
+    max_tokens_limit: MaxTokensLimit,
+    headers: &HeaderMap,
+    Turn {
+        conversation_id,
+        mut user_id,
+        messages,
+        replacing,
+        options:
+            TurnOptions {
+                model,
+                preset,
+                params,
+                response_format,
+            },
+    }: Turn,
+) -> Result<Response, ApiError> {
+    let mut all_messages = Vec::new();
+    let mut summary = None;
+    let mut system_prompt = None;
+    if let Some(id) = conversation_id {
+        let owner = conversations::owner(headers);
+        let history =
+            conversations::history(&state.db, id, owner.as_deref(), replacing.as_ref()).await?;
+        let Some(history) = history else {
+            return Err(StatusCode::NOT_FOUND.into());
+        };
+        all_messages = history.messages;
+        summary = history.summary;
+        system_prompt = history.system_prompt;
+        user_id = history.user_id.or(user_id);
+    }
+    all_messages.extend(messages.iter().cloned());
+
+    let violations = violations(&all_messages);
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let (system, mut history): (Vec<_>, Vec<_>) = all_messages
+        .into_iter()
+        .partition(|message| message.role == Role::System);
+    // the conversation's own system prompt goes before any the client sent
+    let system: Vec<String> = system_prompt
+        .into_iter()
+        .chain(system.into_iter().map(|message| message.content))
+        .collect();
+    // checked above, the last message is the user's
+    let prompt = history
+        .pop()
+        .map(|message| message.content)
+        .unwrap_or_default();
+    let response_format = response_format.unwrap_or_else(|| ResponseFormat::from_accept(headers));
+
+    let prompt = Prompt {
+        prompt,
+        model,
+        preset,
+        system: (!system.is_empty()).then(|| system.join("\n\n")),
+        images: Vec::new(),
+        params,
+        response_format: Some(response_format),
+        n: None,
+        logprobs: false,
+    };
+    let mut prepared = prepare_prompt(state, route, max_tokens_limit, prompt)?;
+    // these go after whichever system prompt is used, rather than replacing the default one
+    let memories = match &user_id {
+        Some(user_id) => {
+            let owner = conversations::owner(headers);
+            memories::context(&state.db, owner.as_deref(), user_id).await?
+        }
+        None => None,
+    };
+    let summary = summary.map(|summary| format!("A summary of the conversation so far: {summary}"));
+    let context: Vec<String> = prepared
+        .system
+        .take()
+        .into_iter()
+        .chain(memories)
+        .chain(summary)
+        .collect();
+    prepared.system = (!context.is_empty()).then(|| context.join("\n\n"));
+    prepared.history = truncated(history, &prepared, state.chat.truncation);
+
+    let completion = complete(state, &prepared, &prepared.params).await?;
+    if let Some(id) = conversation_id {
+        conversations::append(
+            &state.db,
+            id,
+            &messages,
+            &completion.text,
+            &completion.model,
+            &prepared.params,
+            replacing.as_ref(),
+        )
+        .await?;
+        summaries::spawn(state.clone(), id);
+        titles::spawn(state.clone(), id);
+    }
+
+    Ok(completion.into_response(response_format))
+}
+
+/// The Converse API wants turns to alternate between the user and the assistant, starting and
+/// ending with the user. System messages can go anywhere.
+pub fn violations(messages: &[ChatMessage]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let turns: Vec<Role> = messages
+        .iter()
+        .map(|message| message.role)
+        .filter(|role| *role != Role::System)
+        .collect();
+
+    if turns.last() != Some(&Role::User) {
+        violations.push(Violation::new(
+            "messages",
+            "must end with a message from the user",
+        ));
+    }
+    if turns.first().is_some_and(|role| *role != Role::User) {
+        violations.push(Violation::new(
+            "messages",
+            "must start with a message from the user",
+        ));
+    }
+    if turns.windows(2).any(|pair| pair[0] == pair[1]) {
+        violations.push(Violation::new(
+            "messages",
+            "must alternate between the user and the assistant",
+        ));
+    }
+    if messages
+        .iter()
+        .any(|message| message.content.trim().is_empty())
+    {
+        violations.push(Violation::new("messages", "must not be empty"));
+    }
+
+    violations
+}
+
+/// Drops the oldest turns (a user message and the reply to it) from the history until it fits
+/// in the model's context window, along with the prompt and room for the reply. Bedrock would
+/// reject the whole conversation otherwise.
+fn truncated(
+    mut history: Vec<ChatMessage>,
+    prepared: &PreparedPrompt,
+    truncation: Truncation,
+) -> Vec<ChatMessage> {
+    // the history is always whole turns, the latest message has already been taken off it
+    if let Truncation::LastTurns(turns) = truncation {
+        let dropped = history.len().saturating_sub(turns * 2);
+        history.drain(..dropped);
+    }
+
+    let Some(info) = ModelInfo::lookup(&prepared.model.id) else {
+        return history;
+    };
+    let reply_tokens = prepared
+        .model
+        .provider
+        .max_tokens(&prepared.prompt, prepared.params.max_tokens)
+        .unwrap_or(0);
+    let mut tokens = models::estimate_tokens(&prepared.prompt)
+        + prepared
+            .system
+            .as_deref()
+            .map_or(0, models::estimate_tokens)
+        + reply_tokens
+        + history
+            .iter()
+            .map(|message| models::estimate_tokens(&message.content))
+            .sum::<i32>();
+
+    let mut dropped = 0;
+    while tokens > info.context_window && dropped < history.len() {
+        tokens -= history[dropped..dropped + 2]
+            .iter()
+            .map(|message| models::estimate_tokens(&message.content))
+            .sum::<i32>();
+        dropped += 2;
+    }
+    history.drain(..dropped);
+
+    history
+}
+
+/// For models that only take a single prompt, the earlier turns are written out before it.
+pub fn transcript(history: &[ChatMessage], prompt: &str) -> String {
+    format!("{}User: {prompt}\n\nAssistant:", written_out(history))
+}
+
+/// Each message after who it's from, eg. `User: hello`, with a blank line between them.
+pub fn written_out(messages: &[ChatMessage]) -> String {
+    let mut written = String::new();
+    for message in messages {
+        let speaker = match message.role {
+            Role::Assistant => "Assistant",
+            Role::System | Role::User => "User",
+        };
+        written.push_str(&format!("{speaker}: {}\n\n", message.content));
+    }
+
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Model;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    fn messages(violations: Vec<Violation>) -> Vec<String> {
+        violations
+            .into_iter()
+            .map(|violation| violation.message)
+            .collect()
+    }
+
+    #[test]
+    fn alternating_turns_are_fine() {
+        let turns = [
+            message(Role::System, "be brief"),
+            message(Role::User, "hi"),
+            message(Role::Assistant, "hello"),
+            message(Role::User, "how are you?"),
+        ];
+
+        assert!(violations(&turns).is_empty());
+    }
+
+    #[test]
+    fn system_messages_dont_count_as_turns() {
+        let turns = [
+            message(Role::User, "hi"),
+            message(Role::System, "be brief"),
+            message(Role::Assistant, "hello"),
+            message(Role::User, "how are you?"),
+        ];
+
+        assert!(violations(&turns).is_empty());
+    }
+
+    #[test]
+    fn turns_have_to_start_and_end_with_the_user() {
+        let turns = [
+            message(Role::Assistant, "hello"),
+            message(Role::User, "hi"),
+            message(Role::Assistant, "hello again"),
+        ];
+
+        assert_eq!(
+            messages(violations(&turns)),
+            [
+                "must end with a message from the user",
+                "must start with a message from the user"
+            ]
+        );
+    }
+
+    #[test]
+    fn turns_have_to_alternate() {
+        let turns = [message(Role::User, "hi"), message(Role::User, "hello?")];
+
+        assert_eq!(
+            messages(violations(&turns)),
+            ["must alternate between the user and the assistant"]
+        );
+    }
+
+    #[test]
+    fn messages_cant_be_blank() {
+        let turns = [message(Role::User, "  ")];
+
+        assert_eq!(messages(violations(&turns)), ["must not be empty"]);
+    }
+
+    #[test]
+    fn no_messages_dont_end_with_the_user() {
+        assert_eq!(
+            messages(violations(&[])),
+            ["must end with a message from the user"]
+        );
+    }
+
+    fn history(turns: usize, length: usize) -> Vec<ChatMessage> {
+        (0..turns)
+            .flat_map(|turn| {
+                [
+                    message(Role::User, &format!("{turn}{}", "q".repeat(length))),
+                    message(Role::Assistant, &format!("{turn}{}", "a".repeat(length))),
+                ]
+            })
+            .collect()
+    }
+
+    fn prepared(model: &Model) -> PreparedPrompt<'_> {
+        PreparedPrompt {
+            model,
+            logprobs: false,
+            prompt: "and now?".to_string(),
+            system: None,
+            images: Vec::new(),
+            params: GenerationParams::default(),
+            history: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn history_that_fits_is_kept() {
+        let model = Model::new("meta.llama3-8b-instruct-v1:0".to_string()).unwrap();
+
+        let kept = truncated(history(3, 10), &prepared(&model), Truncation::DropOldest);
+
+        assert_eq!(kept.len(), 6);
+    }
+
+    #[test]
+    fn the_oldest_turns_are_dropped_until_it_fits() {
+        // 8k tokens of context, and each turn is 4k tokens
+        let model = Model::new("meta.llama3-8b-instruct-v1:0".to_string()).unwrap();
+
+        let kept = truncated(history(4, 8_000), &prepared(&model), Truncation::DropOldest);
+
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].role, Role::User);
+        assert!(kept[0].content.starts_with('3'));
+    }
+
+    #[test]
+    fn only_the_last_turns_are_sent() {
+        let model = Model::new("meta.llama3-8b-instruct-v1:0".to_string()).unwrap();
+
+        let kept = truncated(history(4, 10), &prepared(&model), Truncation::LastTurns(2));
+
+        assert_eq!(kept.len(), 4);
+        assert!(kept[0].content.starts_with('2'));
+    }
+
+    #[test]
+    fn history_for_unknown_models_isnt_dropped() {
+        let model = Model::new("meta.llama3-unreleased".to_string()).unwrap();
+
+        let kept = truncated(history(4, 8_000), &prepared(&model), Truncation::DropOldest);
+
+        assert_eq!(kept.len(), 8);
+    }
+}
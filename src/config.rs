@@ -0,0 +1,468 @@
+//! Settings read from Secrets.toml. Secrets can only be strings, so lists are comma separated and
+//! mappings are comma separated `key=value` pairs. Anything more structured than that is JSON.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr, time::Duration};
+
+use shuttle_runtime::SecretStore;
+
+use crate::{
+    documents::chunking::Strategy,
+    embeddings::DEFAULT_EMBEDDING_MODEL_ID,
+    images::DEFAULT_IMAGE_MODEL_ID,
+    models::{
+        params::{default_presets, valid_stop_sequences, GenerationParams},
+        ModelFamily, DEFAULT_MODEL_ID,
+    },
+    search::rerank::DEFAULT_RERANK_MODEL_ID,
+};
+
+pub struct ModelConfig {
+    pub default_model_id: String,
+    pub allowed_model_ids: Vec<String>,
+    /// Model ids, or prefixes of them (eg. `anthropic.claude-3-opus`), that can never be invoked
+    /// whatever else is configured.
+    pub denied_model_ids: Vec<String>,
+    /// Custom model ARNs don't tell us which schema the model uses, so these are configured
+    /// with their family. They are always allowed.
+    pub custom_models: Vec<(String, ModelFamily)>,
+    /// Model ids mapped to the provisioned throughput ARN to invoke instead.
+    pub provisioned_throughput: Vec<(String, String)>,
+    /// Invoke models through a cross-region inference profile (eg. `eu.anthropic...`) when
+    /// they have one, so Bedrock can route requests to other regions when ours is busy.
+    pub prefer_inference_profiles: bool,
+    /// Friendly names (eg. `fast`) mapped to model ids, so clients don't have to know Bedrock's.
+    /// Aliased models are always allowed.
+    pub aliases: Vec<(String, String)>,
+    /// Generation parameters for each model family, for anything a request doesn't set.
+    pub family_defaults: Vec<(ModelFamily, GenerationParams)>,
+    /// Named bundles of generation parameters a prompt can ask for instead of setting them.
+    pub presets: Vec<(String, GenerationParams)>,
+    /// Used for prompts that don't bring their own stop sequences.
+    pub stop_sequences: Vec<String>,
+    /// Used for prompts that don't bring their own system prompt.
+    pub system_prompt: Option<String>,
+    /// Used by `/embeddings` when a request doesn't ask for a model.
+    pub embedding_model_id: String,
+    pub allowed_embedding_model_ids: Vec<String>,
+    /// The most texts a single `/embeddings` request can have.
+    pub max_embedding_inputs: usize,
+    /// How many embeddings are kept in memory to be used again, see [`crate::embeddings::cache`].
+    pub embedding_cache_size: usize,
+    /// Used by `/images/generate` when a request doesn't ask for a model.
+    pub image_model_id: String,
+    pub allowed_image_model_ids: Vec<String>,
+}
+
+impl ModelConfig {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        // eg. "anthropic.claude-3-haiku-20240307-v1:0" to use Claude instead of Titan
+        let default_model_id = secrets
+            .get("BEDROCK_MODEL_ID")
+            .unwrap_or_else(|| DEFAULT_MODEL_ID.to_string());
+        // eg. "amazon.titan-text-express-v1,meta.llama3-8b-instruct-v1:0"
+        let allowed_model_ids = list(secrets, "BEDROCK_ALLOWED_MODELS");
+        let denied_model_ids = list(secrets, "BEDROCK_DENIED_MODELS");
+        // eg. "arn:aws:bedrock:eu-west-1:123456789012:provisioned-model/abc123=llama"
+        let custom_models = pairs(secrets, "BEDROCK_CUSTOM_MODELS")
+            .into_iter()
+            .map(|(arn, family)| {
+                let family = ModelFamily::from_name(&family)
+                    .unwrap_or_else(|| panic!("{family} is not a supported model family"));
+
+                (arn, family)
+            })
+            .collect();
+        // eg. "anthropic.claude-3-haiku-20240307-v1:0=arn:aws:bedrock:eu-west-1:123456789012:provisioned-model/abc123"
+        let provisioned_throughput = pairs(secrets, "BEDROCK_PROVISIONED_THROUGHPUT");
+        let prefer_inference_profiles = flag(secrets, "BEDROCK_PREFER_INFERENCE_PROFILES");
+        // eg. "fast=amazon.titan-text-lite-v1:0:4k,smart=anthropic.claude-3-sonnet-20240229-v1:0"
+        let aliases = pairs(secrets, "BEDROCK_MODEL_ALIASES");
+        // eg. '{"claude": {"max_tokens": 1024}, "titan-lite": {"stop_sequences": ["User:"]}}'
+        let family_defaults = secrets
+            .get("BEDROCK_MODEL_DEFAULTS")
+            .map(|configured| {
+                let configured: HashMap<String, GenerationParams> =
+                    serde_json::from_str(&configured).unwrap_or_else(|err| {
+                        panic!("BEDROCK_MODEL_DEFAULTS is not valid JSON: {err}")
+                    });
+
+                configured
+                    .into_iter()
+                    .map(|(family, params)| {
+                        let family = ModelFamily::from_name(&family)
+                            .unwrap_or_else(|| panic!("{family} is not a supported model family"));
+
+                        (family, params)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // eg. '{"creative": {"temperature": 1.0}, "terse": {"max_tokens": 64}}'
+        let mut presets = default_presets();
+        if let Some(configured) = secrets.get("BEDROCK_PRESETS") {
+            let configured: HashMap<String, GenerationParams> = serde_json::from_str(&configured)
+                .unwrap_or_else(|err| panic!("BEDROCK_PRESETS is not valid JSON: {err}"));
+            presets.retain(|(name, _)| !configured.contains_key(name));
+            presets.extend(configured);
+        }
+        // eg. "User:,</answer>"
+        let stop_sequences = list(secrets, "BEDROCK_STOP_SEQUENCES");
+        assert!(
+            valid_stop_sequences(&stop_sequences),
+            "BEDROCK_STOP_SEQUENCES can have at most 4 sequences of up to 100 characters"
+        );
+        let system_prompt = secrets.get("BEDROCK_SYSTEM_PROMPT");
+        let embedding_model_id = secrets
+            .get("BEDROCK_EMBEDDING_MODEL_ID")
+            .unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL_ID.to_string());
+        // eg. "cohere.embed-multilingual-v3" for multilingual text
+        let allowed_embedding_model_ids = list(secrets, "BEDROCK_ALLOWED_EMBEDDING_MODELS");
+        let max_embedding_inputs =
+            number(secrets, "BEDROCK_MAX_EMBEDDING_INPUTS").unwrap_or(DEFAULT_MAX_EMBEDDING_INPUTS);
+        // eg. "50000", or "0" to not cache embeddings at all
+        let embedding_cache_size =
+            number(secrets, "BEDROCK_EMBEDDING_CACHE_SIZE").unwrap_or(DEFAULT_EMBEDDING_CACHE_SIZE);
+        let image_model_id = secrets
+            .get("BEDROCK_IMAGE_MODEL_ID")
+            .unwrap_or_else(|| DEFAULT_IMAGE_MODEL_ID.to_string());
+        // eg. "stability.stable-diffusion-xl-v1,stability.sd3-large-v1:0"
+        let allowed_image_model_ids = list(secrets, "BEDROCK_ALLOWED_IMAGE_MODELS");
+
+        Self {
+            default_model_id,
+            allowed_model_ids,
+            denied_model_ids,
+            custom_models,
+            provisioned_throughput,
+            prefer_inference_profiles,
+            aliases,
+            family_defaults,
+            presets,
+            stop_sequences,
+            system_prompt,
+            embedding_model_id,
+            allowed_embedding_model_ids,
+            max_embedding_inputs,
+            embedding_cache_size,
+            image_model_id,
+            allowed_image_model_ids,
+        }
+    }
+}
+
+const DEFAULT_MAX_EMBEDDING_INPUTS: usize = 256;
+/// At 1024 floats each, about 40MB.
+const DEFAULT_EMBEDDING_CACHE_SIZE: usize = 10_000;
+const DEFAULT_HEARTBEAT_SECS: u64 = 15;
+const DEFAULT_STREAM_BUFFER: usize = 16;
+const DEFAULT_SUMMARIZE_AFTER: usize = 20;
+const DEFAULT_SUMMARY_KEEP_TURNS: usize = 4;
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+const DEFAULT_CHUNK_OVERLAP: usize = 200;
+/// The token estimates are rough, so this leaves some room for them to be wrong.
+const DEFAULT_CONTEXT_SHARE: f32 = 0.75;
+
+pub fn flag(secrets: &SecretStore, key: &str) -> bool {
+    secrets
+        .get(key)
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+pub fn list(secrets: &SecretStore, key: &str) -> Vec<String> {
+    secrets
+        .get(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn number<T>(secrets: &SecretStore, key: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    secrets.get(key).map(|value| {
+        value
+            .trim()
+            .parse()
+            .unwrap_or_else(|err| panic!("{value} is not a valid {key}: {err}"))
+    })
+}
+
+pub fn pairs(secrets: &SecretStore, key: &str) -> Vec<(String, String)> {
+    list(secrets, key)
+        .into_iter()
+        .map(|item| {
+            let (key, value) = item
+                .split_once('=')
+                .unwrap_or_else(|| panic!("expected key=value, got {item}"));
+
+            (key.trim().to_string(), value.trim().to_string())
+        })
+        .collect()
+}
+
+/// Limits on what each API key's callers can ask for, see [`crate::limits`].
+pub struct LimitsConfig {
+    /// Whether callers need an API key from `api_keys`, see [`crate::auth`].
+    pub require_api_keys: bool,
+    /// API key to tier name.
+    pub key_tiers: Vec<(String, String)>,
+    /// Tier name to the most tokens its callers can ask a model for.
+    pub tier_max_tokens: Vec<(String, i32)>,
+}
+
+impl LimitsConfig {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        // on unless it's "false"
+        let require_api_keys = secrets
+            .get("BEDROCK_REQUIRE_API_KEYS")
+            .is_none_or(|value| !value.trim().eq_ignore_ascii_case("false"));
+        // eg. "key-abc123=free,key-def456=pro"
+        let key_tiers = pairs(secrets, "BEDROCK_API_KEY_TIERS");
+        // eg. "default=256,free=512,pro=4096", keys without a tier get the default tier
+        let tier_max_tokens = pairs(secrets, "BEDROCK_TIER_MAX_TOKENS")
+            .into_iter()
+            .map(|(tier, max_tokens)| {
+                let max_tokens = max_tokens.parse().unwrap_or_else(|_| {
+                    panic!("{max_tokens} is not a valid max_tokens for {tier}")
+                });
+
+                (tier, max_tokens)
+            })
+            .collect();
+
+        Self {
+            require_api_keys,
+            key_tiers,
+            tier_max_tokens,
+        }
+    }
+}
+
+/// Text wrapped around every prompt on some routes, eg. formatting instructions the deployment
+/// wants followed whatever the client sends.
+pub struct PromptWrapper {
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    /// Routes to wrap prompts for, all of them if empty.
+    pub routes: Vec<String>,
+}
+
+impl PromptWrapper {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        Self {
+            // eg. "Answer in British English."
+            prefix: secrets.get("BEDROCK_PROMPT_PREFIX"),
+            suffix: secrets.get("BEDROCK_PROMPT_SUFFIX"),
+            // eg. "/prompt,/prompt/streamed"
+            routes: list(secrets, "BEDROCK_PROMPT_WRAPPER_ROUTES"),
+        }
+    }
+
+    pub fn wrap(&self, route: &str, prompt: String) -> String {
+        if !self.routes.is_empty() && !self.routes.iter().any(|wrapped| wrapped == route) {
+            return prompt;
+        }
+
+        match (&self.prefix, &self.suffix) {
+            (None, None) => prompt,
+            (prefix, suffix) => format!(
+                "{}{prompt}{}",
+                prefix
+                    .as_deref()
+                    .map(|prefix| format!("{prefix}\n\n"))
+                    .unwrap_or_default(),
+                suffix
+                    .as_deref()
+                    .map(|suffix| format!("\n\n{suffix}"))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+/// How streamed prompts are sent, see [`crate::streaming`].
+pub struct StreamingConfig {
+    /// How often to send an SSE comment while the model is quiet, so proxies and browsers don't
+    /// give up on it during a long time-to-first-token. `None` turns them off.
+    pub heartbeat: Option<Duration>,
+    /// Deltas are joined together until there are at least this many bytes of them...
+    pub flush_bytes: usize,
+    /// ...or this long has passed since the first one, whichever comes first.
+    pub flush_interval: Option<Duration>,
+    /// The longest a stream can go on for before it's cut off, so a stuck generation can't hold
+    /// a connection open forever.
+    pub timeout: Option<Duration>,
+    /// How many events can be read from Bedrock ahead of what the client has been sent.
+    pub buffer: usize,
+}
+
+impl StreamingConfig {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        // eg. "15", or "0" to turn heartbeats off
+        let heartbeat =
+            number(secrets, "BEDROCK_STREAM_HEARTBEAT_SECS").unwrap_or(DEFAULT_HEARTBEAT_SECS);
+        // neither of these are set by default, so every delta is sent as soon as it arrives
+        let flush_bytes = number(secrets, "BEDROCK_STREAM_FLUSH_BYTES").unwrap_or(0);
+        let flush_interval = number(secrets, "BEDROCK_STREAM_FLUSH_MS").map(Duration::from_millis);
+        // eg. "300"
+        let timeout = number(secrets, "BEDROCK_STREAM_TIMEOUT_SECS").map(Duration::from_secs);
+        let buffer = number(secrets, "BEDROCK_STREAM_BUFFER").unwrap_or(DEFAULT_STREAM_BUFFER);
+        assert!(buffer > 0, "BEDROCK_STREAM_BUFFER must be at least 1");
+
+        Self {
+            heartbeat: (heartbeat > 0).then(|| Duration::from_secs(heartbeat)),
+            flush_bytes,
+            flush_interval,
+            timeout,
+            buffer,
+        }
+    }
+}
+
+/// How `/chat` conversations are sent to the model, see [`crate::chat`].
+pub struct ChatConfig {
+    pub truncation: Truncation,
+    /// Once a stored conversation has this many turns that haven't been summarized, the older
+    /// ones are folded into its summary. `None` turns summaries off.
+    pub summarize_after: Option<usize>,
+    /// How many of the latest turns are left out of the summary and sent as they are.
+    pub summary_keep_turns: usize,
+    /// Summaries, titles and query rewrites don't need a smart model, so this defaults to the
+    /// default one.
+    pub summary_model_id: Option<String>,
+    /// Whether conversations without a title get one written for them after the first reply.
+    pub auto_titles: bool,
+    /// Conversations are deleted once they've gone this long without a message, unless they
+    /// have their own retention period. `None` keeps them forever.
+    pub retention_hours: Option<i32>,
+}
+
+impl ChatConfig {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        // eg. "drop-oldest", or "last-turns:10" to only ever send the last 10 turns
+        let truncation = secrets
+            .get("BEDROCK_CHAT_TRUNCATION")
+            .map(|value| {
+                Truncation::parse(value.trim())
+                    .unwrap_or_else(|| panic!("{value} is not a valid BEDROCK_CHAT_TRUNCATION"))
+            })
+            .unwrap_or(Truncation::DropOldest);
+        // eg. "20", or "0" to never summarize
+        let summarize_after =
+            number(secrets, "BEDROCK_SUMMARIZE_AFTER_TURNS").unwrap_or(DEFAULT_SUMMARIZE_AFTER);
+        let summary_keep_turns =
+            number(secrets, "BEDROCK_SUMMARY_KEEP_TURNS").unwrap_or(DEFAULT_SUMMARY_KEEP_TURNS);
+        assert!(
+            summarize_after == 0 || summary_keep_turns < summarize_after,
+            "BEDROCK_SUMMARY_KEEP_TURNS must be less than BEDROCK_SUMMARIZE_AFTER_TURNS"
+        );
+        // eg. "amazon.titan-text-lite-v1"
+        let summary_model_id = secrets.get("BEDROCK_SUMMARY_MODEL_ID");
+        // on unless it's "false"
+        let auto_titles = secrets
+            .get("BEDROCK_AUTO_TITLES")
+            .is_none_or(|value| !value.trim().eq_ignore_ascii_case("false"));
+        // eg. "720" for 30 days
+        let retention_hours = number(secrets, "BEDROCK_CONVERSATION_RETENTION_HOURS");
+        assert!(
+            retention_hours.is_none_or(|hours| hours > 0),
+            "BEDROCK_CONVERSATION_RETENTION_HOURS must be at least 1"
+        );
+
+        Self {
+            truncation,
+            summarize_after: (summarize_after > 0).then_some(summarize_after),
+            summary_keep_turns,
+            summary_model_id,
+            auto_titles,
+            retention_hours,
+        }
+    }
+}
+
+/// How `/documents` are split up before they're embedded, see [`crate::documents`], and how
+/// they're searched.
+pub struct DocumentsConfig {
+    /// Requests can ask for another one, see [`Strategy`].
+    pub chunking: Strategy,
+    /// In characters, and in tokens for documents chunked by tokens. Requests can ask for
+    /// something else.
+    pub chunk_size: usize,
+    /// How many characters each chunk repeats from the end of the one before it, so something
+    /// split across two chunks can still be found in one of them.
+    pub chunk_overlap: usize,
+    /// Used when a search asks for its results to be reranked.
+    pub rerank_model_id: String,
+    /// How much of what's left of the model's context window `/ask` fills with chunks, after
+    /// the question and room for the answer. Between 0 and 1.
+    pub context_share: f32,
+}
+
+impl DocumentsConfig {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        // eg. "sentences"
+        let chunking = secrets
+            .get("BEDROCK_CHUNKING")
+            .map(|value| {
+                Strategy::parse(value.trim())
+                    .unwrap_or_else(|| panic!("{value} is not a valid BEDROCK_CHUNKING"))
+            })
+            .unwrap_or_default();
+        // eg. "1000" and "200"
+        let chunk_size = number(secrets, "BEDROCK_CHUNK_SIZE").unwrap_or(DEFAULT_CHUNK_SIZE);
+        let chunk_overlap =
+            number(secrets, "BEDROCK_CHUNK_OVERLAP").unwrap_or(DEFAULT_CHUNK_OVERLAP);
+        assert!(
+            chunk_overlap < chunk_size,
+            "BEDROCK_CHUNK_OVERLAP must be less than BEDROCK_CHUNK_SIZE"
+        );
+
+        // eg. "amazon.rerank-v1:0"
+        let rerank_model_id = secrets
+            .get("BEDROCK_RERANK_MODEL_ID")
+            .unwrap_or_else(|| DEFAULT_RERANK_MODEL_ID.to_string());
+        // eg. "0.5"
+        let context_share =
+            number(secrets, "BEDROCK_CONTEXT_SHARE").unwrap_or(DEFAULT_CONTEXT_SHARE);
+        assert!(
+            context_share > 0.0 && context_share <= 1.0,
+            "BEDROCK_CONTEXT_SHARE must be more than 0 and at most 1"
+        );
+
+        Self {
+            chunking,
+            chunk_size,
+            chunk_overlap,
+            rerank_model_id,
+            context_share,
+        }
+    }
+}
+
+/// What to do with a conversation that's too long for the model's context window. Either way
+/// the system prompt and the latest message are always kept.
+#[derive(Clone, Copy)]
+pub enum Truncation {
+    /// Drop the oldest turns until it fits.
+    DropOldest,
+    /// Only send this many of the latest turns, and drop even those if they don't fit.
+    LastTurns(usize),
+}
+
+impl Truncation {
+    fn parse(value: &str) -> Option<Self> {
+        if value == "drop-oldest" {
+            return Some(Self::DropOldest);
+        }
+        let turns = value.strip_prefix("last-turns:")?.trim().parse().ok()?;
+
+        Some(Self::LastTurns(turns))
+    }
+}
@@ -0,0 +1,961 @@
+//! Conversations kept in Postgres, so clients only have to send their newest messages to `/chat`.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{MatchedPath, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{types::Json as JsonColumn, FromRow, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    chat::{self, ChatMessage, Role, Turn, TurnOptions},
+    error::{ApiError, Violation},
+    limits::{self, MaxTokensLimit},
+    models::params::GenerationParams,
+    AppState,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+const MAX_LABELS: usize = 20;
+const MAX_LABEL_LENGTH: usize = 256;
+const CONVERSATION_COLUMNS: &str = "id, user_id, title, system_prompt, forked_from, metadata, \
+     tags, retention_hours, created_at, updated_at";
+/// A message's role, if the message is in the conversation and the conversation is the caller's.
+const OWNED_MESSAGE_ROLE: &str = "SELECT messages.role FROM messages \
+     JOIN conversations ON conversations.id = messages.conversation_id \
+     WHERE messages.id = $1 AND messages.conversation_id = $2 \
+     AND conversations.owner IS NOT DISTINCT FROM $3";
+
+#[derive(Serialize, FromRow)]
+pub struct Conversation {
+    id: Uuid,
+    /// Who the conversation is with, for their memories.
+    user_id: Option<String>,
+    title: Option<String>,
+    /// Sent on every turn in place of the deployment's system prompt, eg. for a persona.
+    system_prompt: Option<String>,
+    /// The conversation this one was forked from, while it still exists.
+    forked_from: Option<Uuid>,
+    /// Whatever the client wants to segment conversations by, eg. `{"app": "support"}`.
+    metadata: serde_json::Value,
+    tags: Vec<String>,
+    /// How long after its last message it's deleted, if not the deployment's retention period.
+    retention_hours: Option<i32>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// A message as it's stored, rather than as it's sent to the model.
+#[derive(Serialize, FromRow)]
+pub struct StoredMessage {
+    id: i64,
+    role: Role,
+    content: String,
+    /// Which model wrote it and how, for replies.
+    model: Option<String>,
+    params: Option<serde_json::Value>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    #[serde(default = "default_page_size")]
+    limit: i64,
+    /// `next_cursor` from the previous page.
+    cursor: Option<String>,
+    #[serde(default)]
+    sort: SortBy,
+    #[serde(default)]
+    order: Order,
+    tags: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    updated_after: Option<DateTime<Utc>>,
+    updated_before: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    #[default]
+    UpdatedAt,
+    CreatedAt,
+}
+
+impl SortBy {
+    fn column(self) -> &'static str {
+        match self {
+            SortBy::UpdatedAt => "updated_at",
+            SortBy::CreatedAt => "created_at",
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+pub fn default_page_size() -> i64 {
+    DEFAULT_PAGE_SIZE
+}
+
+pub fn page_violations(limit: i64, offset: i64) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        violations.push(Violation::new(
+            "limit",
+            format!("must be between 1 and {MAX_PAGE_SIZE}"),
+        ));
+    }
+    if offset < 0 {
+        violations.push(Violation::new("offset", "must not be negative"));
+    }
+
+    violations
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    #[serde(default = "default_page_size")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+/// A conversation with the message in it that best matches the search.
+#[derive(Serialize, FromRow)]
+pub struct SearchResult {
+    #[sqlx(flatten)]
+    conversation: Conversation,
+    message_id: i64,
+    /// The matching parts of the message, with the matched words in `<mark>` tags.
+    snippet: String,
+    rank: f32,
+}
+
+#[derive(Deserialize, Default)]
+pub struct NewConversation {
+    user_id: Option<String>,
+    title: Option<String>,
+    system_prompt: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    retention_hours: Option<i32>,
+}
+
+/// Anything left out stays as it is. An empty `system_prompt` removes it, and `metadata` and
+/// `tags` replace what was there.
+#[derive(Deserialize)]
+pub struct Changes {
+    title: Option<String>,
+    system_prompt: Option<String>,
+    metadata: Option<HashMap<String, String>>,
+    tags: Option<Vec<String>>,
+    retention_hours: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct Fork {
+    /// The last message to copy into the fork, which has to be a reply.
+    message_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct Edit {
+    content: String,
+    #[serde(flatten)]
+    options: TurnOptions,
+}
+
+/// Starts an empty conversation, returning its id for `/chat`. The body is optional.
+pub async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<NewConversation>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let NewConversation {
+        user_id,
+        title,
+        system_prompt,
+        metadata,
+        tags,
+        retention_hours,
+    } = body.map(|Json(body)| body).unwrap_or_default();
+    let mut violations = label_violations(Some(&metadata), Some(&tags));
+    violations.extend(retention_violation(retention_hours));
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let id: Uuid = sqlx::query_scalar(
+        "INSERT INTO conversations \
+         (title, system_prompt, metadata, tags, retention_hours, user_id, owner) \
+         VALUES (NULLIF(trim($1), ''), NULLIF($2, ''), $3, $4, $5, $6, $7) RETURNING id",
+    )
+    .bind(title)
+    .bind(system_prompt)
+    .bind(JsonColumn(metadata))
+    .bind(tags)
+    .bind(retention_hours)
+    .bind(user_id)
+    .bind(owner(&headers))
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(json!({ "id": id }))))
+}
+
+/// The caller's conversations, most recently active first unless `sort` and `order` say
+/// otherwise. They can be filtered by when they were created or last active, by tags (eg.
+/// `?tags=beta,urgent` for conversations with both) and by metadata (eg. `?metadata.app=support`).
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(ListQuery {
+        limit,
+        cursor,
+        sort,
+        order,
+        tags,
+        created_after,
+        created_before,
+        updated_after,
+        updated_before,
+    }): Query<ListQuery>,
+    Query(filters): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut violations = page_violations(limit, 0);
+    let cursor = match cursor.as_deref().map(parse_cursor) {
+        Some(None) => {
+            violations.push(Violation::new("cursor", "isn't one from a previous page"));
+            None
+        }
+        Some(cursor) => cursor,
+        None => None,
+    };
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+    let tags: Vec<&str> = tags
+        .as_deref()
+        .map(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let metadata: HashMap<&str, &str> = filters
+        .iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix("metadata.")?, value.as_str())))
+        .collect();
+
+    let column = sort.column();
+    let (direction, after) = match order {
+        Order::Asc => ("ASC", ">"),
+        Order::Desc => ("DESC", "<"),
+    };
+    // one more than asked for, to find out if there's another page
+    let mut conversations: Vec<Conversation> = sqlx::query_as(&format!(
+        "SELECT {CONVERSATION_COLUMNS} FROM conversations \
+         WHERE owner IS NOT DISTINCT FROM $1 AND tags @> $2 AND metadata @> $3 \
+         AND ($4::timestamptz IS NULL OR created_at > $4) \
+         AND ($5::timestamptz IS NULL OR created_at < $5) \
+         AND ($6::timestamptz IS NULL OR updated_at > $6) \
+         AND ($7::timestamptz IS NULL OR updated_at < $7) \
+         AND ($8::timestamptz IS NULL OR ({column}, id) {after} ($8, $9::uuid)) \
+         ORDER BY {column} {direction}, id {direction} LIMIT $10"
+    ))
+    .bind(owner(&headers))
+    .bind(tags)
+    .bind(JsonColumn(metadata))
+    .bind(created_after)
+    .bind(created_before)
+    .bind(updated_after)
+    .bind(updated_before)
+    .bind(cursor.map(|(at, _)| at))
+    .bind(cursor.map(|(_, id)| id))
+    .bind(limit + 1)
+    .fetch_all(&state.db)
+    .await?;
+    let next_cursor = (conversations.len() as i64 > limit).then(|| {
+        conversations.truncate(limit as usize);
+        let last = &conversations[conversations.len() - 1];
+        let at = match sort {
+            SortBy::UpdatedAt => last.updated_at,
+            SortBy::CreatedAt => last.created_at,
+        };
+
+        BASE64_URL_SAFE_NO_PAD.encode(format!("{}|{}", at.timestamp_micros(), last.id))
+    });
+
+    Ok(Json(json!({
+        "conversations": conversations,
+        "next_cursor": next_cursor,
+    })))
+}
+
+/// Where the previous page left off.
+fn parse_cursor(cursor: &str) -> Option<(DateTime<Utc>, Uuid)> {
+    let cursor = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (at, id) = cursor.split_once('|')?;
+
+    Some((
+        DateTime::from_timestamp_micros(at.parse().ok()?)?,
+        id.parse().ok()?,
+    ))
+}
+
+/// Conversations with messages matching `q`, which can use web search syntax like quoted
+/// phrases and `-excluded` words. The best matches come first.
+pub async fn search(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(SearchQuery { q, limit, offset }): Query<SearchQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut violations = page_violations(limit, offset);
+    if q.trim().is_empty() {
+        violations.push(Violation::new("q", "must not be empty"));
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    // one more than asked for, to find out if there's another page
+    let mut results: Vec<SearchResult> = sqlx::query_as(&format!(
+        "SELECT {CONVERSATION_COLUMNS}, message_id, snippet, rank FROM ( \
+             SELECT DISTINCT ON (messages.conversation_id) messages.conversation_id, \
+             messages.id AS message_id, ts_rank(messages.search, query) AS rank, \
+             ts_headline('english', messages.content, query, \
+                 'StartSel=<mark>, StopSel=</mark>, MaxFragments=2') AS snippet \
+             FROM messages, websearch_to_tsquery('english', $1) AS query \
+             WHERE messages.search @@ query \
+             ORDER BY messages.conversation_id, rank DESC \
+         ) AS matches JOIN conversations ON conversations.id = matches.conversation_id \
+         WHERE owner IS NOT DISTINCT FROM $4 \
+         ORDER BY rank DESC, id LIMIT $2 OFFSET $3"
+    ))
+    .bind(q.trim())
+    .bind(limit + 1)
+    .bind(offset)
+    .bind(owner(&headers))
+    .fetch_all(&state.db)
+    .await?;
+    let has_more = results.len() as i64 > limit;
+    results.truncate(limit as usize);
+
+    Ok(Json(json!({
+        "results": results,
+        "has_more": has_more,
+    })))
+}
+
+/// The conversation and every message in it, including any that have been summarized.
+pub async fn get(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    let owner = owner(&headers);
+    let Some(conversation) = find(&state.db, id, owner.as_deref()).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let messages = messages(&state.db, id, owner.as_deref()).await?;
+
+    Ok(Json(json!({
+        "conversation": conversation,
+        "messages": messages,
+    })))
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Markdown,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// The whole transcript as a file to download, with which model wrote each reply and how.
+pub async fn export(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Query(ExportQuery { format }): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let owner = owner(&headers);
+    let Some(conversation) = find(&state.db, id, owner.as_deref()).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let messages = messages(&state.db, id, owner.as_deref()).await?;
+
+    let (content_type, extension, body) = match format {
+        ExportFormat::Json => (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&json!({
+                "conversation": conversation,
+                "messages": messages,
+            }))
+            .unwrap(),
+        ),
+        ExportFormat::Markdown => (
+            "text/markdown; charset=utf-8",
+            "md",
+            markdown(&conversation, &messages),
+        ),
+    };
+    let disposition = format!("attachment; filename=\"conversation-{id}.{extension}\"");
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn markdown(conversation: &Conversation, messages: &[StoredMessage]) -> String {
+    let title = conversation.title.as_deref().unwrap_or("Conversation");
+    let mut markdown = format!(
+        "# {title}\n\nStarted {}, last active {}.\n",
+        conversation.created_at.to_rfc3339(),
+        conversation.updated_at.to_rfc3339()
+    );
+    if let Some(system_prompt) = &conversation.system_prompt {
+        markdown.push_str(&format!("\n## System prompt\n\n{system_prompt}\n"));
+    }
+
+    for message in messages {
+        let speaker = match message.role {
+            Role::System => "System",
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        };
+        markdown.push_str(&format!("\n## {speaker}\n\n"));
+        if let Some(model) = &message.model {
+            markdown.push_str(&format!("_{model}_"));
+            // only the parameters that were set, rather than every one there is
+            if let Some(serde_json::Value::Object(params)) = &message.params {
+                let params: Vec<String> = params
+                    .iter()
+                    .filter(|(_, value)| !value.is_null())
+                    .map(|(name, value)| format!("{name}: {value}"))
+                    .collect();
+                if !params.is_empty() {
+                    markdown.push_str(&format!(" ({})", params.join(", ")));
+                }
+            }
+            markdown.push_str("\n\n");
+        }
+        markdown.push_str(&format!("{}\n", message.content));
+    }
+
+    markdown
+}
+
+pub async fn update(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Json(Changes {
+        title,
+        system_prompt,
+        metadata,
+        tags,
+        retention_hours,
+    }): Json<Changes>,
+) -> Result<impl IntoResponse, ApiError> {
+    let title = title.as_deref().map(str::trim);
+    let mut violations = label_violations(metadata.as_ref(), tags.as_deref());
+    violations.extend(retention_violation(retention_hours));
+    if title.is_some_and(str::is_empty) {
+        violations.push(Violation::new("title", "must not be empty"));
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let conversation: Option<Conversation> = sqlx::query_as(&format!(
+        "UPDATE conversations SET title = COALESCE($2, title), \
+         system_prompt = CASE WHEN $3::text IS NULL THEN system_prompt ELSE NULLIF($3, '') END, \
+         metadata = COALESCE($4, metadata), tags = COALESCE($5, tags), \
+         retention_hours = COALESCE($6, retention_hours) \
+         WHERE id = $1 AND owner IS NOT DISTINCT FROM $7 RETURNING {CONVERSATION_COLUMNS}"
+    ))
+    .bind(id)
+    .bind(title)
+    .bind(system_prompt)
+    .bind(metadata.map(JsonColumn))
+    .bind(tags)
+    .bind(retention_hours)
+    .bind(owner(&headers))
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(conversation) = conversation else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    Ok(Json(conversation))
+}
+
+/// Copies the conversation up to and including one of its replies into a new conversation, so
+/// it can go somewhere else from there. The original is left as it is.
+pub async fn fork(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Json(Fork { message_id }): Json<Fork>,
+) -> Result<impl IntoResponse, ApiError> {
+    let role: Option<Role> = sqlx::query_scalar(OWNED_MESSAGE_ROLE)
+        .bind(message_id)
+        .bind(id)
+        .bind(owner(&headers))
+        .fetch_optional(&state.db)
+        .await?;
+    let Some(role) = role else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    // otherwise the next message from the user wouldn't alternate
+    if role != Role::Assistant {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "message_id",
+            "must be a reply from the assistant",
+        )]));
+    }
+
+    let mut tx = state.db.begin().await?;
+    // the summary isn't copied, as it could cover messages after the fork
+    let conversation: Conversation = sqlx::query_as(&format!(
+        "INSERT INTO conversations \
+         (user_id, title, system_prompt, forked_from, metadata, tags, retention_hours, owner) \
+         SELECT user_id, title, system_prompt, id, metadata, tags, retention_hours, $2 \
+         FROM conversations WHERE id = $1 \
+         RETURNING {CONVERSATION_COLUMNS}"
+    ))
+    .bind(id)
+    .bind(owner(&headers))
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO messages (conversation_id, role, content, model, params, created_at) \
+         SELECT $2, role, content, model, params, created_at FROM messages \
+         WHERE conversation_id = $1 AND id <= $3 ORDER BY id",
+    )
+    .bind(id)
+    .bind(conversation.id)
+    .bind(message_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(conversation)))
+}
+
+/// Replaces the last reply in the conversation with a new one, which can be from another model
+/// or with other parameters. The old reply is kept if generating the new one fails.
+pub async fn regenerate(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Json(options): Json<TurnOptions>,
+) -> Result<Response, ApiError> {
+    let last: Option<(i64, Role)> = sqlx::query_as(
+        "SELECT messages.id, messages.role FROM messages \
+         JOIN conversations ON conversations.id = messages.conversation_id \
+         WHERE messages.conversation_id = $1 AND conversations.owner IS NOT DISTINCT FROM $2 \
+         ORDER BY messages.id DESC LIMIT 1",
+    )
+    .bind(id)
+    .bind(owner(&headers))
+    .fetch_optional(&state.db)
+    .await?;
+    let Some((message_id, role)) = last else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    // if generating the last reply failed there's nothing to replace, only a reply to write
+    let turn = Turn {
+        conversation_id: Some(id),
+        user_id: None,
+        messages: Vec::new(),
+        replacing: (role == Role::Assistant).then_some(Replacing::Reply(message_id)),
+        options,
+    };
+    chat::respond(&state, &route, max_tokens_limit, &headers, turn).await
+}
+
+/// Changes one of the user's messages, dropping everything after it, and replies to it again.
+/// Nothing changes if the reply can't be generated.
+pub async fn edit(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    headers: HeaderMap,
+    Path((id, message_id)): Path<(Uuid, i64)>,
+    Json(Edit { content, options }): Json<Edit>,
+) -> Result<Response, ApiError> {
+    if content.trim().is_empty() {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "content",
+            "must not be empty",
+        )]));
+    }
+
+    let role: Option<Role> = sqlx::query_scalar(OWNED_MESSAGE_ROLE)
+        .bind(message_id)
+        .bind(id)
+        .bind(owner(&headers))
+        .fetch_optional(&state.db)
+        .await?;
+    let Some(role) = role else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    if role != Role::User {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "message_id",
+            "must be a message from the user",
+        )]));
+    }
+
+    let turn = Turn {
+        conversation_id: Some(id),
+        user_id: None,
+        messages: Vec::new(),
+        replacing: Some(Replacing::Edit {
+            message_id,
+            content,
+        }),
+        options,
+    };
+    chat::respond(&state, &route, max_tokens_limit, &headers, turn).await
+}
+
+/// A summary that covers a message that's been changed or removed is out of date, so the
+/// conversation is sent as it is until it's summarized again.
+async fn forget_summary(
+    tx: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    message_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE conversations SET summary = NULL, summarized_through = 0 \
+         WHERE id = $1 AND summarized_through >= $2",
+    )
+    .bind(id)
+    .bind(message_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes the conversation along with its messages and any sessions for it.
+pub async fn delete(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let deleted =
+        sqlx::query("DELETE FROM conversations WHERE id = $1 AND owner IS NOT DISTINCT FROM $2")
+            .bind(id)
+            .bind(owner(&headers))
+            .execute(&state.db)
+            .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Keeps labels small enough that they can't be used to store documents.
+pub fn label_violations(
+    metadata: Option<&HashMap<String, String>>,
+    tags: Option<&[String]>,
+) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if let Some(metadata) = metadata {
+        if metadata.len() > MAX_LABELS {
+            violations.push(Violation::new(
+                "metadata",
+                format!("can have at most {MAX_LABELS} keys"),
+            ));
+        }
+        if metadata.iter().any(|(key, value)| {
+            key.is_empty() || key.len() > MAX_LABEL_LENGTH || value.len() > MAX_LABEL_LENGTH
+        }) {
+            violations.push(Violation::new(
+                "metadata",
+                format!("must have non-empty keys, and keys and values of at most {MAX_LABEL_LENGTH} bytes"),
+            ));
+        }
+    }
+    if let Some(tags) = tags {
+        if tags.len() > MAX_LABELS {
+            violations.push(Violation::new(
+                "tags",
+                format!("can have at most {MAX_LABELS} tags"),
+            ));
+        }
+        // tags are filtered on with a comma separated list
+        if tags
+            .iter()
+            .any(|tag| tag.trim().is_empty() || tag.contains(',') || tag.len() > MAX_LABEL_LENGTH)
+        {
+            violations.push(Violation::new(
+                "tags",
+                format!(
+                    "must not be empty or have commas, and can be at most {MAX_LABEL_LENGTH} bytes"
+                ),
+            ));
+        }
+    }
+
+    violations
+}
+
+fn retention_violation(retention_hours: Option<i32>) -> Option<Violation> {
+    retention_hours
+        .is_some_and(|hours| hours < 1)
+        .then(|| Violation::new("retention_hours", "must be at least 1"))
+}
+
+/// Whose conversations these are, which is a hash of the caller's API key so the keys themselves
+/// aren't stored.
+pub fn owner(headers: &HeaderMap) -> Option<String> {
+    limits::api_key(headers).map(key_hash)
+}
+
+pub fn key_hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key))
+}
+
+/// `None` if there's no such conversation, or it isn't `owner`'s.
+async fn find(
+    db: &PgPool,
+    id: Uuid,
+    owner: Option<&str>,
+) -> Result<Option<Conversation>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "SELECT {CONVERSATION_COLUMNS} FROM conversations \
+         WHERE id = $1 AND owner IS NOT DISTINCT FROM $2"
+    ))
+    .bind(id)
+    .bind(owner)
+    .fetch_optional(db)
+    .await
+}
+
+async fn messages(
+    db: &PgPool,
+    id: Uuid,
+    owner: Option<&str>,
+) -> Result<Vec<StoredMessage>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT messages.id, messages.role, messages.content, messages.model, messages.params, \
+         messages.created_at FROM messages \
+         JOIN conversations ON conversations.id = messages.conversation_id \
+         WHERE messages.conversation_id = $1 AND conversations.owner IS NOT DISTINCT FROM $2 \
+         ORDER BY messages.id",
+    )
+    .bind(id)
+    .bind(owner)
+    .fetch_all(db)
+    .await
+}
+
+/// What's sent to the model for a stored conversation.
+#[derive(FromRow)]
+pub struct History {
+    pub user_id: Option<String>,
+    pub system_prompt: Option<String>,
+    /// Of the messages before `messages`, if any have been summarized.
+    pub summary: Option<String>,
+    summarized_through: i64,
+    #[sqlx(skip)]
+    pub messages: Vec<ChatMessage>,
+}
+
+/// What a new reply takes the place of in a conversation.
+pub enum Replacing {
+    /// The last reply.
+    Reply(i64),
+    /// One of the user's messages, which gets this content, and everything after it.
+    Edit { message_id: i64, content: String },
+}
+
+impl Replacing {
+    /// The first message that's changed or dropped, which a summary can't cover any more.
+    fn first(&self) -> i64 {
+        match self {
+            Self::Reply(message_id) | Self::Edit { message_id, .. } => *message_id,
+        }
+    }
+
+    /// Everything from here on is dropped.
+    fn dropped_from(&self) -> i64 {
+        match self {
+            Self::Reply(message_id) => *message_id,
+            Self::Edit { message_id, .. } => message_id + 1,
+        }
+    }
+
+    fn edit(&self) -> Option<(i64, &str)> {
+        match self {
+            Self::Reply(_) => None,
+            Self::Edit {
+                message_id,
+                content,
+            } => Some((*message_id, content)),
+        }
+    }
+}
+
+/// The conversation's summary and every message since, oldest first, or `None` if there's no
+/// such conversation or it isn't `owner`'s. It's as it'll be once whatever it's `replacing` has
+/// been replaced.
+pub async fn history(
+    db: &PgPool,
+    id: Uuid,
+    owner: Option<&str>,
+    replacing: Option<&Replacing>,
+) -> Result<Option<History>, sqlx::Error> {
+    let history: Option<History> = sqlx::query_as(
+        "SELECT user_id, system_prompt, summary, summarized_through FROM conversations \
+         WHERE id = $1 AND owner IS NOT DISTINCT FROM $2",
+    )
+    .bind(id)
+    .bind(owner)
+    .fetch_optional(db)
+    .await?;
+    let Some(mut history) = history else {
+        return Ok(None);
+    };
+    // the summary would still have the old messages in it
+    if replacing.is_some_and(|replacing| history.summarized_through >= replacing.first()) {
+        history.summary = None;
+        history.summarized_through = 0;
+    }
+
+    let edit = replacing.and_then(Replacing::edit);
+    history.messages = sqlx::query_as(
+        "SELECT role, CASE WHEN id = $4 THEN $5::text ELSE content END AS content \
+         FROM messages WHERE conversation_id = $1 AND id > $2 \
+         AND ($3::bigint IS NULL OR id < $3) ORDER BY id",
+    )
+    .bind(id)
+    .bind(history.summarized_through)
+    .bind(replacing.map(Replacing::dropped_from))
+    .bind(edit.map(|(message_id, _)| message_id))
+    .bind(edit.map(|(_, content)| content))
+    .fetch_all(db)
+    .await?;
+
+    Ok(Some(history))
+}
+
+/// Adds the client's new messages and the model's reply to the conversation, in place of
+/// whatever it's `replacing`, all or nothing.
+pub async fn append(
+    db: &PgPool,
+    id: Uuid,
+    messages: &[ChatMessage],
+    reply: &str,
+    model_id: &str,
+    params: &GenerationParams,
+    replacing: Option<&Replacing>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    if let Some(replacing) = replacing {
+        sqlx::query("DELETE FROM messages WHERE conversation_id = $1 AND id >= $2")
+            .bind(id)
+            .bind(replacing.dropped_from())
+            .execute(&mut *tx)
+            .await?;
+        if let Some((message_id, content)) = replacing.edit() {
+            sqlx::query("UPDATE messages SET content = $2 WHERE id = $1")
+                .bind(message_id)
+                .bind(content)
+                .execute(&mut *tx)
+                .await?;
+        }
+        forget_summary(&mut tx, id, replacing.first()).await?;
+    }
+
+    for message in messages {
+        sqlx::query("INSERT INTO messages (conversation_id, role, content) VALUES ($1, $2, $3)")
+            .bind(id)
+            .bind(message.role)
+            .bind(&message.content)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query(
+        "INSERT INTO messages (conversation_id, role, content, model, params) \
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(id)
+    .bind(Role::Assistant)
+    .bind(reply)
+    .bind(model_id)
+    .bind(JsonColumn(params))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE conversations SET updated_at = now() WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_are_where_the_last_page_ended() {
+        let id = Uuid::new_v4();
+        let cursor = BASE64_URL_SAFE_NO_PAD.encode(format!("1700000000123456|{id}"));
+
+        let (at, parsed) = parse_cursor(&cursor).unwrap();
+
+        assert_eq!(at.timestamp_micros(), 1_700_000_000_123_456);
+        assert_eq!(parsed, id);
+    }
+
+    #[test]
+    fn malformed_cursors_are_none() {
+        assert!(parse_cursor("not base64!").is_none());
+        assert!(parse_cursor(&BASE64_URL_SAFE_NO_PAD.encode("1700000000")).is_none());
+        assert!(parse_cursor(&BASE64_URL_SAFE_NO_PAD.encode("soon|not-a-uuid")).is_none());
+    }
+}
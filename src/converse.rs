@@ -0,0 +1,156 @@
+//! Helpers for the Converse API, which gives us one request shape for every model family.
+
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ContentBlockDelta, ConversationRole, ConverseOutput, ConverseStreamOutput,
+    InferenceConfiguration, Message, SystemContentBlock,
+};
+
+use aws_smithy_types::{Document, Number};
+
+use crate::{
+    attachments::Image,
+    chat::ChatMessage,
+    models::{params::GenerationParams, with_system_prefix, Model},
+};
+
+/// Images go before the text, which is what Claude recommends for questions about them.
+pub fn user_message(prompt: String, images: &[Image]) -> Option<Message> {
+    let content = images
+        .iter()
+        .map(Image::content_block)
+        .collect::<Option<Vec<_>>>()?;
+
+    Message::builder()
+        .role(ConversationRole::User)
+        .set_content(Some(content))
+        .content(ContentBlock::Text(prompt))
+        .build()
+        .ok()
+}
+
+/// The earlier turns of a chat, then the prompt.
+pub fn messages(history: &[ChatMessage], prompt: String, images: &[Image]) -> Option<Vec<Message>> {
+    let mut messages = history
+        .iter()
+        .map(|message| {
+            Message::builder()
+                .role(message.role.conversation_role())
+                .content(ContentBlock::Text(message.content.clone()))
+                .build()
+                .ok()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    messages.push(user_message(prompt, images)?);
+
+    Some(messages)
+}
+
+/// Splits the system prompt out for models that take one, otherwise it's prefixed to `prompt`.
+pub fn system_prompt(
+    model: &Model,
+    system: Option<String>,
+    prompt: String,
+) -> (String, Option<Vec<SystemContentBlock>>) {
+    if model.provider.supports_system_prompt() {
+        (
+            prompt,
+            system.map(|system| vec![SystemContentBlock::Text(system)]),
+        )
+    } else {
+        (with_system_prefix(system.as_deref(), prompt), None)
+    }
+}
+
+/// Returns `None` if the prompt leaves no room for the model to respond.
+pub fn inference_config(
+    model: &Model,
+    prompt: &str,
+    params: &GenerationParams,
+) -> Option<InferenceConfiguration> {
+    let max_tokens = model.provider.max_tokens(prompt, params.max_tokens)?;
+
+    Some(
+        InferenceConfiguration::builder()
+            .max_tokens(max_tokens)
+            .temperature(params.temperature.unwrap_or(0.0))
+            .set_top_p(params.top_p)
+            .set_stop_sequences(params.stop_sequences.clone())
+            .build(),
+    )
+}
+
+/// Family-specific parameters (eg. `top_k`) for `additionalModelRequestFields`.
+pub fn additional_fields(model: &Model, params: &GenerationParams) -> Option<Document> {
+    model.provider.additional_fields(params).map(document)
+}
+
+/// The other way round, for documents Bedrock sends back.
+pub fn json(document: &Document) -> serde_json::Value {
+    match document {
+        Document::Null => serde_json::Value::Null,
+        Document::Bool(value) => (*value).into(),
+        Document::Number(Number::PosInt(value)) => (*value).into(),
+        Document::Number(Number::NegInt(value)) => (*value).into(),
+        Document::Number(Number::Float(value)) => (*value).into(),
+        Document::String(value) => value.as_str().into(),
+        Document::Array(values) => values.iter().map(json).collect(),
+        Document::Object(values) => values
+            .iter()
+            .map(|(key, value)| (key.clone(), json(value)))
+            .collect(),
+    }
+}
+
+fn document(value: serde_json::Value) -> Document {
+    match value {
+        serde_json::Value::Null => Document::Null,
+        serde_json::Value::Bool(value) => Document::Bool(value),
+        serde_json::Value::Number(value) => Document::Number(if let Some(value) = value.as_u64() {
+            Number::PosInt(value)
+        } else if let Some(value) = value.as_i64() {
+            Number::NegInt(value)
+        } else {
+            Number::Float(value.as_f64().unwrap_or_default())
+        }),
+        serde_json::Value::String(value) => Document::String(value),
+        serde_json::Value::Array(values) => {
+            Document::Array(values.into_iter().map(document).collect())
+        }
+        serde_json::Value::Object(values) => Document::Object(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, document(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Joins the text blocks of the model's reply, skipping anything else (eg. tool use).
+pub fn output_text(output: ConverseOutput) -> Option<String> {
+    let ConverseOutput::Message(message) = output else {
+        return None;
+    };
+
+    Some(
+        message
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text(text) => Some(text),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// The text from a streamed event. `messageStart`, `messageStop` and `metadata` events have
+/// nothing to add to a plain text stream, so they are skipped along with non-text deltas.
+pub fn delta_text(event: ConverseStreamOutput) -> Option<String> {
+    match event {
+        ConverseStreamOutput::ContentBlockDelta(event) => match event.delta? {
+            ContentBlockDelta::Text(text) => Some(text),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,155 @@
+use serde::Deserialize;
+
+/// How many characters a token is taken to be, like [`crate::models::estimate_tokens`].
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// How a document is split into chunks. Sizes and overlaps are in characters, except for
+/// `Tokens`.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Fixed-size chunks, see [`chunks`].
+    #[default]
+    Characters,
+    /// Like `Characters`, but sized in tokens going by the usual estimate.
+    Tokens,
+    /// As many whole sentences as fit, see [`sentence_chunks`].
+    Sentences,
+    /// Split at markdown headings first and then into sentences, so no chunk is from under two
+    /// headings.
+    Markdown,
+}
+
+impl Strategy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "characters" => Some(Self::Characters),
+            "tokens" => Some(Self::Tokens),
+            "sentences" => Some(Self::Sentences),
+            "markdown" => Some(Self::Markdown),
+            _ => None,
+        }
+    }
+
+    /// How many characters sizes and overlaps are counted in.
+    pub fn unit(self) -> usize {
+        match self {
+            Self::Tokens => CHARS_PER_TOKEN,
+            _ => 1,
+        }
+    }
+
+    pub fn chunks(self, text: &str, size: usize, overlap: usize) -> Vec<&str> {
+        match self {
+            Self::Characters | Self::Tokens => {
+                chunks(text, size * self.unit(), overlap * self.unit())
+            }
+            Self::Sentences | Self::Markdown => sentence_chunks(text, size, overlap),
+        }
+    }
+}
+
+/// Splits text into chunks of at most `size` characters, each starting `overlap` characters
+/// before the last one ended. Chunks end at whitespace where they can, so words aren't cut in
+/// half, and are trimmed.
+pub fn chunks(text: &str, size: usize, overlap: usize) -> Vec<&str> {
+    // by character rather than byte, so a chunk never ends partway through one
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < chars.len() {
+        let mut end = (start + size).min(chars.len());
+        if end < chars.len() {
+            // only look back over the second half, so a long word doesn't make tiny chunks
+            if let Some(space) = (start + size / 2..end)
+                .rev()
+                .find(|&index| chars[index].1.is_whitespace())
+            {
+                end = space + 1;
+            }
+        }
+
+        let from = chars[start].0;
+        let to = chars.get(end).map_or(text.len(), |(index, _)| *index);
+        let chunk = text[from..to].trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+
+        if end == chars.len() {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+
+    chunks
+}
+
+/// Like [`chunks`], but chunks are made of whole sentences with the overlap made of whole
+/// sentences too. Sentences too long for a chunk of their own are chunked like [`chunks`] does.
+pub fn sentence_chunks(text: &str, size: usize, overlap: usize) -> Vec<&str> {
+    let sentences = sentences(text);
+    let length = |from: usize, to: usize| text[from..to].chars().count();
+    let mut chunks = Vec::new();
+    let mut first = 0;
+
+    while first < sentences.len() {
+        let (start, end) = sentences[first];
+        if length(start, end) > size {
+            chunks.extend(self::chunks(&text[start..end], size, overlap));
+            first += 1;
+            continue;
+        }
+
+        let mut last = first;
+        while last + 1 < sentences.len() && length(start, sentences[last + 1].1) <= size {
+            last += 1;
+        }
+        let end = sentences[last].1;
+        let chunk = text[start..end].trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+
+        if last + 1 == sentences.len() {
+            break;
+        }
+        // the next chunk starts with as many of this one's sentences as fit in the overlap, as
+        // long as there's still room for a new one after them
+        let mut next = last + 1;
+        while next - 1 > first
+            && length(sentences[next - 1].0, end) <= overlap
+            && length(sentences[next - 1].0, sentences[last + 1].1) <= size
+        {
+            next -= 1;
+        }
+        first = next;
+    }
+
+    chunks
+}
+
+/// Byte ranges of the text's sentences, which end at a `.`, `!` or `?` followed by whitespace,
+/// or at a line break.
+fn sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, char)) = chars.next() {
+        let before_space = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        if char == '\n' || (matches!(char, '.' | '!' | '?') && before_space) {
+            let end = index + char.len_utf8();
+            if !text[start..end].trim().is_empty() {
+                sentences.push((start, end));
+            }
+            start = end;
+        }
+    }
+    if !text[start..].trim().is_empty() {
+        sentences.push((start, text.len()));
+    }
+
+    sentences
+}
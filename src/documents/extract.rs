@@ -0,0 +1,351 @@
+//! Getting the text out of uploaded files, split into the pages or sections it came from so
+//! each chunk can say where in the document it is.
+
+use std::io::{Cursor, Read};
+
+use quick_xml::{events::Event, Reader};
+use scraper::{ElementRef, Html, Selector};
+
+/// Part of a document, which is chunked on its own so no chunk spans two of them.
+pub struct Section {
+    /// The heading the text is under, for HTML and DOCX.
+    pub heading: Option<String>,
+    /// Counting from 1, for PDFs.
+    pub page: Option<usize>,
+    pub text: String,
+}
+
+impl Section {
+    pub fn new(text: String) -> Self {
+        Self {
+            heading: None,
+            page: None,
+            text,
+        }
+    }
+
+    /// A section that starts with its heading, so the heading is searched too.
+    fn under(heading: &str) -> Self {
+        Self {
+            heading: Some(heading.to_string()),
+            page: None,
+            text: format!("{heading}\n\n"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Html,
+    Pdf,
+    Docx,
+}
+
+impl Format {
+    /// From the file's content type, or its extension if the client didn't send a useful one.
+    pub fn detect(content_type: Option<&str>, file_name: Option<&str>) -> Option<Self> {
+        let content_type = content_type.map(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+        match content_type.as_deref() {
+            Some("application/pdf") => return Some(Self::Pdf),
+            Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document") => {
+                return Some(Self::Docx)
+            }
+            Some("text/html" | "application/xhtml+xml") => return Some(Self::Html),
+            Some("text/plain" | "text/markdown") => return Some(Self::Text),
+            _ => {}
+        }
+
+        let extension = file_name?.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "pdf" => Some(Self::Pdf),
+            "docx" => Some(Self::Docx),
+            "html" | "htm" => Some(Self::Html),
+            "txt" | "md" | "markdown" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Html => "HTML",
+            Self::Pdf => "PDF",
+            Self::Docx => "DOCX",
+        }
+    }
+}
+
+/// This can take a while for a big PDF, so it's best run on a blocking thread.
+pub fn sections(format: Format, bytes: &[u8]) -> Result<Vec<Section>, String> {
+    let sections = match format {
+        Format::Text => {
+            let text = std::str::from_utf8(bytes).map_err(|err| err.to_string())?;
+
+            vec![Section::new(text.to_string())]
+        }
+        Format::Html => {
+            let html = std::str::from_utf8(bytes).map_err(|err| err.to_string())?;
+
+            html_sections(html)
+        }
+        Format::Pdf => pdf_extract::extract_text_from_mem_by_pages(bytes)
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| Section {
+                heading: None,
+                page: Some(index + 1),
+                text,
+            })
+            .collect(),
+        Format::Docx => docx_sections(bytes)?,
+    };
+
+    Ok(sections
+        .into_iter()
+        .map(|section| Section {
+            text: tidy(&section.text),
+            ..section
+        })
+        .filter(|section| !section.text.is_empty())
+        .collect())
+}
+
+/// Splits markdown at its headings, leaving alone anything in a code block that looks like one.
+pub fn markdown_sections(text: &str, page: Option<usize>) -> Vec<Section> {
+    let mut sections = vec![Section::new(String::new())];
+    let mut in_code = false;
+
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        match markdown_heading(line).filter(|_| !in_code) {
+            Some(heading) => sections.push(Section::under(heading)),
+            None => {
+                let section = sections.last_mut().expect("there's always a section");
+                section.text.push_str(line);
+                section.text.push('\n');
+            }
+        }
+    }
+
+    sections
+        .into_iter()
+        .map(|section| Section {
+            page,
+            text: tidy(&section.text),
+            ..section
+        })
+        .filter(|section| !section.text.is_empty())
+        .collect()
+}
+
+/// eg. `Setup` for `## Setup`.
+fn markdown_heading(line: &str) -> Option<&str> {
+    let level = line.len() - line.trim_start_matches('#').len();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let heading = line[level..]
+        .strip_prefix(' ')?
+        .trim()
+        .trim_end_matches('#')
+        .trim();
+
+    (!heading.is_empty()).then_some(heading)
+}
+
+/// Elements whose text is its own paragraph, rather than running on from what's around it.
+const HTML_BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "li",
+    "ul",
+    "ol",
+    "pre",
+    "blockquote",
+    "table",
+    "tr",
+    "td",
+    "th",
+    "dt",
+    "dd",
+    "section",
+    "article",
+    "br",
+];
+/// Elements with nothing in them worth searching.
+const HTML_SKIPPED: &[&str] = &[
+    "head", "script", "style", "noscript", "template", "svg", "nav", "aside", "form", "button",
+    "iframe",
+];
+/// Skipped too when there's no `main` or `article` to say where the content is, as they're
+/// usually the site's rather than the page's.
+const HTML_PAGE_SKIPPED: &[&str] = &["header", "footer"];
+/// Landmarks that are the site's rather than the page's, however they're marked up.
+const HTML_SKIPPED_ROLES: &[&str] = &["navigation", "banner", "contentinfo", "complementary"];
+
+/// Only the page's content where it says what that is, rather than its navigation, headers and
+/// footers.
+fn html_sections(html: &str) -> Vec<Section> {
+    let document = Html::parse_document(html);
+    let content = Selector::parse("main, article, [role=main]").expect("the selector is valid");
+    let mut sections = vec![Section::new(String::new())];
+    match document.select(&content).next() {
+        Some(content) => html_walk(content, &mut sections, HTML_SKIPPED),
+        None => html_walk(
+            document.root_element(),
+            &mut sections,
+            &[HTML_SKIPPED, HTML_PAGE_SKIPPED].concat(),
+        ),
+    }
+
+    sections
+}
+
+/// The page's `<title>`, for a document that wasn't given one.
+pub fn html_title(html: &str) -> Option<String> {
+    let title = Selector::parse("title").expect("the selector is valid");
+    let title = Html::parse_document(html)
+        .select(&title)
+        .next()?
+        .text()
+        .collect::<String>();
+    let title = collapse_whitespace(&title).trim().to_string();
+
+    (!title.is_empty()).then_some(title)
+}
+
+fn html_walk(element: ElementRef, sections: &mut Vec<Section>, skipped: &[&str]) {
+    for child in element.children() {
+        if let Some(text) = child.value().as_text() {
+            let current = sections.last_mut().expect("there's always a section");
+            current.text.push_str(&collapse_whitespace(text));
+            continue;
+        }
+        let Some(child) = ElementRef::wrap(child) else {
+            continue;
+        };
+
+        let name = child.value().name();
+        let role = child.value().attr("role").unwrap_or_default();
+        if skipped.contains(&name)
+            || HTML_SKIPPED_ROLES.contains(&role)
+            || child.value().attr("aria-hidden") == Some("true")
+        {
+            continue;
+        }
+        if matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6") {
+            let heading = collapse_whitespace(&child.text().collect::<String>());
+            sections.push(Section::under(heading.trim()));
+            continue;
+        }
+
+        let block = HTML_BLOCKS.contains(&name);
+        if block {
+            paragraph_break(sections);
+        }
+        html_walk(child, sections, skipped);
+        if block {
+            paragraph_break(sections);
+        }
+    }
+}
+
+fn paragraph_break(sections: &mut [Section]) {
+    if let Some(current) = sections.last_mut() {
+        current.text.push_str("\n\n");
+    }
+}
+
+/// The text of a `.docx` is in `word/document.xml`, one `w:p` element per paragraph. Headings
+/// are paragraphs with a heading or title style.
+fn docx_sections(bytes: &[u8]) -> Result<Vec<Section>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|err| err.to_string())?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")
+        .map_err(|err| err.to_string())?
+        .read_to_string(&mut xml)
+        .map_err(|err| err.to_string())?;
+
+    let mut reader = Reader::from_str(&xml);
+    let mut sections = vec![Section::new(String::new())];
+    let mut paragraph = String::new();
+    let mut is_heading = false;
+    let mut in_text = false;
+    loop {
+        match reader.read_event().map_err(|err| err.to_string())? {
+            Event::Start(element) if element.name().as_ref() == b"w:t" => in_text = true,
+            Event::End(element) if element.name().as_ref() == b"w:t" => in_text = false,
+            Event::Text(text) if in_text => {
+                paragraph.push_str(&text.unescape().map_err(|err| err.to_string())?);
+            }
+            Event::Empty(element) => match element.name().as_ref() {
+                b"w:tab" => paragraph.push('\t'),
+                b"w:br" => paragraph.push('\n'),
+                b"w:pStyle" => {
+                    let style = element
+                        .try_get_attribute("w:val")
+                        .map_err(|err| err.to_string())?
+                        .map(|style| style.value.to_ascii_lowercase())
+                        .unwrap_or_default();
+                    is_heading = style.starts_with(b"heading") || style == b"title";
+                }
+                _ => {}
+            },
+            Event::End(element) if element.name().as_ref() == b"w:p" => {
+                let text = std::mem::take(&mut paragraph);
+                if std::mem::take(&mut is_heading) && !text.trim().is_empty() {
+                    sections.push(Section::under(text.trim()));
+                } else {
+                    let current = sections.last_mut().expect("there's always a section");
+                    current.text.push_str(&text);
+                    current.text.push_str("\n\n");
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(sections)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut last_was_space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !last_was_space {
+                collapsed.push(' ');
+            }
+            last_was_space = true;
+        } else {
+            collapsed.push(c);
+            last_was_space = false;
+        }
+    }
+
+    collapsed
+}
+
+/// Trims each paragraph and leaves exactly one blank line between them.
+fn tidy(text: &str) -> String {
+    let paragraphs: Vec<&str> = text
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect();
+
+    paragraphs.join("\n\n")
+}
@@ -0,0 +1,673 @@
+//! Documents ingested for retrieval. Each one is split into chunks, and each chunk's embedding
+//! goes in the vector store along with its text and the document's metadata.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, Multipart, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{types::Json as JsonColumn, FromRow};
+use uuid::Uuid;
+
+use crate::{
+    conversations::{self, default_page_size, page_violations},
+    embeddings::{self, EmbeddingOptions, EmbeddingType, Vector},
+    error::{ApiError, Violation},
+    models,
+    vectors::{self, Condition, Point, TENANT_FIELD},
+    AppState,
+};
+
+use chunking::Strategy;
+
+pub mod chunking;
+pub mod extract;
+pub mod sync;
+pub mod web;
+
+/// Titan's v2 embedding model takes up to 8k tokens, which is at least this many characters.
+const MAX_CHUNK_SIZE: usize = 8000;
+/// The most chunks one document can be split into, so one request can't embed a whole library.
+const MAX_CHUNKS: usize = 2000;
+
+#[derive(Deserialize)]
+pub struct NewDocument {
+    #[serde(default)]
+    pub text: String,
+    /// Only from a multipart form, and used instead of `text`.
+    #[serde(skip)]
+    pub file: Option<File>,
+    pub title: Option<String>,
+    /// Copied onto every chunk, so searches can be filtered on it.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Falls back to the deployment's, as do the size and overlap.
+    pub chunking: Option<Strategy>,
+    pub chunk_size: Option<usize>,
+    pub chunk_overlap: Option<usize>,
+    /// The embedding model, falls back to the deployment's default one.
+    pub model: Option<String>,
+    /// Where the document's from, eg. its URL. Ingesting the same source again replaces the
+    /// document, or leaves it be if nothing about it has changed.
+    pub source: Option<String>,
+    /// The S3 ETag for documents from a [`sync`].
+    #[serde(skip)]
+    pub source_etag: Option<String>,
+    /// Whose document it is, see [`vectors::tenant`].
+    #[serde(skip)]
+    pub tenant: String,
+}
+
+/// An uploaded file, which can be text, HTML, PDF or DOCX.
+pub struct File {
+    pub content_type: Option<String>,
+    pub name: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct Ingested {
+    pub id: Uuid,
+    pub title: Option<String>,
+    pub chunks: usize,
+    /// The rest of the chunks had the same text as ones that were already embedded.
+    pub embedded: usize,
+    /// Whether its source was already ingested just the same, so nothing was done.
+    pub unchanged: bool,
+    pub model: String,
+    pub usage: Usage,
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub input_tokens: i32,
+    /// Whether any of the counts are our estimate, for models that don't report them.
+    pub estimated: bool,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Document {
+    id: Uuid,
+    title: Option<String>,
+    metadata: JsonColumn<HashMap<String, String>>,
+    chunks: i32,
+    embedding_model: String,
+    source: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+/// A document along with its chunks, as they were indexed.
+#[derive(Serialize)]
+pub struct DocumentChunks {
+    document: Document,
+    chunks: Vec<StoredChunk>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct StoredChunk {
+    id: Uuid,
+    index: i32,
+    text: String,
+    page: Option<i32>,
+    section: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Only documents whose source starts with this, eg. an S3 prefix or a site.
+    source: Option<String>,
+    #[serde(default = "default_page_size")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+const DOCUMENT_COLUMNS: &str = "id, title, metadata, chunks, embedding_model, source, created_at";
+
+/// A [`NewDocument`] from either a JSON body or a multipart form with the same fields as the
+/// JSON, where the text can be a `file` instead (with its name as the default title).
+/// `metadata` is JSON in a form.
+pub struct DocumentBody(pub NewDocument);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for DocumentBody {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_multipart = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+
+        if !is_multipart {
+            let Ok(Json(document)) = Json::<NewDocument>::from_request(req, state).await else {
+                return Err(StatusCode::BAD_REQUEST);
+            };
+
+            return Ok(Self(document));
+        }
+
+        let Ok(multipart) = Multipart::from_request(req, state).await else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        from_multipart(multipart)
+            .await
+            .map(Self)
+            .ok_or(StatusCode::BAD_REQUEST)
+    }
+}
+
+async fn from_multipart(mut multipart: Multipart) -> Option<NewDocument> {
+    let mut text = String::new();
+    let mut file = None;
+    let mut title = None;
+    let mut metadata = HashMap::new();
+    let mut chunking = None;
+    let mut chunk_size = None;
+    let mut chunk_overlap = None;
+    let mut model = None;
+    let mut source = None;
+
+    while let Some(field) = multipart.next_field().await.ok()? {
+        match field.name() {
+            Some("text") => text = field.text().await.ok()?,
+            Some("file") => {
+                let content_type = field.content_type().map(str::to_string);
+                let name = field.file_name().map(str::to_string);
+                let bytes = field.bytes().await.ok()?;
+
+                file = Some(File {
+                    content_type,
+                    name,
+                    bytes: bytes.to_vec(),
+                });
+            }
+            Some("title") => title = Some(field.text().await.ok()?),
+            Some("metadata") => metadata = serde_json::from_str(&field.text().await.ok()?).ok()?,
+            Some("chunking") => chunking = Some(Strategy::parse(&field.text().await.ok()?)?),
+            Some("chunk_size") => chunk_size = Some(field.text().await.ok()?.parse().ok()?),
+            Some("chunk_overlap") => chunk_overlap = Some(field.text().await.ok()?.parse().ok()?),
+            Some("model") => model = Some(field.text().await.ok()?),
+            Some("source") => source = Some(field.text().await.ok()?),
+            _ => {}
+        }
+    }
+
+    let file_name = file.as_ref().and_then(|file: &File| file.name.clone());
+
+    Some(NewDocument {
+        text,
+        file,
+        title: title.or(file_name),
+        metadata,
+        chunking,
+        chunk_size,
+        chunk_overlap,
+        model,
+        source,
+        source_etag: None,
+        tenant: String::new(),
+    })
+}
+
+/// Chunks, embeds and stores a document, returning its id. Chunks from PDFs say which page
+/// they're from, and chunks from HTML and DOCX say which heading they're under. A chunk with
+/// the same text as one of the caller's that's already stored isn't embedded again.
+pub async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    DocumentBody(mut document): DocumentBody,
+) -> Result<impl IntoResponse, ApiError> {
+    document.tenant = vectors::tenant(&headers);
+    let ingested = ingest(&state, document).await?;
+    let status = if ingested.unchanged {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+
+    Ok((status, Json(ingested)))
+}
+
+/// The most recently ingested documents first, with how many chunks each was split into.
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(ListQuery {
+        source,
+        limit,
+        offset,
+    }): Query<ListQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let violations = page_violations(limit, offset);
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    // one more than asked for, to find out if there's another page
+    let mut documents: Vec<Document> = sqlx::query_as(&format!(
+        "SELECT {DOCUMENT_COLUMNS} FROM documents \
+         WHERE tenant = $1 AND ($2::text IS NULL OR starts_with(source, $2)) \
+         ORDER BY created_at DESC, id LIMIT $3 OFFSET $4"
+    ))
+    .bind(vectors::tenant(&headers))
+    .bind(source)
+    .bind(limit + 1)
+    .bind(offset)
+    .fetch_all(&state.db)
+    .await?;
+    let has_more = documents.len() as i64 > limit;
+    documents.truncate(limit as usize);
+
+    Ok(Json(json!({
+        "documents": documents,
+        "has_more": has_more,
+    })))
+}
+
+/// A document and every one of its chunks in order, to see how it was split up.
+pub async fn get(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Result<Json<DocumentChunks>, ApiError> {
+    let document: Option<Document> = sqlx::query_as(&format!(
+        "SELECT {DOCUMENT_COLUMNS} FROM documents WHERE id = $1 AND tenant = $2"
+    ))
+    .bind(id)
+    .bind(vectors::tenant(&headers))
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(document) = document else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let chunks: Vec<StoredChunk> = sqlx::query_as(
+        "SELECT id, (payload ->> 'chunk')::int AS index, payload ->> 'text' AS text, \
+         (payload ->> 'page')::int AS page, payload ->> 'section' AS section \
+         FROM chunks WHERE document_id = $1 ORDER BY index",
+    )
+    .bind(id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(DocumentChunks { document, chunks }))
+}
+
+/// Deletes the document, and its chunks from the vector store, so it stops turning up in
+/// searches.
+pub async fn delete(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM documents WHERE id = $1 AND tenant = $2)")
+            .bind(id)
+            .bind(vectors::tenant(&headers))
+            .fetch_one(&state.db)
+            .await?;
+    if !exists || !remove(&state, id).await? {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Does the work of [`create`], for anything else that ingests documents.
+pub async fn ingest(
+    state: &AppState,
+    NewDocument {
+        text,
+        file,
+        title,
+        metadata,
+        chunking,
+        chunk_size,
+        chunk_overlap,
+        model,
+        source,
+        source_etag,
+        tenant,
+    }: NewDocument,
+) -> Result<Ingested, ApiError> {
+    let Some(model) = state.embedding_model(model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    let chunking = chunking.unwrap_or(state.documents.chunking);
+    let chunk_size = chunk_size.unwrap_or(state.documents.chunk_size);
+    let chunk_overlap = chunk_overlap.unwrap_or(state.documents.chunk_overlap);
+    let max_chunk_size = MAX_CHUNK_SIZE / chunking.unit();
+
+    let mut violations = conversations::label_violations(Some(&metadata), None);
+    let format = match &file {
+        Some(file) => {
+            let format =
+                extract::Format::detect(file.content_type.as_deref(), file.name.as_deref());
+            if format.is_none() {
+                violations.push(Violation::new("file", "must be text, HTML, PDF or DOCX"));
+            }
+            format
+        }
+        None => {
+            if text.trim().is_empty() {
+                violations.push(Violation::new("text", "must not be empty"));
+            }
+            None
+        }
+    };
+    if chunk_size == 0 || chunk_size > max_chunk_size {
+        violations.push(Violation::new(
+            "chunk_size",
+            format!("must be between 1 and {max_chunk_size}"),
+        ));
+    }
+    if chunk_overlap >= chunk_size {
+        violations.push(Violation::new(
+            "chunk_overlap",
+            "must be less than chunk_size",
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let sections = match (file, format) {
+        (Some(file), Some(format)) => {
+            let Ok(extracted) =
+                tokio::task::spawn_blocking(move || extract::sections(format, &file.bytes)).await
+            else {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+            };
+            match extracted {
+                Ok(sections) if !sections.is_empty() => sections,
+                Ok(_) => {
+                    return Err(ApiError::Invalid(vec![Violation::new(
+                        "file",
+                        format!("has no text in it, as a {}", format.name()),
+                    )]))
+                }
+                Err(err) => {
+                    return Err(ApiError::Invalid(vec![Violation::new(
+                        "file",
+                        format!("couldn't be read as a {}: {err}", format.name()),
+                    )]))
+                }
+            }
+        }
+        _ => vec![extract::Section::new(text)],
+    };
+    // sections from HTML and DOCX headings are already as small as markdown's would be
+    let sections = if chunking == Strategy::Markdown {
+        sections
+            .into_iter()
+            .flat_map(|section| match section.heading {
+                Some(_) => vec![section],
+                None => extract::markdown_sections(&section.text, section.page),
+            })
+            .collect()
+    } else {
+        sections
+    };
+    // every chunk is from one section, so it can say where it's from
+    let mut chunks: Vec<(&extract::Section, String)> = Vec::new();
+    for section in &sections {
+        chunks.extend(
+            chunking
+                .chunks(&section.text, chunk_size, chunk_overlap)
+                .into_iter()
+                .map(|chunk| (section, chunk.to_string())),
+        );
+    }
+    if chunks.len() > MAX_CHUNKS {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "text",
+            format!(
+                "makes {} chunks, which is more than {MAX_CHUNKS}",
+                chunks.len()
+            ),
+        )]));
+    }
+
+    // the same text embedded by the same model always makes the same vector
+    let hashes: Vec<String> = chunks
+        .iter()
+        .map(|(_, chunk)| content_hash(&[&model.id, chunk]))
+        .collect();
+    let document_hash = document_hash(&model.id, title.as_deref(), &metadata, &chunks, &hashes);
+
+    let existing: Option<(Uuid, Option<String>, i32)> =
+        match &source {
+            Some(source) => sqlx::query_as(
+                "SELECT id, content_hash, chunks FROM documents WHERE tenant = $1 AND source = $2",
+            )
+            .bind(&tenant)
+            .bind(source)
+            .fetch_optional(&state.db)
+            .await?,
+            None => None,
+        };
+    if let Some((id, Some(existing_hash), existing_chunks)) = &existing {
+        if *existing_hash == document_hash {
+            sqlx::query("UPDATE documents SET source_etag = $2 WHERE id = $1")
+                .bind(id)
+                .bind(&source_etag)
+                .execute(&state.db)
+                .await?;
+
+            return Ok(Ingested {
+                id: *id,
+                title,
+                chunks: *existing_chunks as usize,
+                embedded: 0,
+                unchanged: true,
+                model: model.id.clone(),
+                usage: Usage {
+                    input_tokens: 0,
+                    estimated: false,
+                },
+            });
+        }
+    }
+
+    // chunks with the same text, in this document or any other of the tenant's, already have
+    // their vectors
+    let stored: Vec<(Uuid, String)> = sqlx::query_as(
+        "SELECT DISTINCT ON (content_hash) id, content_hash FROM chunks \
+         WHERE content_hash = ANY($1) AND payload @> $2",
+    )
+    .bind(&hashes)
+    .bind(JsonColumn(json!({ TENANT_FIELD: tenant })))
+    .fetch_all(&state.db)
+    .await?;
+    let ids: Vec<Uuid> = stored.iter().map(|(id, _)| *id).collect();
+    let vectors = state.vectors.vectors(&ids).await?;
+    let reusable: HashMap<&str, &Vec<f32>> = stored
+        .iter()
+        .filter_map(|(id, hash)| Some((hash.as_str(), vectors.get(id)?)))
+        .collect();
+
+    let options = EmbeddingOptions {
+        input_type: Some("search_document".to_string()),
+        embedding_type: EmbeddingType::Float,
+    };
+    let texts: Vec<String> = chunks
+        .iter()
+        .zip(&hashes)
+        .filter(|(_, hash)| !reusable.contains_key(hash.as_str()))
+        .map(|((_, chunk), _)| chunk.clone())
+        .collect();
+    let embedded = texts.len();
+    let mut embeddings = embeddings::embed(state, model, &texts, &options)
+        .await?
+        .into_iter();
+
+    let id = Uuid::new_v4();
+    let mut estimated = false;
+    let mut input_tokens = 0;
+    let mut points = Vec::new();
+    for (index, ((section, chunk), hash)) in chunks.iter().zip(&hashes).enumerate() {
+        let vector = match reusable.get(hash.as_str()) {
+            Some(vector) => vector.to_vec(),
+            None => {
+                let Some(embedding) = embeddings.next() else {
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+                };
+                let Vector::Float(vector) = embedding.embedding else {
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+                };
+                input_tokens += embedding.input_tokens.unwrap_or_else(|| {
+                    estimated = true;
+                    models::estimate_tokens(chunk)
+                });
+                vector
+            }
+        };
+        // metadata first, so it can't overwrite the fields every chunk has
+        let mut payload: Map<String, Value> = metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), Value::from(value.as_str())))
+            .collect();
+        payload.insert(TENANT_FIELD.to_string(), tenant.as_str().into());
+        payload.insert("document_id".to_string(), id.to_string().into());
+        payload.insert("chunk".to_string(), index.into());
+        payload.insert("text".to_string(), chunk.as_str().into());
+        if let Some(title) = &title {
+            payload.insert("title".to_string(), title.as_str().into());
+        }
+        if let Some(page) = section.page {
+            payload.insert("page".to_string(), page.into());
+        }
+        if let Some(heading) = &section.heading {
+            payload.insert("section".to_string(), heading.as_str().into());
+        }
+
+        points.push(Point {
+            id: Uuid::new_v4(),
+            vector,
+            payload,
+        });
+    }
+    if let Some(dimensions) = state.vectors.dimensions() {
+        if points.iter().any(|point| point.vector.len() != dimensions) {
+            return Err(ApiError::Invalid(vec![Violation::new(
+                "model",
+                format!("must make vectors with {dimensions} dimensions for this vector store"),
+            )]));
+        }
+    }
+
+    // the document is only kept if its chunks were, and only replaces the old one if it's kept
+    let mut tx = state.db.begin().await?;
+    if let Some((existing_id, ..)) = &existing {
+        sqlx::query("DELETE FROM documents WHERE id = $1")
+            .bind(existing_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query(
+        "INSERT INTO documents \
+         (id, title, metadata, chunks, embedding_model, source, source_etag, content_hash, tenant) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(id)
+    .bind(&title)
+    .bind(JsonColumn(&metadata))
+    .bind(chunks.len() as i32)
+    .bind(&model.id)
+    .bind(&source)
+    .bind(&source_etag)
+    .bind(&document_hash)
+    .bind(&tenant)
+    .execute(&mut *tx)
+    .await?;
+    // and for keyword search
+    for (point, hash) in points.iter().zip(&hashes) {
+        sqlx::query(
+            "INSERT INTO chunks (id, document_id, payload, content_hash) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(point.id)
+        .bind(id)
+        .bind(JsonColumn(&point.payload))
+        .bind(hash)
+        .execute(&mut *tx)
+        .await?;
+    }
+    state.vectors.upsert(points).await?;
+    tx.commit().await?;
+    if let Some((existing_id, ..)) = existing {
+        let filter = [(
+            "document_id".to_string(),
+            Condition::Equals(existing_id.to_string()),
+        )]
+        .into();
+        state.vectors.delete(&filter).await?;
+    }
+
+    Ok(Ingested {
+        id,
+        title,
+        chunks: chunks.len(),
+        embedded,
+        unchanged: false,
+        model: model.id.clone(),
+        usage: Usage {
+            input_tokens,
+            estimated,
+        },
+    })
+}
+
+/// Hex SHA-256 of the parts, which are kept apart so `["ab", "c"]` and `["a", "bc"]` differ.
+fn content_hash(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Covers everything that ends up stored for the document, so a document with the same hash
+/// doesn't need ingesting again.
+fn document_hash(
+    model: &str,
+    title: Option<&str>,
+    metadata: &HashMap<String, String>,
+    chunks: &[(&extract::Section, String)],
+    hashes: &[String],
+) -> String {
+    let mut parts = vec![model.to_string(), title.unwrap_or_default().to_string()];
+    // a HashMap's order changes from run to run
+    for (key, value) in metadata.iter().collect::<BTreeMap<_, _>>() {
+        parts.push(format!("{key}={value}"));
+    }
+    for ((section, _), hash) in chunks.iter().zip(hashes) {
+        parts.push(format!("{hash} {:?} {:?}", section.page, section.heading));
+    }
+
+    content_hash(&parts.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+/// Deletes a document along with its chunks, returning whether there was one.
+pub async fn remove(state: &AppState, id: Uuid) -> Result<bool, ApiError> {
+    let deleted = sqlx::query("DELETE FROM documents WHERE id = $1")
+        .bind(id)
+        .execute(&state.db)
+        .await?;
+    let filter = [("document_id".to_string(), Condition::Equals(id.to_string()))].into();
+    state.vectors.delete(&filter).await?;
+
+    Ok(deleted.rows_affected() > 0)
+}
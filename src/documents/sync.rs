@@ -0,0 +1,219 @@
+//! Keeping the documents in step with an S3 prefix: new objects are ingested, changed ones are
+//! ingested again and deleted ones are removed, going by each object's ETag.
+
+use std::{collections::HashMap, time::Duration};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{extract::Format, File, NewDocument};
+use crate::{error::ApiError, AppState};
+
+pub struct S3Sync {
+    pub client: aws_sdk_s3::Client,
+    pub bucket: String,
+    /// Only objects under it are synced, everything when it's empty.
+    pub prefix: String,
+    /// Whose documents they are, see [`crate::vectors::tenant`].
+    pub tenant: String,
+    /// How often to sync on its own, never when there's none.
+    pub interval: Option<Duration>,
+    /// Held for as long as a sync runs, so two can't run at once.
+    pub running: Mutex<()>,
+}
+
+/// How many documents a sync changed. `failed` ones are logged, and tried again next time.
+#[derive(Serialize, Default)]
+pub struct SyncReport {
+    added: usize,
+    updated: usize,
+    deleted: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+/// Runs a sync now, answering once it's done. 409 if one is already running.
+pub async fn run(State(state): State<AppState>) -> Result<Json<SyncReport>, ApiError> {
+    let Some(s3_sync) = state.s3_sync.as_deref() else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let Ok(_running) = s3_sync.running.try_lock() else {
+        return Err(StatusCode::CONFLICT.into());
+    };
+
+    Ok(Json(sync(&state, s3_sync).await?))
+}
+
+/// Runs for as long as the service does, when there's a sync interval. A sync that's still
+/// running when the next one is due is left to finish.
+pub async fn schedule(state: AppState) {
+    let Some(s3_sync) = state.s3_sync.clone() else {
+        return;
+    };
+    let Some(period) = s3_sync.interval else {
+        return;
+    };
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        let Ok(_running) = s3_sync.running.try_lock() else {
+            continue;
+        };
+        match sync(&state, &s3_sync).await {
+            Ok(report) => println!(
+                "Synced s3://{}/{}: {} added, {} updated, {} deleted, {} failed",
+                s3_sync.bucket,
+                s3_sync.prefix,
+                report.added,
+                report.updated,
+                report.deleted,
+                report.failed
+            ),
+            Err(err) => println!(
+                "Couldn't sync s3://{}/{}: {err:?}",
+                s3_sync.bucket, s3_sync.prefix
+            ),
+        }
+    }
+}
+
+async fn sync(state: &AppState, s3_sync: &S3Sync) -> Result<SyncReport, ApiError> {
+    let root = format!("s3://{}/{}", s3_sync.bucket, s3_sync.prefix);
+    let synced: Vec<(Uuid, String, Option<String>)> = sqlx::query_as(
+        "SELECT id, source, source_etag FROM documents \
+         WHERE tenant = $1 AND starts_with(source, $2)",
+    )
+    .bind(&s3_sync.tenant)
+    .bind(&root)
+    .fetch_all(&state.db)
+    .await?;
+    let mut synced: HashMap<String, (Uuid, Option<String>)> = synced
+        .into_iter()
+        .map(|(id, source, etag)| (source, (id, etag)))
+        .collect();
+
+    let mut report = SyncReport::default();
+    let mut pages = s3_sync
+        .client
+        .list_objects_v2()
+        .bucket(&s3_sync.bucket)
+        .prefix(&s3_sync.prefix)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = match page {
+            Ok(page) => page,
+            Err(err) => {
+                // without the whole listing, it can't tell what's been deleted
+                println!(
+                    "Couldn't list s3://{}/{}: {err:?}",
+                    s3_sync.bucket, s3_sync.prefix
+                );
+                return Err(StatusCode::BAD_GATEWAY.into());
+            }
+        };
+
+        for object in page.contents() {
+            let Some(key) = object.key() else {
+                continue;
+            };
+            // folders, and files that couldn't be ingested anyway
+            if key.ends_with('/') || Format::detect(None, Some(key)).is_none() {
+                continue;
+            }
+            let uri = format!("s3://{}/{key}", s3_sync.bucket);
+            let etag = object.e_tag().map(str::to_string);
+
+            let existing = synced.remove(&uri);
+            if let Some((_, existing_etag)) = &existing {
+                if etag.is_some() && existing_etag == &etag {
+                    report.unchanged += 1;
+                    continue;
+                }
+            }
+
+            // a new ETag doesn't always mean new content, eg. when it's uploaded again
+            let ingested = ingest(state, s3_sync, key, uri.clone(), etag).await;
+            match (ingested, existing.is_some()) {
+                (Ok(true), _) => report.unchanged += 1,
+                (Ok(false), false) => report.added += 1,
+                (Ok(false), true) => report.updated += 1,
+                (Err(err), _) => {
+                    println!("Couldn't sync {uri}: {err:?}");
+                    report.failed += 1;
+                }
+            }
+        }
+    }
+
+    // whatever wasn't listed has been deleted from the bucket
+    for (uri, (id, _)) in synced {
+        match super::remove(state, id).await {
+            Ok(_) => report.deleted += 1,
+            Err(err) => {
+                println!("Couldn't remove {uri}: {err:?}");
+                report.failed += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Returns whether the object was unchanged after all.
+async fn ingest(
+    state: &AppState,
+    s3_sync: &S3Sync,
+    key: &str,
+    uri: String,
+    etag: Option<String>,
+) -> Result<bool, ApiError> {
+    let object = s3_sync
+        .client
+        .get_object()
+        .bucket(&s3_sync.bucket)
+        .key(key)
+        .send()
+        .await;
+    let object = match object {
+        Ok(object) => object,
+        Err(err) => {
+            println!("Couldn't get {uri}: {err:?}");
+            return Err(StatusCode::BAD_GATEWAY.into());
+        }
+    };
+    let content_type = object.content_type().map(str::to_string);
+    let Ok(bytes) = object.body.collect().await else {
+        return Err(StatusCode::BAD_GATEWAY.into());
+    };
+
+    // an object that's already been ingested is replaced, after the new version has been
+    let name = key.rsplit('/').next().unwrap_or(key).to_string();
+    let ingested = super::ingest(
+        state,
+        NewDocument {
+            text: String::new(),
+            file: Some(File {
+                content_type,
+                name: Some(name.clone()),
+                bytes: bytes.into_bytes().to_vec(),
+            }),
+            title: Some(name),
+            metadata: HashMap::new(),
+            chunking: None,
+            chunk_size: None,
+            chunk_overlap: None,
+            model: None,
+            source: Some(uri),
+            source_etag: etag,
+            tenant: s3_sync.tenant.clone(),
+        },
+    )
+    .await?;
+
+    Ok(ingested.unchanged)
+}
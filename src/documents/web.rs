@@ -0,0 +1,240 @@
+//! Ingesting web pages by their URL. HTML has its navigation, headers and footers left out, and
+//! anything else a URL can point to is ingested like an uploaded file of the same type.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use reqwest::{header, redirect, Url};
+use serde::Deserialize;
+
+use super::{chunking::Strategy, extract, File, NewDocument};
+use crate::{
+    error::{ApiError, Violation},
+    vectors, AppState,
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Same as an upload's limit, near enough.
+const MAX_PAGE_BYTES: usize = 10 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Deserialize)]
+pub struct UrlDocument {
+    url: String,
+    /// Defaults to the page's `<title>`.
+    title: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    chunking: Option<Strategy>,
+    chunk_size: Option<usize>,
+    chunk_overlap: Option<usize>,
+    model: Option<String>,
+}
+
+/// For [`AppState`]. Redirects are followed by hand, so each one can be checked.
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .user_agent(concat!("shuttle-bedrock/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("couldn't build the HTTP client")
+}
+
+/// Fetches the page and ingests it like `/documents` would, with the URL as its source so
+/// ingesting it again replaces it.
+pub async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(UrlDocument {
+        url,
+        title,
+        metadata,
+        chunking,
+        chunk_size,
+        chunk_overlap,
+        model,
+    }): Json<UrlDocument>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(url) = Url::parse(&url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+    else {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "url",
+            "must be an http or https URL",
+        )]));
+    };
+
+    let (url, content_type, bytes) = fetch(&state.http, url).await?;
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string);
+    let format = extract::Format::detect(content_type.as_deref(), name.as_deref());
+    if format.is_none() {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "url",
+            "must be a web page, or a text, PDF or DOCX file",
+        )]));
+    }
+    let title = title.or_else(|| match format {
+        Some(extract::Format::Html) => {
+            extract::html_title(&String::from_utf8_lossy(&bytes)).or_else(|| name.clone())
+        }
+        _ => name.clone(),
+    });
+
+    let document = NewDocument {
+        text: String::new(),
+        file: Some(File {
+            content_type,
+            name,
+            bytes,
+        }),
+        title,
+        metadata,
+        chunking,
+        chunk_size,
+        chunk_overlap,
+        model,
+        source: Some(url.to_string()),
+        source_etag: None,
+        tenant: vectors::tenant(&headers),
+    };
+    let ingested = super::ingest(&state, document).await?;
+    let status = if ingested.unchanged {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+
+    Ok((status, Json(ingested)))
+}
+
+/// Returns the URL it ended up at, along with the content type and body.
+async fn fetch(
+    client: &reqwest::Client,
+    mut url: Url,
+) -> Result<(Url, Option<String>, Vec<u8>), ApiError> {
+    for _ in 0..=MAX_REDIRECTS {
+        if !is_public(&url).await {
+            return Err(ApiError::Invalid(vec![Violation::new(
+                "url",
+                "must be somewhere on the public internet",
+            )]));
+        }
+
+        let mut response = match client.get(url.clone()).send().await {
+            Ok(response) => response,
+            Err(err) => {
+                println!("Couldn't fetch {url}: {err}");
+                return Err(StatusCode::BAD_GATEWAY.into());
+            }
+        };
+        if response.status().is_redirection() {
+            let Some(location) = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+            else {
+                return Err(StatusCode::BAD_GATEWAY.into());
+            };
+            url = location;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(ApiError::Invalid(vec![Violation::new(
+                "url",
+                format!("returned {}", response.status()),
+            )]));
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_string);
+        let mut bytes = Vec::new();
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => bytes.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(err) => {
+                    println!("Couldn't fetch {url}: {err}");
+                    return Err(StatusCode::BAD_GATEWAY.into());
+                }
+            }
+            if bytes.len() > MAX_PAGE_BYTES {
+                return Err(ApiError::Invalid(vec![Violation::new(
+                    "url",
+                    format!("must be at most {MAX_PAGE_BYTES} bytes"),
+                )]));
+            }
+        }
+
+        return Ok((url, content_type, bytes));
+    }
+
+    Err(ApiError::Invalid(vec![Violation::new(
+        "url",
+        format!("redirected more than {MAX_REDIRECTS} times"),
+    )]))
+}
+
+/// Whether every address the URL's host has is a public one, so nobody can use this to reach
+/// something only the service can, eg. the instance metadata endpoint.
+async fn is_public(url: &Url) -> bool {
+    let addresses: Vec<IpAddr> = match url.host() {
+        Some(url::Host::Ipv4(ip)) => vec![ip.into()],
+        Some(url::Host::Ipv6(ip)) => vec![ip.into()],
+        Some(url::Host::Domain(domain)) => {
+            let port = url.port_or_known_default().unwrap_or(80);
+            match tokio::net::lookup_host((domain, port)).await {
+                Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+                Err(_) => Vec::new(),
+            }
+        }
+        None => Vec::new(),
+    };
+
+    !addresses.is_empty() && addresses.into_iter().all(is_public_ip)
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 100.64.0.0/10 is carrier-grade NAT
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(ip.into()),
+            None => !(ip.is_loopback() || ip.is_unspecified() || is_local_v6(ip)),
+        },
+    }
+}
+
+/// Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+fn is_local_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+}
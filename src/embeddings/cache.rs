@@ -0,0 +1,91 @@
+//! Embeddings that have already been made, so the same text embedded the same way isn't sent to
+//! the model again, eg. for a document that's ingested twice or a query that's searched often.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use sha2::{Digest, Sha256};
+
+use super::{Embedding, EmbeddingOptions, EmbeddingType};
+
+/// Kept in memory, so each instance of the service has its own. It holds at most `capacity`
+/// embeddings, and the oldest go first once it's full.
+pub struct EmbeddingCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    embeddings: HashMap<String, Embedding>,
+    /// Oldest first.
+    keys: VecDeque<String>,
+}
+
+impl EmbeddingCache {
+    /// A capacity of 0 turns the cache off.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// A cached embedding costs nothing, so it counts no input tokens.
+    pub fn get(&self, key: &str) -> Option<Embedding> {
+        let entries = self.entries.lock().expect("the cache lock was poisoned");
+        let embedding = entries.embeddings.get(key)?;
+
+        Some(Embedding {
+            embedding: embedding.embedding.clone(),
+            input_tokens: Some(0),
+        })
+    }
+
+    pub fn insert(&self, key: String, embedding: &Embedding) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().expect("the cache lock was poisoned");
+        if entries.embeddings.contains_key(&key) {
+            return;
+        }
+        while entries.keys.len() >= self.capacity {
+            let Some(oldest) = entries.keys.pop_front() else {
+                break;
+            };
+            entries.embeddings.remove(&oldest);
+        }
+        entries.keys.push_back(key.clone());
+        entries.embeddings.insert(
+            key,
+            Embedding {
+                embedding: embedding.embedding.clone(),
+                input_tokens: embedding.input_tokens,
+            },
+        );
+    }
+}
+
+/// A hash of everything that changes what the embedding of `text` comes out as.
+pub fn key(model_id: &str, options: &EmbeddingOptions, text: &str) -> String {
+    let embedding_type = match options.embedding_type {
+        EmbeddingType::Float => "float",
+        EmbeddingType::Int8 => "int8",
+    };
+    let mut hasher = Sha256::new();
+    for part in [
+        model_id,
+        options.input_type.as_deref().unwrap_or_default(),
+        embedding_type,
+        text,
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
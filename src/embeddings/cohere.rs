@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Embedding, EmbeddingOptions, EmbeddingProvider, EmbeddingType, Vector};
+
+pub struct CohereEmbed;
+
+impl EmbeddingProvider for CohereEmbed {
+    fn batch_size(&self) -> usize {
+        96
+    }
+
+    fn build_request_body(&self, texts: &[String], options: &EmbeddingOptions) -> Option<Vec<u8>> {
+        let embedding_type = match options.embedding_type {
+            EmbeddingType::Float => "float",
+            EmbeddingType::Int8 => "int8",
+        };
+
+        serde_json::to_vec(&CohereEmbedRequest {
+            texts,
+            input_type: options.input_type.as_deref().unwrap_or("search_document"),
+            embedding_types: [embedding_type],
+        })
+        .ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<Embedding>> {
+        let response_body = serde_json::from_slice::<CohereEmbedResponse>(body).ok()?;
+        let EmbeddingsByType { float, int8 } = response_body.embeddings;
+
+        // only the type we asked for comes back. Cohere doesn't report token counts in the body
+        let vectors: Vec<Vector> = match (float, int8) {
+            (Some(float), _) => float.into_iter().map(Vector::Float).collect(),
+            (_, Some(int8)) => int8.into_iter().map(Vector::Int8).collect(),
+            _ => return None,
+        };
+
+        Some(
+            vectors
+                .into_iter()
+                .map(|embedding| Embedding {
+                    embedding,
+                    input_tokens: None,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct CohereEmbedRequest<'a> {
+    texts: &'a [String],
+    /// Required by v3 models, documents and queries are embedded differently.
+    input_type: &'a str,
+    embedding_types: [&'static str; 1],
+}
+
+#[derive(Deserialize, Debug)]
+struct CohereEmbedResponse {
+    embeddings: EmbeddingsByType,
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingsByType {
+    float: Option<Vec<Vec<f32>>>,
+    int8: Option<Vec<Vec<i8>>>,
+}
@@ -0,0 +1,313 @@
+//! Embedding more texts than one `/embeddings` request can take. A job is submitted and embedded
+//! in the background, a batch at a time, and the client polls it until it's done, then pages
+//! through the embeddings.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{types::Json as JsonColumn, FromRow, PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use super::{EmbeddingData, EmbeddingModel, EmbeddingOptions, EmbeddingType};
+use crate::{
+    conversations,
+    error::{ApiError, Violation},
+    models, AppState,
+};
+
+const MAX_JOB_INPUTS: usize = 100_000;
+const DEFAULT_RESULTS_PAGE: i64 = 100;
+const MAX_RESULTS_PAGE: i64 = 1000;
+/// How many jobs are embedded at once, the rest are queued until one finishes.
+pub const MAX_RUNNING_JOBS: usize = 2;
+
+#[derive(Deserialize)]
+pub struct NewJob {
+    input: Vec<String>,
+    /// Like for `/embeddings`.
+    model: Option<String>,
+    input_type: Option<String>,
+    #[serde(default)]
+    embedding_type: EmbeddingType,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Job {
+    id: Uuid,
+    model: String,
+    /// `queued`, `running`, `completed` or `failed`.
+    status: String,
+    total: i32,
+    /// How many of the texts have been embedded so far.
+    completed: i32,
+    input_tokens: i32,
+    /// Whether any of the counts are our estimate, for models that don't report them.
+    estimated: bool,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    /// A page of the embeddings, like `/embeddings` returns them, once the job has completed.
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Vec<Value>>,
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    has_more: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ResultsQuery {
+    #[serde(default = "default_results_page")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_results_page() -> i64 {
+    DEFAULT_RESULTS_PAGE
+}
+
+/// Queues the texts to be embedded, returning the job's id to poll. Jobs belong to the API key
+/// that submitted them.
+pub async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(NewJob {
+        input,
+        model,
+        input_type,
+        embedding_type,
+    }): Json<NewJob>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(model) = state.embedding_model(model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    if input.is_empty() {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "input",
+            "must not be empty",
+        )]));
+    }
+    if input.len() > MAX_JOB_INPUTS {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "input",
+            format!("can have at most {MAX_JOB_INPUTS} texts"),
+        )]));
+    }
+
+    let id: Uuid = sqlx::query_scalar(
+        "INSERT INTO embedding_jobs (owner, model, total) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(conversations::owner(&headers))
+    .bind(&model.id)
+    .bind(input.len() as i32)
+    .fetch_one(&state.db)
+    .await?;
+
+    let total = input.len();
+    let options = EmbeddingOptions {
+        input_type,
+        embedding_type,
+    };
+    tokio::spawn(run(state.clone(), id, model.clone(), input, options));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "id": id, "status": "queued", "total": total })),
+    ))
+}
+
+/// The job's progress, and once it has completed, `?limit=` of its embeddings from `?offset=`.
+pub async fn get(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Query(ResultsQuery { limit, offset }): Query<ResultsQuery>,
+) -> Result<Json<Job>, ApiError> {
+    let mut violations = Vec::new();
+    if !(1..=MAX_RESULTS_PAGE).contains(&limit) {
+        violations.push(Violation::new(
+            "limit",
+            format!("must be between 1 and {MAX_RESULTS_PAGE}"),
+        ));
+    }
+    if offset < 0 {
+        violations.push(Violation::new("offset", "must not be negative"));
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let job: Option<Job> = sqlx::query_as(
+        "SELECT id, model, status, total, completed, input_tokens, estimated, error, \
+         created_at, finished_at FROM embedding_jobs \
+         WHERE id = $1 AND owner IS NOT DISTINCT FROM $2",
+    )
+    .bind(id)
+    .bind(conversations::owner(&headers))
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(mut job) = job else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    if job.status != "completed" {
+        return Ok(Json(job));
+    }
+
+    let mut data: Vec<Value> = sqlx::query_scalar(
+        "SELECT data FROM embedding_job_results WHERE job_id = $1 \
+         ORDER BY index LIMIT $2 OFFSET $3",
+    )
+    .bind(id)
+    .bind(limit + 1)
+    .bind(offset)
+    .fetch_all(&state.db)
+    .await?;
+    job.has_more = Some(data.len() as i64 > limit);
+    data.truncate(limit as usize);
+    job.data = Some(data);
+
+    Ok(Json(job))
+}
+
+/// The texts are only kept in memory while they're embedded, so jobs that were still queued or
+/// running when the service last stopped can't carry on and have to be submitted again.
+pub async fn fail_interrupted(db: &PgPool) {
+    let failed = sqlx::query(
+        "UPDATE embedding_jobs SET status = 'failed', \
+         error = 'the service restarted before the job finished', finished_at = now() \
+         WHERE status IN ('queued', 'running')",
+    )
+    .execute(db)
+    .await
+    .expect("couldn't fail the interrupted embedding jobs");
+    if failed.rows_affected() > 0 {
+        println!(
+            "Failed {} embedding jobs the last restart interrupted",
+            failed.rows_affected()
+        );
+    }
+}
+
+async fn run(
+    state: AppState,
+    id: Uuid,
+    model: EmbeddingModel,
+    texts: Vec<String>,
+    options: EmbeddingOptions,
+) {
+    let Ok(_running) = state.embedding_jobs.acquire().await else {
+        return;
+    };
+
+    if let Err(err) = embed_job(&state, id, &model, &texts, &options).await {
+        println!("Embedding job {id} failed: {err}");
+        let failed = sqlx::query(
+            "UPDATE embedding_jobs SET status = 'failed', error = $2, finished_at = now() \
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(err)
+        .execute(&state.db)
+        .await;
+        if let Err(err) = failed {
+            println!("Couldn't mark embedding job {id} as failed: {err}");
+        }
+    }
+}
+
+/// A batch the size of an `/embeddings` request at a time, saving each one's embeddings as it
+/// goes so the job's progress can be seen.
+async fn embed_job(
+    state: &AppState,
+    id: Uuid,
+    model: &EmbeddingModel,
+    texts: &[String],
+    options: &EmbeddingOptions,
+) -> Result<(), String> {
+    sqlx::query("UPDATE embedding_jobs SET status = 'running' WHERE id = $1")
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let batch_size = state.max_embedding_inputs.max(1);
+    for (batch, texts) in texts.chunks(batch_size).enumerate() {
+        let start = batch * batch_size;
+        let Ok(embeddings) = super::embed(state, model, texts, options).await else {
+            return Err(format!(
+                "couldn't embed texts {start} to {}",
+                start + texts.len() - 1
+            ));
+        };
+
+        let mut estimated = false;
+        let data: Vec<EmbeddingData> = embeddings
+            .into_iter()
+            .zip(texts)
+            .enumerate()
+            .map(|(index, (embedding, text))| EmbeddingData {
+                index: start + index,
+                embedding: embedding.embedding,
+                input_tokens: embedding.input_tokens.unwrap_or_else(|| {
+                    estimated = true;
+                    models::estimate_tokens(text)
+                }),
+            })
+            .collect();
+        let input_tokens: i32 = data.iter().map(|embedding| embedding.input_tokens).sum();
+
+        save_batch(&state.db, id, &data, input_tokens, estimated)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    sqlx::query(
+        "UPDATE embedding_jobs SET status = 'completed', finished_at = now() WHERE id = $1",
+    )
+    .bind(id)
+    .execute(&state.db)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+/// The batch's embeddings along with the job's progress, so the count always matches the rows.
+async fn save_batch(
+    db: &PgPool,
+    id: Uuid,
+    data: &[EmbeddingData],
+    input_tokens: i32,
+    estimated: bool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    QueryBuilder::<Postgres>::new("INSERT INTO embedding_job_results (job_id, index, data) ")
+        .push_values(data, |mut row, embedding| {
+            row.push_bind(id)
+                .push_bind(embedding.index as i32)
+                .push_bind(JsonColumn(embedding));
+        })
+        .build()
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "UPDATE embedding_jobs SET completed = completed + $2, input_tokens = input_tokens + $3, \
+         estimated = estimated OR $4 WHERE id = $1",
+    )
+    .bind(id)
+    .bind(data.len() as i32)
+    .bind(input_tokens)
+    .bind(estimated)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! Text embeddings, for anything that wants to search over text rather than generate it.
+
+use std::sync::Arc;
+
+use aws_sdk_bedrockruntime::primitives::Blob;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{ApiError, Violation},
+    models, AppState,
+};
+
+pub mod cache;
+pub mod cohere;
+pub mod jobs;
+pub mod titan;
+
+pub const DEFAULT_EMBEDDING_MODEL_ID: &str = "amazon.titan-embed-text-v2:0";
+
+/// How many requests to the embedding model one `/embeddings` request can have in flight, so a
+/// big batch for a model that only embeds one text at a time doesn't get throttled.
+const MAX_CONCURRENT_BATCHES: usize = 8;
+
+/// Like [`crate::models::ModelProvider`], for each embedding model's request and response schema.
+pub trait EmbeddingProvider: Send + Sync {
+    /// How many texts can go in a single request.
+    fn batch_size(&self) -> usize;
+
+    /// Returns `None` if the model can't produce what `options` asks for.
+    fn build_request_body(&self, texts: &[String], options: &EmbeddingOptions) -> Option<Vec<u8>>;
+
+    /// Returns one embedding per text, in the same order.
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<Embedding>>;
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingType {
+    #[default]
+    Float,
+    /// Smaller vectors at some cost to accuracy, only Cohere supports these.
+    Int8,
+}
+
+pub struct EmbeddingOptions {
+    /// What the texts will be used for, eg. `search_document` or `search_query`. Cohere embeds
+    /// documents and queries differently, other models ignore it.
+    pub input_type: Option<String>,
+    pub embedding_type: EmbeddingType,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+pub enum Vector {
+    Float(Vec<f32>),
+    Int8(Vec<i8>),
+}
+
+pub struct Embedding {
+    pub embedding: Vector,
+    /// `None` if the model only reports tokens for the whole request.
+    pub input_tokens: Option<i32>,
+}
+
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    pub id: String,
+    pub provider: Arc<dyn EmbeddingProvider>,
+}
+
+impl EmbeddingModel {
+    /// Returns `None` if the model isn't one we know how to talk to.
+    pub fn new(id: String) -> Option<Self> {
+        let provider: Arc<dyn EmbeddingProvider> = if id.starts_with("amazon.titan-embed-text") {
+            Arc::new(titan::TitanEmbed)
+        } else if id.starts_with("cohere.embed") {
+            Arc::new(cohere::CohereEmbed)
+        } else {
+            return None;
+        };
+
+        Some(Self { id, provider })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl Input {
+    fn into_texts(self) -> Vec<String> {
+        match self {
+            Input::Single(text) => vec![text],
+            Input::Batch(texts) => texts,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingsRequest {
+    input: Input,
+    /// Falls back to the deployment's default embedding model if not given.
+    model: Option<String>,
+    input_type: Option<String>,
+    #[serde(default)]
+    embedding_type: EmbeddingType,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsResponse {
+    model: String,
+    data: Vec<EmbeddingData>,
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vector,
+    input_tokens: i32,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    input_tokens: i32,
+    /// Whether any of the counts are our estimate, for models that don't report them.
+    estimated: bool,
+}
+
+pub async fn embeddings(
+    State(state): State<AppState>,
+    Json(EmbeddingsRequest {
+        input,
+        model,
+        input_type,
+        embedding_type,
+    }): Json<EmbeddingsRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(model) = state.embedding_model(model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let texts = input.into_texts();
+    if texts.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if texts.len() > state.max_embedding_inputs {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "input",
+            format!("can have at most {} texts", state.max_embedding_inputs),
+        )]));
+    }
+
+    let options = EmbeddingOptions {
+        input_type,
+        embedding_type,
+    };
+    let embeddings = embed(&state, model, &texts, &options).await?;
+
+    let mut estimated = false;
+    let data: Vec<EmbeddingData> = embeddings
+        .into_iter()
+        .zip(&texts)
+        .enumerate()
+        .map(|(index, (embedding, text))| EmbeddingData {
+            index,
+            embedding: embedding.embedding,
+            input_tokens: embedding.input_tokens.unwrap_or_else(|| {
+                estimated = true;
+                models::estimate_tokens(text)
+            }),
+        })
+        .collect();
+    let usage = Usage {
+        input_tokens: data.iter().map(|embedding| embedding.input_tokens).sum(),
+        estimated,
+    };
+
+    Ok(Json(EmbeddingsResponse {
+        model: model.id.clone(),
+        data,
+        usage,
+    }))
+}
+
+/// Embeds any number of texts, in as many requests to the model as it takes. Returns one
+/// embedding per text, in the same order. Texts that are in the cache aren't sent at all.
+pub async fn embed(
+    state: &AppState,
+    model: &EmbeddingModel,
+    texts: &[String],
+    options: &EmbeddingOptions,
+) -> Result<Vec<Embedding>, StatusCode> {
+    let keys: Vec<String> = texts
+        .iter()
+        .map(|text| cache::key(&model.id, options, text))
+        .collect();
+    let mut embeddings: Vec<Option<Embedding>> = keys
+        .iter()
+        .map(|key| state.embedding_cache.get(key))
+        .collect();
+    let uncached: Vec<String> = texts
+        .iter()
+        .zip(&embeddings)
+        .filter(|(_, embedding)| embedding.is_none())
+        .map(|(text, _)| text.clone())
+        .collect();
+
+    let mut embedded = embed_uncached(state, model, &uncached, options)
+        .await?
+        .into_iter();
+    for (embedding, key) in embeddings.iter_mut().zip(keys) {
+        if embedding.is_some() {
+            continue;
+        }
+        let Some(new) = embedded.next() else {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        state.embedding_cache.insert(key, &new);
+        *embedding = Some(new);
+    }
+
+    Ok(embeddings.into_iter().flatten().collect())
+}
+
+async fn embed_uncached(
+    state: &AppState,
+    model: &EmbeddingModel,
+    texts: &[String],
+    options: &EmbeddingOptions,
+) -> Result<Vec<Embedding>, StatusCode> {
+    // collected first, as the lifetimes are too much for the compiler otherwise
+    let requests: Vec<_> = texts
+        .chunks(model.provider.batch_size())
+        .map(|batch| embed_batch(state, model, batch, options))
+        .collect();
+    let batches: Vec<Vec<Embedding>> = stream::iter(requests)
+        .buffered(MAX_CONCURRENT_BATCHES)
+        .try_collect()
+        .await?;
+
+    Ok(batches.into_iter().flatten().collect())
+}
+
+async fn embed_batch(
+    state: &AppState,
+    model: &EmbeddingModel,
+    texts: &[String],
+    options: &EmbeddingOptions,
+) -> Result<Vec<Embedding>, StatusCode> {
+    let Some(body) = model.provider.build_request_body(texts, options) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    let blob = Blob::new(body);
+
+    let Ok(res) = state
+        .clients
+        .send_to(&model.id, |client, model_id| {
+            client
+                .invoke_model()
+                .body(blob.clone())
+                .model_id(model_id)
+                .send()
+        })
+        .await
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let res: &[u8] = &res.body.into_inner();
+    match model.provider.parse_response(res) {
+        Some(embeddings) if embeddings.len() == texts.len() => Ok(embeddings),
+        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
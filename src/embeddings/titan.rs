@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Embedding, EmbeddingOptions, EmbeddingProvider, EmbeddingType, Vector};
+
+pub struct TitanEmbed;
+
+impl EmbeddingProvider for TitanEmbed {
+    /// Titan only embeds one text per request.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    fn build_request_body(&self, texts: &[String], options: &EmbeddingOptions) -> Option<Vec<u8>> {
+        let [text] = texts else {
+            return None;
+        };
+        if options.embedding_type != EmbeddingType::Float {
+            return None;
+        }
+
+        serde_json::to_vec(&TitanEmbedRequest { input_text: text }).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<Embedding>> {
+        let response_body = serde_json::from_slice::<TitanEmbedResponse>(body).ok()?;
+
+        Some(vec![Embedding {
+            embedding: Vector::Float(response_body.embedding),
+            input_tokens: Some(response_body.input_text_token_count),
+        }])
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanEmbedRequest<'a> {
+    input_text: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TitanEmbedResponse {
+    embedding: Vec<f32>,
+    input_text_token_count: i32,
+}
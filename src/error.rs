@@ -0,0 +1,74 @@
+//! Errors handlers can return, so a client can find out everything wrong with a request at once
+//! rather than one bare status code at a time.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+/// One constraint a request broke, eg. `temperature` being above 1.
+#[derive(Serialize, Debug)]
+pub struct Violation {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    Status(StatusCode),
+    /// Returned as `422 Unprocessable Entity` with every violation in the body.
+    Invalid(Vec<Violation>),
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+/// Database errors are our problem rather than the client's, so they're only logged.
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        println!("Database error: {err}");
+
+        Self::Status(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// The same goes for the vector store.
+impl From<crate::vectors::StoreError> for ApiError {
+    fn from(err: crate::vectors::StoreError) -> Self {
+        println!("Vector store error: {err}");
+
+        Self::Status(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+#[derive(Serialize)]
+struct ValidationErrors {
+    errors: Vec<Violation>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Status(status) => status.into_response(),
+            Self::Invalid(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ValidationErrors { errors }),
+            )
+                .into_response(),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Scoring how well an answer sticks to the context it was given, by asking a judge model to
+//! check each of its claims against the context. Running the same questions before and after
+//! changing the documents or the prompts shows whether the answers got better or worse.
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    complete,
+    error::{ApiError, Violation},
+    AppState, PreparedPrompt,
+};
+
+const INSTRUCTIONS: &str = "You are grading an answer to a question, given the numbered context \
+the answer was written from. Split the answer into the separate claims it makes, and for each \
+one decide whether the context supports it. A claim is only supported if the context says it, \
+not if it's merely plausible. Then rate from 0 to 1 how well the answer addresses the question, \
+and how relevant the context is to the question.
+
+Reply with only this JSON, and nothing before or after it:
+{\"claims\": [{\"claim\": \"...\", \"supported\": true, \"sources\": [1]}], \
+\"answer_relevance\": 0.0, \"context_relevance\": 0.0}";
+const MAX_JUDGE_TOKENS: i32 = 2048;
+const MAX_CONTEXT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct EvalRequest {
+    question: String,
+    /// The chunks the answer was written from, in the order it was given them. Either their text
+    /// or the chunks `/ask` and `/search` return, as they are.
+    context: Vec<ContextItem>,
+    answer: String,
+    /// The judge, which falls back to the summary model and then the default one.
+    model: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ContextItem {
+    Text(String),
+    Chunk { text: String },
+}
+
+impl ContextItem {
+    fn text(&self) -> &str {
+        match self {
+            Self::Text(text) | Self::Chunk { text } => text,
+        }
+    }
+}
+
+/// What the judge is asked to reply with.
+#[derive(Deserialize)]
+struct Verdict {
+    #[serde(default)]
+    claims: Vec<Claim>,
+    answer_relevance: Option<f32>,
+    context_relevance: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Claim {
+    claim: String,
+    supported: bool,
+    /// Which parts of the context support it, numbered from 1.
+    #[serde(default)]
+    sources: Vec<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Scores {
+    /// The share of the answer's claims the context supports, from 0 to 1. An answer without any
+    /// claims, eg. "I don't know", counts as faithful.
+    faithfulness: f32,
+    answer_relevance: Option<f32>,
+    context_relevance: Option<f32>,
+    claims: Vec<Claim>,
+    model: String,
+    input_tokens: Option<i32>,
+    output_tokens: Option<i32>,
+}
+
+pub async fn evaluate(
+    State(state): State<AppState>,
+    Json(EvalRequest {
+        question,
+        context,
+        answer,
+        model,
+    }): Json<EvalRequest>,
+) -> Result<Json<Scores>, ApiError> {
+    let mut violations = Vec::new();
+    if question.trim().is_empty() {
+        violations.push(Violation::new("question", "must not be empty"));
+    }
+    if answer.trim().is_empty() {
+        violations.push(Violation::new("answer", "must not be empty"));
+    }
+    if context.len() > MAX_CONTEXT {
+        violations.push(Violation::new(
+            "context",
+            format!("can have at most {MAX_CONTEXT} parts"),
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+    let requested = model.as_deref().or(state.chat.summary_model_id.as_deref());
+    let Some(model) = state.model(requested) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let mut params = model.defaults.clone();
+    params.temperature = Some(0.0);
+    params.max_tokens = Some(MAX_JUDGE_TOKENS);
+    let prepared = PreparedPrompt {
+        model,
+        logprobs: false,
+        prompt: written_out(&question, &context, &answer),
+        system: Some(INSTRUCTIONS.to_string()),
+        images: Vec::new(),
+        params,
+        history: Vec::new(),
+    };
+    let completion = complete(&state, &prepared, &prepared.params).await?;
+    let Some(verdict) = parse_verdict(&completion.text) else {
+        println!("The judge's reply wasn't a verdict: {}", completion.text);
+        return Err(StatusCode::BAD_GATEWAY.into());
+    };
+
+    let supported = verdict
+        .claims
+        .iter()
+        .filter(|claim| claim.supported)
+        .count();
+    let faithfulness = if verdict.claims.is_empty() {
+        1.0
+    } else {
+        supported as f32 / verdict.claims.len() as f32
+    };
+
+    Ok(Json(Scores {
+        faithfulness,
+        answer_relevance: verdict.answer_relevance.map(|score| score.clamp(0.0, 1.0)),
+        context_relevance: verdict.context_relevance.map(|score| score.clamp(0.0, 1.0)),
+        claims: verdict.claims,
+        model: completion.model,
+        input_tokens: completion.input_tokens,
+        output_tokens: completion.output_tokens,
+    }))
+}
+
+/// The context numbered like `/ask` numbers it, so the judge's sources line up with citations.
+fn written_out(question: &str, context: &[ContextItem], answer: &str) -> String {
+    let context = if context.is_empty() {
+        "(no context)".to_string()
+    } else {
+        context
+            .iter()
+            .enumerate()
+            .map(|(index, item)| format!("[{}]\n{}", index + 1, item.text()))
+            .collect::<Vec<_>>()
+            .join("\n\n---\n\n")
+    };
+
+    format!("Question:\n{question}\n\nContext:\n\n{context}\n\nAnswer:\n{answer}")
+}
+
+/// Models like to wrap JSON in a code block or say something first, so this only looks at
+/// what's between the outermost braces.
+fn parse_verdict(reply: &str) -> Option<Verdict> {
+    let start = reply.find('{')?;
+    let end = reply.rfind('}')?;
+
+    serde_json::from_str(reply.get(start..=end)?).ok()
+}
@@ -0,0 +1,161 @@
+//! Thumbs up or down on replies, so the bad ones can be found later to improve the prompts or
+//! pick a better model.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    chat::Role,
+    conversations::{self, default_page_size, page_violations},
+    error::{ApiError, Violation},
+    AppState,
+};
+
+const MAX_COMMENT_LENGTH: usize = 4000;
+
+#[derive(Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+#[derive(Deserialize)]
+pub struct NewFeedback {
+    rating: Rating,
+    comment: Option<String>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Feedback {
+    message_id: i64,
+    rating: Rating,
+    comment: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// Feedback along with the reply it's about.
+#[derive(Serialize, FromRow)]
+pub struct RatedReply {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    feedback: Feedback,
+    conversation_id: Uuid,
+    content: String,
+    model: Option<String>,
+    params: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct FeedbackQuery {
+    rating: Option<Rating>,
+    #[serde(default = "default_page_size")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+/// Rates a reply, replacing any rating it already had.
+pub async fn rate(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(message_id): Path<i64>,
+    Json(NewFeedback { rating, comment }): Json<NewFeedback>,
+) -> Result<impl IntoResponse, ApiError> {
+    let comment = comment.filter(|comment| !comment.trim().is_empty());
+    if comment
+        .as_ref()
+        .is_some_and(|comment| comment.len() > MAX_COMMENT_LENGTH)
+    {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "comment",
+            format!("must be at most {MAX_COMMENT_LENGTH} bytes"),
+        )]));
+    }
+
+    // only replies in the caller's own conversations
+    let role: Option<Role> = sqlx::query_scalar(
+        "SELECT messages.role FROM messages \
+         JOIN conversations ON conversations.id = messages.conversation_id \
+         WHERE messages.id = $1 AND conversations.owner IS NOT DISTINCT FROM $2",
+    )
+    .bind(message_id)
+    .bind(conversations::owner(&headers))
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(role) = role else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    if role != Role::Assistant {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "message_id",
+            "must be a reply from the assistant",
+        )]));
+    }
+
+    let feedback: Feedback = sqlx::query_as(
+        "INSERT INTO feedback (message_id, rating, comment) VALUES ($1, $2, $3) \
+         ON CONFLICT (message_id) DO UPDATE \
+         SET rating = excluded.rating, comment = excluded.comment, updated_at = now() \
+         RETURNING message_id, rating, comment, created_at, updated_at",
+    )
+    .bind(message_id)
+    .bind(rating)
+    .bind(comment)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(feedback))
+}
+
+/// The most recently rated replies first, eg. `?rating=down` for the ones to look into.
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(FeedbackQuery {
+        rating,
+        limit,
+        offset,
+    }): Query<FeedbackQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let violations = page_violations(limit, offset);
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    // one more than asked for, to find out if there's another page
+    let mut feedback: Vec<RatedReply> = sqlx::query_as(
+        "SELECT feedback.message_id, feedback.rating, feedback.comment, feedback.created_at, \
+         feedback.updated_at, messages.conversation_id, messages.content, messages.model, \
+         messages.params \
+         FROM feedback JOIN messages ON messages.id = feedback.message_id \
+         JOIN conversations ON conversations.id = messages.conversation_id \
+         WHERE ($1::text IS NULL OR feedback.rating = $1) \
+         AND conversations.owner IS NOT DISTINCT FROM $4 \
+         ORDER BY feedback.updated_at DESC, feedback.message_id LIMIT $2 OFFSET $3",
+    )
+    .bind(rating)
+    .bind(limit + 1)
+    .bind(offset)
+    .bind(conversations::owner(&headers))
+    .fetch_all(&state.db)
+    .await?;
+    let has_more = feedback.len() as i64 > limit;
+    feedback.truncate(limit as usize);
+
+    Ok(Json(json!({
+        "feedback": feedback,
+        "has_more": has_more,
+    })))
+}
@@ -0,0 +1,161 @@
+//! Image generation, returned either as base64 JSON or as the image itself.
+
+use std::sync::Arc;
+
+use aws_sdk_bedrockruntime::primitives::Blob;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+pub mod stability;
+pub mod titan;
+
+pub const DEFAULT_IMAGE_MODEL_ID: &str = "amazon.titan-image-generator-v1";
+
+/// Like [`crate::models::ModelProvider`], for each image model's request and response schema.
+pub trait ImageProvider: Send + Sync {
+    fn build_request_body(&self, request: &ImageRequest) -> Option<Vec<u8>>;
+
+    /// Returns the base64 encoded images.
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<String>>;
+
+    fn content_type(&self) -> &'static str {
+        "image/png"
+    }
+}
+
+#[derive(Clone)]
+pub struct ImageModel {
+    pub id: String,
+    pub provider: Arc<dyn ImageProvider>,
+}
+
+impl ImageModel {
+    /// Returns `None` if the model isn't one we know how to talk to.
+    pub fn new(id: String) -> Option<Self> {
+        let provider: Arc<dyn ImageProvider> = if id.starts_with("amazon.titan-image-generator") {
+            Arc::new(titan::TitanImage)
+        } else if id.starts_with("stability.stable-diffusion-xl") {
+            Arc::new(stability::StableDiffusionXl)
+        } else if id.starts_with("stability.") {
+            // SD3 and the Stable Image models share a simpler schema
+            Arc::new(stability::StableImage)
+        } else {
+            return None;
+        };
+
+        Some(Self { id, provider })
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ResponseFormat {
+    /// The images as base64 strings in a JSON body.
+    #[default]
+    Base64,
+    /// The image itself, only if a single image was asked for.
+    Binary,
+}
+
+#[derive(Deserialize)]
+pub struct ImageRequest {
+    /// Falls back to the deployment's default image model if not given.
+    model: Option<String>,
+    pub prompt: String,
+    /// What the image shouldn't contain.
+    pub negative_prompt: Option<String>,
+    #[serde(default = "default_number_of_images")]
+    pub number_of_images: i32,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// How closely the image should follow the prompt.
+    pub cfg_scale: Option<f32>,
+    pub seed: Option<i64>,
+    /// Extra prompts with their own weight, negative weights steer away from the prompt. SDXL only.
+    #[serde(default)]
+    pub weighted_prompts: Vec<WeightedPrompt>,
+    /// eg. `photographic` or `anime`. SDXL only.
+    pub style_preset: Option<String>,
+    #[serde(default)]
+    response_format: ResponseFormat,
+}
+
+#[derive(Deserialize)]
+pub struct WeightedPrompt {
+    pub text: String,
+    pub weight: f32,
+}
+
+fn default_number_of_images() -> i32 {
+    1
+}
+
+#[derive(Serialize)]
+struct ImagesResponse {
+    model: String,
+    images: Vec<String>,
+}
+
+pub async fn generate(
+    State(state): State<AppState>,
+    Json(request): Json<ImageRequest>,
+) -> Result<Response, StatusCode> {
+    let binary = request.response_format == ResponseFormat::Binary;
+    if binary && request.number_of_images != 1 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let Some(model) = state.image_model(request.model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let Some(body) = model.provider.build_request_body(&request) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    let blob = Blob::new(body);
+
+    let Ok(res) = state
+        .clients
+        .send_to(&model.id, |client, model_id| {
+            client
+                .invoke_model()
+                .body(blob.clone())
+                .model_id(model_id)
+                .send()
+        })
+        .await
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let res: &[u8] = &res.body.into_inner();
+    let Some(images) = model.provider.parse_response(res) else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    if !binary {
+        return Ok(Json(ImagesResponse {
+            model: model.id.clone(),
+            images,
+        })
+        .into_response());
+    }
+
+    let Some(Ok(image)) = images.first().map(|image| BASE64_STANDARD.decode(image)) else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    Ok((
+        [(header::CONTENT_TYPE, model.provider.content_type())],
+        image,
+    )
+        .into_response())
+}
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+use super::{ImageProvider, ImageRequest, WeightedPrompt};
+
+pub struct StableDiffusionXl;
+
+impl ImageProvider for StableDiffusionXl {
+    fn build_request_body(&self, request: &ImageRequest) -> Option<Vec<u8>> {
+        serde_json::to_vec(&SdxlRequest::new(request)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<String>> {
+        let response_body = serde_json::from_slice::<SdxlResponse>(body).ok()?;
+        if response_body.result != "success" {
+            return None;
+        }
+
+        Some(
+            response_body
+                .artifacts
+                .into_iter()
+                .map(|artifact| artifact.base64)
+                .collect(),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct SdxlRequest<'a> {
+    text_prompts: Vec<TextPrompt<'a>>,
+    cfg_scale: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    width: i32,
+    height: i32,
+    samples: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    style_preset: Option<&'a str>,
+}
+
+impl<'a> SdxlRequest<'a> {
+    fn new(request: &'a ImageRequest) -> Self {
+        let negative_prompt = request
+            .negative_prompt
+            .as_deref()
+            .map(|text| TextPrompt { text, weight: -1.0 });
+        let text_prompts = [TextPrompt {
+            text: &request.prompt,
+            weight: 1.0,
+        }]
+        .into_iter()
+        .chain(negative_prompt)
+        .chain(request.weighted_prompts.iter().map(TextPrompt::from))
+        .collect();
+
+        Self {
+            text_prompts,
+            cfg_scale: request.cfg_scale.unwrap_or(7.0),
+            seed: request.seed,
+            width: request.width.unwrap_or(1024),
+            height: request.height.unwrap_or(1024),
+            samples: request.number_of_images,
+            style_preset: request.style_preset.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TextPrompt<'a> {
+    text: &'a str,
+    weight: f32,
+}
+
+impl<'a> From<&'a WeightedPrompt> for TextPrompt<'a> {
+    fn from(prompt: &'a WeightedPrompt) -> Self {
+        Self {
+            text: &prompt.text,
+            weight: prompt.weight,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SdxlResponse {
+    result: String,
+    artifacts: Vec<SdxlArtifact>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SdxlArtifact {
+    base64: String,
+}
+
+/// SD3 and Stable Image Core/Ultra, which make one image at a time and size it by aspect ratio
+/// rather than width and height.
+pub struct StableImage;
+
+impl ImageProvider for StableImage {
+    fn build_request_body(&self, request: &ImageRequest) -> Option<Vec<u8>> {
+        if request.number_of_images != 1
+            || request.width.is_some()
+            || request.height.is_some()
+            || !request.weighted_prompts.is_empty()
+            || request.style_preset.is_some()
+        {
+            return None;
+        }
+
+        serde_json::to_vec(&StableImageRequest {
+            prompt: &request.prompt,
+            negative_prompt: request.negative_prompt.as_deref(),
+            seed: request.seed,
+            output_format: "png",
+        })
+        .ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<String>> {
+        let response_body = serde_json::from_slice::<StableImageResponse>(body).ok()?;
+
+        Some(response_body.images)
+    }
+}
+
+#[derive(Serialize)]
+struct StableImageRequest<'a> {
+    prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative_prompt: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    output_format: &'static str,
+}
+
+#[derive(Deserialize, Debug)]
+struct StableImageResponse {
+    images: Vec<String>,
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+use super::{ImageProvider, ImageRequest};
+
+pub struct TitanImage;
+
+impl ImageProvider for TitanImage {
+    fn build_request_body(&self, request: &ImageRequest) -> Option<Vec<u8>> {
+        if !request.weighted_prompts.is_empty() || request.style_preset.is_some() {
+            return None;
+        }
+
+        serde_json::to_vec(&TitanImageRequest::new(request)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<Vec<String>> {
+        let response_body = serde_json::from_slice::<TitanImageResponse>(body).ok()?;
+        if response_body.error.is_some() {
+            return None;
+        }
+
+        Some(response_body.images)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanImageRequest<'a> {
+    task_type: &'static str,
+    text_to_image_params: TextToImageParams<'a>,
+    image_generation_config: ImageGenerationConfig,
+}
+
+impl<'a> TitanImageRequest<'a> {
+    fn new(request: &'a ImageRequest) -> Self {
+        Self {
+            task_type: "TEXT_IMAGE",
+            text_to_image_params: TextToImageParams {
+                text: &request.prompt,
+                negative_text: request.negative_prompt.as_deref(),
+            },
+            image_generation_config: ImageGenerationConfig {
+                number_of_images: request.number_of_images,
+                width: request.width.unwrap_or(1024),
+                height: request.height.unwrap_or(1024),
+                cfg_scale: request.cfg_scale.unwrap_or(8.0),
+                seed: request.seed,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TextToImageParams<'a> {
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative_text: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageGenerationConfig {
+    number_of_images: i32,
+    width: i32,
+    height: i32,
+    cfg_scale: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TitanImageResponse {
+    images: Vec<String>,
+    error: Option<String>,
+}
@@ -0,0 +1,399 @@
+//! Importing conversations exported from ChatGPT (`conversations.json`), or in OpenAI's chat
+//! format, so switching to this service doesn't mean losing them.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    chat::{self, ChatMessage, Role},
+    conversations,
+    error::{ApiError, Violation},
+    AppState,
+};
+
+/// Either everything ChatGPT exported, or a single conversation.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Export {
+    Many(Vec<ExportedConversation>),
+    One(Box<ExportedConversation>),
+}
+
+#[derive(Deserialize)]
+pub struct ExportedConversation {
+    title: Option<String>,
+    /// Seconds since the epoch, like the rest of ChatGPT's times.
+    create_time: Option<f64>,
+    update_time: Option<f64>,
+    /// ChatGPT keeps every branch of the conversation, as a tree of messages. Only the branch
+    /// ending at `current_node` is imported, which is the one that was last looked at.
+    #[serde(default)]
+    mapping: HashMap<String, Node>,
+    current_node: Option<String>,
+    /// For conversations in the chat completions format instead.
+    #[serde(default)]
+    messages: Vec<ChatCompletionMessage>,
+}
+
+#[derive(Deserialize)]
+pub struct Node {
+    message: Option<NodeMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NodeMessage {
+    author: Author,
+    content: Content,
+    create_time: Option<f64>,
+    #[serde(default)]
+    metadata: MessageMetadata,
+}
+
+#[derive(Deserialize)]
+pub struct Author {
+    role: String,
+}
+
+#[derive(Deserialize)]
+pub struct Content {
+    /// Text, or objects for things like images, which aren't imported.
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct MessageMetadata {
+    model_slug: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    /// Who the imported conversations are with, for their memories.
+    user_id: Option<String>,
+}
+
+struct Imported {
+    role: Role,
+    content: String,
+    model: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// Imports every conversation that has any text in it, all or nothing. Tool calls, images and
+/// anything else that isn't text from the user, the assistant or the system are left out. Big
+/// exports have to be split up to fit in the request body limit.
+pub async fn import(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(ImportQuery { user_id }): Query<ImportQuery>,
+    Json(export): Json<Export>,
+) -> Result<impl IntoResponse, ApiError> {
+    let exported = match export {
+        Export::Many(exported) => exported,
+        Export::One(exported) => vec![*exported],
+    };
+    let owner = conversations::owner(&headers);
+
+    let mut tx = state.db.begin().await?;
+    let mut imported = Vec::new();
+    let mut skipped = 0;
+    for (index, conversation) in exported.into_iter().enumerate() {
+        let messages = alternating(messages(&conversation));
+        if messages.is_empty() {
+            skipped += 1;
+            continue;
+        }
+        // anything /chat would turn down would leave the conversation stuck
+        let turns: Vec<ChatMessage> = messages
+            .iter()
+            .map(|message| ChatMessage {
+                role: message.role,
+                content: message.content.clone(),
+            })
+            .collect();
+        let violations: Vec<Violation> = chat::violations(&turns)
+            .into_iter()
+            .map(|violation| {
+                Violation::new(
+                    "conversations",
+                    format!("conversation {index} {}", violation.message),
+                )
+            })
+            .collect();
+        if !violations.is_empty() {
+            return Err(ApiError::Invalid(violations));
+        }
+
+        let id: Uuid = sqlx::query_scalar(
+            "INSERT INTO conversations (title, user_id, owner, created_at, updated_at) \
+             VALUES (NULLIF(trim($1), ''), $2, $3, COALESCE($4, now()), COALESCE($5, now())) \
+             RETURNING id",
+        )
+        .bind(&conversation.title)
+        .bind(&user_id)
+        .bind(&owner)
+        .bind(conversation.create_time.and_then(timestamp))
+        .bind(conversation.update_time.and_then(timestamp))
+        .fetch_one(&mut *tx)
+        .await?;
+        for message in &messages {
+            sqlx::query(
+                "INSERT INTO messages (conversation_id, role, content, model, created_at) \
+                 VALUES ($1, $2, $3, $4, COALESCE($5, now()))",
+            )
+            .bind(id)
+            .bind(message.role)
+            .bind(&message.content)
+            .bind(&message.model)
+            .bind(message.created_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        imported.push(json!({
+            "id": id,
+            "title": conversation.title,
+            "messages": messages.len(),
+        }));
+    }
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "imported": imported, "skipped": skipped })),
+    ))
+}
+
+/// The conversation's messages oldest first, whichever format it's in.
+fn messages(conversation: &ExportedConversation) -> Vec<Imported> {
+    if !conversation.messages.is_empty() {
+        return conversation
+            .messages
+            .iter()
+            .filter_map(|message| {
+                Some(Imported {
+                    role: role(&message.role)?,
+                    content: message.content.clone()?,
+                    model: None,
+                    created_at: None,
+                })
+            })
+            .collect();
+    }
+
+    // walk from the last message back up to the root, so only the current branch is imported
+    let mut messages = Vec::new();
+    let mut next = conversation.current_node.as_deref();
+    // a malformed export could loop forever otherwise
+    let mut steps = 0;
+    while let Some(node) = next.and_then(|id| conversation.mapping.get(id)) {
+        steps += 1;
+        if steps > conversation.mapping.len() {
+            break;
+        }
+
+        if let Some(message) = &node.message {
+            let text: Vec<&str> = message
+                .content
+                .parts
+                .iter()
+                .filter_map(|part| part.as_str())
+                .collect();
+            if let Some(role) = role(&message.author.role) {
+                messages.push(Imported {
+                    role,
+                    content: text.join("\n\n"),
+                    model: message.metadata.model_slug.clone(),
+                    created_at: message.create_time.and_then(timestamp),
+                });
+            }
+        }
+        next = node.parent.as_deref();
+    }
+    messages.reverse();
+
+    messages
+}
+
+fn role(role: &str) -> Option<Role> {
+    match role {
+        "system" => Some(Role::System),
+        "user" => Some(Role::User),
+        "assistant" => Some(Role::Assistant),
+        _ => None,
+    }
+}
+
+/// `/chat` needs turns to alternate, starting with the user and ending with a reply, so the
+/// messages are made to fit: empty ones are dropped, ones in a row from the same role are joined
+/// together (system messages in between don't count, as they aren't turns), and any from the
+/// assistant before the user's first or from the user after the last reply are dropped.
+fn alternating(messages: Vec<Imported>) -> Vec<Imported> {
+    let mut alternating: Vec<Imported> = Vec::new();
+    for message in messages {
+        if message.content.trim().is_empty() {
+            continue;
+        }
+        if message.role == Role::Assistant
+            && !alternating.iter().any(|message| message.role == Role::User)
+        {
+            continue;
+        }
+
+        let last_turn = alternating
+            .iter_mut()
+            .rfind(|message| message.role != Role::System);
+        match last_turn {
+            Some(last) if last.role == message.role && message.role != Role::System => {
+                last.content = format!("{}\n\n{}", last.content, message.content);
+            }
+            _ => alternating.push(message),
+        }
+    }
+
+    while let Some(last) = alternating
+        .iter()
+        .rposition(|message| message.role != Role::System)
+    {
+        if alternating[last].role == Role::Assistant {
+            break;
+        }
+        alternating.remove(last);
+    }
+    // only system messages left
+    if !alternating
+        .iter()
+        .any(|message| message.role == Role::Assistant)
+    {
+        alternating.clear();
+    }
+
+    alternating
+}
+
+fn timestamp(seconds: f64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_micros((seconds * 1_000_000.0) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Imported {
+        Imported {
+            role,
+            content: content.to_string(),
+            model: None,
+            created_at: None,
+        }
+    }
+
+    fn turns(messages: &[Imported]) -> Vec<(Role, &str)> {
+        messages
+            .iter()
+            .map(|message| (message.role, message.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn messages_in_a_row_are_joined() {
+        let messages = alternating(vec![
+            message(Role::User, "hi"),
+            message(Role::User, "are you there?"),
+            message(Role::Assistant, "yes"),
+        ]);
+
+        assert_eq!(
+            turns(&messages),
+            [
+                (Role::User, "hi\n\nare you there?"),
+                (Role::Assistant, "yes")
+            ]
+        );
+    }
+
+    #[test]
+    fn system_messages_in_between_dont_split_turns() {
+        let messages = alternating(vec![
+            message(Role::User, "hi"),
+            message(Role::System, "be brief"),
+            message(Role::User, "are you there?"),
+            message(Role::Assistant, "yes"),
+        ]);
+
+        assert_eq!(
+            turns(&messages),
+            [
+                (Role::User, "hi\n\nare you there?"),
+                (Role::System, "be brief"),
+                (Role::Assistant, "yes")
+            ]
+        );
+        let turns: Vec<ChatMessage> = messages
+            .iter()
+            .chain([&message(Role::User, "thanks")])
+            .map(|message| ChatMessage {
+                role: message.role,
+                content: message.content.clone(),
+            })
+            .collect();
+        assert!(chat::violations(&turns).is_empty());
+    }
+
+    #[test]
+    fn replies_before_the_user_and_questions_after_the_last_reply_are_dropped() {
+        let messages = alternating(vec![
+            message(Role::Assistant, "how can I help?"),
+            message(Role::User, "hi"),
+            message(Role::Assistant, "hello"),
+            message(Role::User, "never answered"),
+        ]);
+
+        assert_eq!(
+            turns(&messages),
+            [(Role::User, "hi"), (Role::Assistant, "hello")]
+        );
+    }
+
+    #[test]
+    fn empty_messages_are_dropped() {
+        let messages = alternating(vec![
+            message(Role::User, "hi"),
+            message(Role::Assistant, " "),
+            message(Role::Assistant, "hello"),
+        ]);
+
+        assert_eq!(
+            turns(&messages),
+            [(Role::User, "hi"), (Role::Assistant, "hello")]
+        );
+    }
+
+    #[test]
+    fn conversations_without_a_reply_are_empty() {
+        let messages = alternating(vec![
+            message(Role::System, "be brief"),
+            message(Role::User, "hi"),
+        ]);
+
+        assert!(messages.is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+//! Answering questions from a Bedrock Knowledge Base with the RetrieveAndGenerate API, for
+//! teams who keep their documents in AWS rather than ingesting them here.
+
+use aws_sdk_bedrockagentruntime::types::{
+    KnowledgeBaseRetrieveAndGenerateConfiguration, RetrieveAndGenerateConfiguration,
+    RetrieveAndGenerateInput, RetrieveAndGenerateType, RetrievedReference,
+};
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    converse,
+    error::{ApiError, Violation},
+    models, AppState,
+};
+
+pub struct KnowledgeBase {
+    pub client: aws_sdk_bedrockagentruntime::Client,
+    pub id: String,
+    /// For the model's ARN, it's the region the client is for.
+    pub region: String,
+}
+
+#[derive(Deserialize)]
+pub struct KnowledgeBaseQuestion {
+    question: String,
+    /// Falls back to the deployment's default model, like a prompt does.
+    model: Option<String>,
+    /// From an earlier answer, so follow-up questions have its context.
+    session_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct KnowledgeBaseAnswer {
+    text: String,
+    model: String,
+    session_id: String,
+    citations: Vec<Citation>,
+}
+
+/// A part of the answer, and the passages from the knowledge base it came from.
+#[derive(Serialize)]
+struct Citation {
+    text: Option<String>,
+    references: Vec<Reference>,
+}
+
+#[derive(Serialize)]
+struct Reference {
+    text: Option<String>,
+    /// Where the passage is, eg. an `s3://` URI.
+    location: Option<String>,
+    metadata: Map<String, Value>,
+}
+
+/// Only there when the deployment has a `BEDROCK_KNOWLEDGE_BASE_ID`.
+pub async fn ask(
+    State(state): State<AppState>,
+    Json(KnowledgeBaseQuestion {
+        question,
+        model,
+        session_id,
+    }): Json<KnowledgeBaseQuestion>,
+) -> Result<Json<KnowledgeBaseAnswer>, ApiError> {
+    let Some(knowledge_base) = state.knowledge_base.as_deref() else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let Some(model) = state.model(model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    if question.trim().is_empty() {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "question",
+            "must not be empty",
+        )]));
+    }
+
+    // provisioned models already have an ARN, on-demand ones are addressed by their base id
+    let model_arn = model.provisioned_arn.clone().unwrap_or_else(|| {
+        format!(
+            "arn:aws:bedrock:{}::foundation-model/{}",
+            knowledge_base.region,
+            models::base_model_id(&model.id)
+        )
+    });
+    let Ok(configuration) = KnowledgeBaseRetrieveAndGenerateConfiguration::builder()
+        .knowledge_base_id(&knowledge_base.id)
+        .model_arn(model_arn)
+        .build()
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    };
+    let Ok(configuration) = RetrieveAndGenerateConfiguration::builder()
+        .r#type(RetrieveAndGenerateType::KnowledgeBase)
+        .knowledge_base_configuration(configuration)
+        .build()
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    };
+    let Ok(input) = RetrieveAndGenerateInput::builder().text(question).build() else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    };
+
+    let res = knowledge_base
+        .client
+        .retrieve_and_generate()
+        .input(input)
+        .retrieve_and_generate_configuration(configuration)
+        .set_session_id(session_id)
+        .send()
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        // eg. a session that has expired
+        Err(err)
+            if err
+                .as_service_error()
+                .is_some_and(|err| err.is_validation_exception()) =>
+        {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        Err(err) => {
+            println!("Knowledge base error: {err:?}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+
+    let citations = res
+        .citations()
+        .iter()
+        .map(|citation| Citation {
+            text: citation
+                .generated_response_part()
+                .and_then(|part| part.text_response_part())
+                .and_then(|part| part.text())
+                .map(str::to_string),
+            references: citation
+                .retrieved_references()
+                .iter()
+                .map(reference)
+                .collect(),
+        })
+        .collect();
+
+    Ok(Json(KnowledgeBaseAnswer {
+        text: res
+            .output()
+            .map(|output| output.text().to_string())
+            .unwrap_or_default(),
+        model: model.id.clone(),
+        session_id: res.session_id().to_string(),
+        citations,
+    }))
+}
+
+fn reference(reference: &RetrievedReference) -> Reference {
+    let location = reference.location().and_then(|location| {
+        location
+            .s3_location()
+            .and_then(|s3| s3.uri())
+            .or_else(|| location.web_location().and_then(|web| web.url()))
+            .map(str::to_string)
+    });
+
+    Reference {
+        text: reference
+            .content()
+            .map(|content| content.text().to_string()),
+        location,
+        metadata: reference
+            .metadata()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), converse::json(value)))
+            .collect(),
+    }
+}
@@ -0,0 +1,61 @@
+//! Per-caller limits, so one API key can't run up the bill for everyone else.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::AppState;
+
+/// Where callers can put their API key, instead of `Authorization: Bearer <key>`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Callers without a key, or with one that isn't given a tier, get this tier's limits.
+pub const DEFAULT_TIER: &str = "default";
+
+/// The most tokens a caller's requests can ask a model for, if it is limited at all.
+#[derive(Clone, Copy)]
+pub struct MaxTokensLimit(pub Option<i32>);
+
+impl MaxTokensLimit {
+    /// `requested` brought down to the limit.
+    pub fn clamp(self, requested: i32) -> i32 {
+        self.0.map_or(requested, |limit| requested.min(limit))
+    }
+}
+
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    bearer.or_else(|| headers.get(API_KEY_HEADER)?.to_str().ok())
+}
+
+/// Works out the caller's tier from their API key and hands its limit to the handlers, which
+/// clamp `max_tokens` to it once they know the model and its defaults.
+pub async fn enforce(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let tier = api_key(req.headers())
+        .and_then(|key| {
+            state
+                .limits
+                .key_tiers
+                .iter()
+                .find(|(tier_key, _)| tier_key == key)
+        })
+        .map_or(DEFAULT_TIER, |(_, tier)| tier.as_str());
+    let limit = state
+        .limits
+        .tier_max_tokens
+        .iter()
+        .find(|(name, _)| name == tier)
+        .map(|(_, max_tokens)| *max_tokens);
+
+    req.extensions_mut().insert(MaxTokensLimit(limit));
+
+    next.run(req).await
+}
@@ -1,197 +1,1150 @@
-use aws_config::Region;
+use aws_config::{Region, SdkConfig};
 use aws_credential_types::Credentials;
-use aws_sdk_bedrockruntime::{primitives::Blob, types::ResponseStream, Client};
+use aws_sdk_bedrock::types::{
+    AuthorizationStatus, EntitlementAvailability, ModelModality, RegionAvailability,
+};
+use aws_sdk_bedrockruntime::{primitives::Blob, Client};
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    extract::{MatchedPath, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post, put},
+    Extension, Json, Router,
 };
-use axum_streams::StreamBodyAs;
-use futures::stream;
+use futures::future::try_join_all;
 use serde::{Deserialize, Serialize};
 use shuttle_runtime::SecretStore;
+use sqlx::PgPool;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+mod admin;
+mod ask;
+mod attachments;
+mod auth;
+mod chat;
+mod config;
+mod conversations;
+mod converse;
+mod documents;
+mod embeddings;
+mod error;
+mod eval;
+mod feedback;
+mod images;
+mod import;
+mod knowledge_base;
+mod limits;
+mod memories;
+mod models;
+mod policy;
+mod pool;
+mod retention;
+mod search;
+mod sessions;
+mod streaming;
+mod summaries;
+mod titles;
+mod vectors;
+
+use attachments::{Image, PromptBody};
+use chat::ChatMessage;
+use config::{
+    ChatConfig, DocumentsConfig, LimitsConfig, ModelConfig, PromptWrapper, StreamingConfig,
+};
+use documents::sync::S3Sync;
+use embeddings::{cache::EmbeddingCache, EmbeddingModel};
+use error::{ApiError, Violation};
+use images::ImageModel;
+use knowledge_base::KnowledgeBase;
+use limits::MaxTokensLimit;
+use models::{params::GenerationParams, registry::ModelInfo, Model, TokenLogprob};
+use pool::{ClientPool, RegionalClient};
+use vectors::{pgvector::PgVectorStore, qdrant::QdrantStore, VectorStore};
+
+/// The most completions a single prompt can ask for, as each one is its own invocation.
+const MAX_COMPLETIONS: u32 = 8;
+
+/// Used when `AWS_REGIONS` isn't set.
+const DEFAULT_REGION: &str = "eu-west-1";
 
 async fn hello_world() -> &'static str {
     "Hello, world!"
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 struct Prompt {
     prompt: String,
+    /// Falls back to the deployment's default model if not given.
+    model: Option<String>,
+    /// eg. `creative`, for anything not set in `params`.
+    preset: Option<String>,
+    /// Falls back to the deployment's system prompt, if it has one.
+    system: Option<String>,
+    /// Only for models that support images, eg. Claude 3.
+    #[serde(default, skip_serializing)]
+    images: Vec<Image>,
+    #[serde(flatten)]
+    params: GenerationParams,
+    /// Takes priority over the `Accept` header when given.
+    #[serde(default, skip_serializing)]
+    response_format: Option<ResponseFormat>,
+    /// How many completions to generate, they're returned as an array when given.
+    n: Option<u32>,
+    /// Include each token's log probability in the JSON response, for models that return them.
+    #[serde(default)]
+    logprobs: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ResponseFormat {
+    /// Just the generated text.
+    #[default]
+    Text,
+    /// A [`Completion`], with usage for client-side accounting.
+    Json,
+}
+
+impl ResponseFormat {
+    /// Whichever of `text/plain` or `application/json` comes first in the `Accept` header, so
+    /// plain clients (eg. curl) get text and JSON clients get the whole [`Completion`].
+    fn from_accept(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .find_map(|media_type| match media_type {
+                "application/json" => Some(Self::Json),
+                "text/plain" => Some(Self::Text),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Serialize)]
+struct Completion {
+    text: String,
+    model: String,
+    /// Only known for models that go through the Converse API.
+    input_tokens: Option<i32>,
+    output_tokens: Option<i32>,
+    /// Why the model stopped, eg. `end_turn` or `max_tokens`.
+    completion_reason: Option<String>,
+    seed: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<TokenLogprob>>,
+    /// Including any retries in other regions.
+    latency_ms: u128,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct TitanResponse {
-    input_text_token_count: i32,
-    results: Vec<TitanTextResult>,
+impl Completion {
+    fn into_response(self, format: ResponseFormat) -> Response {
+        let headers = seed_header(self.seed);
+
+        match format {
+            ResponseFormat::Text => (headers, self.text).into_response(),
+            ResponseFormat::Json => (headers, Json(self)).into_response(),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-struct TitanTextResult {
-    token_count: i32,
-    output_text: String,
-    completion_reason: String,
+/// A prompt checked against the model it's for, with its preset, the model's defaults and the
+/// deployment's system prompt applied.
+struct PreparedPrompt<'a> {
+    model: &'a Model,
+    logprobs: bool,
+    prompt: String,
+    system: Option<String>,
+    images: Vec<Image>,
+    params: GenerationParams,
+    /// Earlier turns of a chat, if there are any.
+    history: Vec<ChatMessage>,
+}
+
+fn prepare_prompt<'a>(
+    state: &'a AppState,
+    route: &MatchedPath,
+    max_tokens_limit: MaxTokensLimit,
+    Prompt {
+        prompt,
+        model,
+        preset,
+        system,
+        images,
+        params,
+        n,
+        logprobs,
+        response_format,
+    }: Prompt,
+) -> Result<PreparedPrompt<'a>, ApiError> {
+    let Some(model) = state.model(model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let mut violations = Vec::new();
+    if n.is_some_and(|n| !(1..=MAX_COMPLETIONS).contains(&n)) {
+        violations.push(Violation::new(
+            "n",
+            format!("must be between 1 and {MAX_COMPLETIONS}"),
+        ));
+    }
+    if prompt.trim().is_empty() {
+        violations.push(Violation::new("prompt", "must not be empty"));
+    }
+    let prompt = state.prompt_wrapper.wrap(route.as_str(), prompt);
+    if logprobs && !model.provider.supports_logprobs() {
+        violations.push(Violation::new(
+            "logprobs",
+            format!("{} doesn't return log probabilities", model.id),
+        ));
+    }
+    if logprobs && response_format != Some(ResponseFormat::Json) {
+        violations.push(Violation::new(
+            "logprobs",
+            "are only returned with response_format json",
+        ));
+    }
+    if !images.is_empty() && !model.provider.supports_images() {
+        violations.push(Violation::new(
+            "images",
+            format!("{} doesn't support images", model.id),
+        ));
+    }
+    let params = match preset {
+        Some(preset) => match state.preset(&preset) {
+            Some(preset) => params.or(preset),
+            None => {
+                violations.push(Violation::new(
+                    "preset",
+                    format!("there is no preset called {preset}"),
+                ));
+                params
+            }
+        },
+        None => params,
+    }
+    .or(&model.defaults);
+    violations.extend(params.violations(model));
+
+    let system = system.or_else(|| state.system_prompt.clone());
+    if let Some(info) = ModelInfo::lookup(&model.id) {
+        let prompt_tokens =
+            models::estimate_tokens(&prompt) + system.as_deref().map_or(0, models::estimate_tokens);
+        if prompt_tokens >= info.context_window {
+            violations.push(Violation::new(
+                "prompt",
+                format!(
+                    "is about {prompt_tokens} tokens, which doesn't fit in {}'s context window of {}",
+                    model.id, info.context_window
+                ),
+            ));
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let mut params = params
+        .with_default_stop_sequences(model, &state.stop_sequences)
+        .with_effective_seed(model);
+    // clamp what the model would actually use, which may be its default rather than a request
+    if max_tokens_limit.0.is_some() {
+        if let Some(max_tokens) = model.provider.max_tokens(&prompt, params.max_tokens) {
+            params.max_tokens = Some(max_tokens_limit.clamp(max_tokens));
+        }
+    }
+
+    Ok(PreparedPrompt {
+        model,
+        logprobs,
+        prompt,
+        system,
+        images,
+        params,
+        history: Vec::new(),
+    })
 }
 
 async fn prompt(
     State(state): State<AppState>,
-    Json(Prompt { prompt }): Json<Prompt>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let titan_req = TitanRequest::new(prompt);
-    let Ok(prompt) = serde_json::to_vec(&titan_req) else {
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    headers: HeaderMap,
+    PromptBody(mut prompt): PromptBody,
+) -> Result<Response, ApiError> {
+    let response_format = *prompt
+        .response_format
+        .get_or_insert_with(|| ResponseFormat::from_accept(&headers));
+    let n = prompt.n;
+    let prepared = prepare_prompt(&state, &route, max_tokens_limit, prompt)?;
+
+    let Some(n) = n else {
+        let completion = complete(&state, &prepared, &prepared.params).await?;
+
+        return Ok(completion.into_response(response_format));
+    };
+
+    // each candidate gets its own seed, otherwise they would all be the same
+    let candidates: Vec<GenerationParams> = (0..n)
+        .map(|i| {
+            let mut params = prepared.params.clone();
+            params.seed = params.seed.map(|seed| seed.wrapping_add(i));
+
+            params
+        })
+        .collect();
+    let completions = try_join_all(
+        candidates
+            .iter()
+            .map(|params| complete(&state, &prepared, params)),
+    )
+    .await?;
+
+    let res = match response_format {
+        ResponseFormat::Text => Json(
+            completions
+                .into_iter()
+                .map(|completion| completion.text)
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        ResponseFormat::Json => Json(completions).into_response(),
+    };
+
+    Ok(res)
+}
+
+async fn complete(
+    state: &AppState,
+    prepared: &PreparedPrompt<'_>,
+    params: &GenerationParams,
+) -> Result<Completion, ApiError> {
+    let started = Instant::now();
+    let PreparedPrompt {
+        model,
+        logprobs,
+        prompt,
+        system,
+        images,
+        history,
+        ..
+    } = prepared;
+    let prompt = prompt.clone();
+
+    if !model.provider.supports_converse() {
+        let prompt = if history.is_empty() {
+            prompt
+        } else {
+            chat::transcript(history, &prompt)
+        };
+        let (text, logprobs) = invoke_prompt(
+            &state.clients,
+            model,
+            prompt,
+            system.as_deref(),
+            params,
+            *logprobs,
+        )
+        .await?;
+
+        return Ok(Completion {
+            text,
+            logprobs,
+            model: model.id.clone(),
+            input_tokens: None,
+            output_tokens: None,
+            completion_reason: None,
+            seed: params.seed,
+            latency_ms: started.elapsed().as_millis(),
+        });
+    }
+
+    let (prompt, system) = converse::system_prompt(model, system.clone(), prompt);
+    let Some(inference_config) = converse::inference_config(model, &prompt, params) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    let additional_fields = converse::additional_fields(model, params);
+    let Some(messages) = converse::messages(history, prompt, images) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let res = state
+        .clients
+        .send(model, |client, model_id| {
+            client
+                .converse()
+                .model_id(model_id)
+                .set_messages(Some(messages.clone()))
+                .set_system(system.clone())
+                .inference_config(inference_config.clone())
+                .set_additional_model_request_fields(additional_fields.clone())
+                .send()
+        })
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            println!("Couldn't generate with {}: {err:?}", model.id);
+            return Err(pool::error_status(&err).into());
+        }
+    };
+
+    let Some(text) = res.output.and_then(converse::output_text) else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    };
+
+    Ok(Completion {
+        text,
+        model: model.id.clone(),
+        input_tokens: res.usage.as_ref().map(|usage| usage.input_tokens),
+        output_tokens: res.usage.as_ref().map(|usage| usage.output_tokens),
+        completion_reason: Some(res.stop_reason.as_str().to_string()),
+        seed: params.seed,
+        logprobs: None,
+        latency_ms: started.elapsed().as_millis(),
+    })
+}
+
+/// The seed a prompt was generated with, so the caller can reproduce it.
+fn seed_header(seed: Option<u32>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(seed) = seed {
+        headers.insert("x-seed", HeaderValue::from(seed));
+    }
+
+    headers
+}
+
+/// For models the Converse API doesn't support, using the family's own request schema.
+async fn invoke_prompt(
+    clients: &ClientPool,
+    model: &Model,
+    prompt: String,
+    system: Option<&str>,
+    params: &GenerationParams,
+    logprobs: bool,
+) -> Result<(String, Option<Vec<TokenLogprob>>), StatusCode> {
+    let Some(prompt) = model.provider.build_request_body(prompt, system, params) else {
         return Err(StatusCode::BAD_REQUEST);
     };
 
     let blob = Blob::new(prompt);
 
-    let res = state
-        .client
-        .invoke_model()
-        .body(blob)
-        .model_id("amazon.titan-text-lite-v1:0:4k")
-        .send()
-        .await
-        .unwrap();
+    let res = clients
+        .send(model, |client, model_id| {
+            client
+                .invoke_model()
+                .body(blob.clone())
+                .model_id(model_id)
+                .send()
+        })
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            println!("Couldn't invoke {}: {err:?}", model.id);
+            return Err(pool::error_status(&err));
+        }
+    };
 
     let res: &[u8] = &res.body.into_inner();
-    let Ok(response_body) = serde_json::from_slice::<TitanResponse>(res) else {
+    let Some(output_text) = model.provider.parse_response(res) else {
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     };
+    let logprobs = logprobs
+        .then(|| model.provider.parse_logprobs(res))
+        .flatten();
 
-    let Some(TitanTextResult { output_text, .. }) = response_body.results.first() else {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    };
+    Ok((output_text, logprobs))
+}
 
-    Ok(output_text.to_owned())
+#[derive(Deserialize)]
+struct RawInvocation {
+    model: String,
+    /// Sent to `invoke_model` as is, so it has to be in the model's own schema.
+    body: serde_json::Value,
 }
 
-async fn streamed_prompt(
+/// For model features we don't wrap yet. Callers with a `max_tokens` limit can't use this, as
+/// there's no knowing where a raw body keeps its token count.
+async fn invoke_raw(
     State(state): State<AppState>,
-    Json(Prompt { prompt }): Json<Prompt>,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    Json(RawInvocation { model, body }): Json<RawInvocation>,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    let titan_req = TitanRequest::new(prompt);
-    let Ok(message) = serde_json::to_vec(&titan_req) else {
+    if max_tokens_limit.0.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if !state.permits(&model) {
         return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let Ok(body) = serde_json::to_vec(&body) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let blob = Blob::new(body);
+    let invoke = |client: &Client, model_id: String| {
+        client
+            .invoke_model()
+            .body(blob.clone())
+            .model_id(model_id)
+            .content_type("application/json")
+            .send()
     };
 
-    let blob = Blob::new(message);
+    // text models may need invoking by a provisioned ARN or inference profile instead
+    let res = match state.model(Some(&model)) {
+        Some(model) => state.clients.send(model, invoke).await,
+        None => state.clients.send_to(&model, invoke).await,
+    };
+    let res = match res {
+        Ok(res) => res,
+        // pass Bedrock's own complaints about the body back to the caller
+        Err(err) => {
+            let status = err
+                .raw_response()
+                .and_then(|res| StatusCode::from_u16(res.status().as_u16()).ok())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
 
-    let res = state
-        .client
-        .invoke_model_with_response_stream()
-        .body(blob)
-        .model_id("amazon.titan-text-lite-v1:0:4k")
-        .send()
-        .await
-        .unwrap();
-
-    let stream = stream::unfold(res.body, |mut state| async move {
-        let message = state.recv().await.unwrap();
-
-        match message {
-            Some(ResponseStream::Chunk(chunk)) => {
-                let Ok(response_body) =
-                    serde_json::from_slice::<TitanResponse>(&chunk.bytes.unwrap().into_inner())
-                else {
-                    println!("Unable to deserialize response body :(");
-                    return None;
-                };
-
-                let Some(TitanTextResult { output_text, .. }) = response_body.results.first()
-                else {
-                    println!("No results :(");
-                    return None;
-                };
-
-                Some((output_text.to_owned(), state))
-            }
-            _ => None,
+            return Err(status);
         }
-    });
+    };
 
-    let stream = StreamBodyAs::text(stream);
+    let content_type = res.content_type.clone();
 
-    Ok(stream)
+    Ok((
+        [(header::CONTENT_TYPE, content_type)],
+        res.body.into_inner(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ModelsQuery {
+    /// One of `TEXT`, `IMAGE` or `EMBEDDING`.
+    output_modality: Option<String>,
+    streaming: Option<bool>,
 }
 
 #[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct TitanRequest {
-    input_text: String,
-    text_generation_config: TextGenConfig,
+struct FoundationModel {
+    id: String,
+    name: Option<String>,
+    provider: Option<String>,
+    output_modalities: Vec<String>,
+    streaming: bool,
+    /// Whether requests to this deployment can ask for this model.
+    allowed: bool,
 }
 
-impl TitanRequest {
-    fn new(prompt: String) -> Self {
-        Self {
-            input_text: prompt,
-            text_generation_config: TextGenConfig {
-                temperature: 0.0,
-                top_p: 0.0,
-                max_token_count: 100,
-                stop_sequences: vec!["|".to_string()],
-            },
-        }
-    }
+async fn list_models(
+    State(state): State<AppState>,
+    Query(ModelsQuery {
+        output_modality,
+        streaming,
+    }): Query<ModelsQuery>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let Ok(res) = state
+        .control_client
+        .list_foundation_models()
+        .set_by_output_modality(output_modality.as_deref().map(ModelModality::from))
+        .send()
+        .await
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let models: Vec<FoundationModel> = res
+        .model_summaries
+        .unwrap_or_default()
+        .into_iter()
+        .map(|summary| FoundationModel {
+            allowed: state.model(Some(&summary.model_id)).is_some(),
+            id: summary.model_id,
+            name: summary.model_name,
+            provider: summary.provider_name,
+            output_modalities: summary
+                .output_modalities
+                .unwrap_or_default()
+                .iter()
+                .map(|modality| modality.as_str().to_string())
+                .collect(),
+            streaming: summary.response_streaming_supported.unwrap_or_default(),
+        })
+        .filter(|model| streaming.is_none_or(|streaming| model.streaming == streaming))
+        .collect();
+
+    Ok(Json(models))
 }
 
 #[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct TextGenConfig {
-    temperature: f32,
-    top_p: f32,
-    max_token_count: i32,
-    stop_sequences: Vec<String>,
+struct ModelDetails {
+    id: String,
+    #[serde(flatten)]
+    info: &'static ModelInfo,
+    allowed: bool,
+}
+
+async fn model_details(
+    State(state): State<AppState>,
+    Path(model_id): Path<String>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let Some(info) = ModelInfo::lookup(&model_id) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    Ok(Json(ModelDetails {
+        allowed: state.model(Some(&model_id)).is_some(),
+        id: model_id,
+        info,
+    }))
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    client: Client,
+    clients: Arc<ClientPool>,
+    /// The Bedrock control plane, for things like listing models rather than invoking them.
+    control_client: aws_sdk_bedrock::Client,
+    default_model: Model,
+    /// Models a request can ask for by id, the default model is always allowed.
+    allowed_models: Arc<Vec<Model>>,
+    /// Alias to model id.
+    aliases: Arc<Vec<(String, String)>>,
+    presets: Arc<Vec<(String, GenerationParams)>>,
+    limits: Arc<LimitsConfig>,
+    prompt_wrapper: Arc<PromptWrapper>,
+    streaming: Arc<StreamingConfig>,
+    streams: Arc<streaming::Streams>,
+    chat: Arc<ChatConfig>,
+    documents: Arc<DocumentsConfig>,
+    /// For the admin endpoints, which can't be used at all without one.
+    admin_key: Option<String>,
+    db: PgPool,
+    /// Where embeddings are kept for retrieval.
+    vectors: Arc<dyn VectorStore>,
+    /// For `/knowledge-base/ask`, which isn't there without one.
+    knowledge_base: Option<Arc<KnowledgeBase>>,
+    /// For `/documents/sync`, which isn't there without one.
+    s3_sync: Option<Arc<S3Sync>>,
+    /// For fetching web pages, see [`documents::web`].
+    http: reqwest::Client,
+    stop_sequences: Arc<Vec<String>>,
+    system_prompt: Option<String>,
+    default_embedding_model: EmbeddingModel,
+    allowed_embedding_models: Arc<Vec<EmbeddingModel>>,
+    max_embedding_inputs: usize,
+    embedding_cache: Arc<EmbeddingCache>,
+    /// Held by each running embedding job, see [`embeddings::jobs`].
+    embedding_jobs: Arc<Semaphore>,
+    default_image_model: ImageModel,
+    allowed_image_models: Arc<Vec<ImageModel>>,
 }
 
 impl AppState {
-    fn new(client: Client) -> Self {
-        Self { client }
+    fn new(
+        clients: ClientPool,
+        control_client: aws_sdk_bedrock::Client,
+        secrets: &SecretStore,
+        db: PgPool,
+        vectors: Arc<dyn VectorStore>,
+        knowledge_base: Option<KnowledgeBase>,
+        s3_sync: Option<S3Sync>,
+    ) -> Self {
+        let config = ModelConfig::from_secrets(secrets);
+        // the default model can be given as an alias too
+        let default_model_id = resolve_alias(&config.aliases, &config.default_model_id).to_string();
+        let chat = ChatConfig::from_secrets(secrets);
+        // and so can the summary model, which is always allowed like the default model is
+        let summary_model_id = chat
+            .summary_model_id
+            .as_deref()
+            .map(|model_id| resolve_alias(&config.aliases, model_id).to_string());
+        let custom_models = config
+            .custom_models
+            .into_iter()
+            .map(|(arn, family)| Model::with_family(arn, family));
+        let mut allowed_models: Vec<Model> = config
+            .allowed_model_ids
+            .into_iter()
+            .map(supported_model)
+            .chain(custom_models)
+            .collect();
+        for model_id in config
+            .aliases
+            .iter()
+            .map(|(_, model_id)| model_id)
+            .chain([&default_model_id])
+            .chain(&summary_model_id)
+        {
+            if !allowed_models.iter().any(|model| &model.id == model_id) {
+                allowed_models.push(supported_model(model_id.clone()));
+            }
+        }
+        // provisioned models are allowed even if they weren't listed, as someone is paying for them
+        for (model_id, arn) in config.provisioned_throughput {
+            match allowed_models.iter_mut().find(|model| model.id == model_id) {
+                Some(model) => model.provisioned_arn = Some(arn),
+                None => {
+                    let mut model = supported_model(model_id);
+                    model.provisioned_arn = Some(arn);
+                    allowed_models.push(model);
+                }
+            }
+        }
+        if config.prefer_inference_profiles {
+            for model in &mut allowed_models {
+                model.prefer_inference_profile();
+            }
+        }
+        for model in &mut allowed_models {
+            if let Some((_, defaults)) = config
+                .family_defaults
+                .iter()
+                .find(|(family, _)| *family == model.family)
+            {
+                assert!(
+                    defaults.is_valid_for(model),
+                    "the configured defaults for {} are out of range",
+                    model.id
+                );
+                model.defaults = defaults.clone();
+            }
+        }
+        let denied = &config.denied_model_ids;
+        allowed_models.retain(|model| !is_denied(denied, &model.id));
+        let default_model = allowed_models
+            .iter()
+            .find(|model| model.id == default_model_id)
+            .cloned()
+            .unwrap_or_else(|| panic!("the default model {default_model_id} is denied"));
+        let mut allowed_embedding_models: Vec<EmbeddingModel> = config
+            .allowed_embedding_model_ids
+            .into_iter()
+            .map(supported_embedding_model)
+            .collect();
+        if !allowed_embedding_models
+            .iter()
+            .any(|model| model.id == config.embedding_model_id)
+        {
+            allowed_embedding_models
+                .push(supported_embedding_model(config.embedding_model_id.clone()));
+        }
+        allowed_embedding_models.retain(|model| !is_denied(denied, &model.id));
+        let default_embedding_model = allowed_embedding_models
+            .iter()
+            .find(|model| model.id == config.embedding_model_id)
+            .cloned()
+            .unwrap_or_else(|| {
+                panic!(
+                    "the default embedding model {} is denied",
+                    config.embedding_model_id
+                )
+            });
+        let mut allowed_image_models: Vec<ImageModel> = config
+            .allowed_image_model_ids
+            .into_iter()
+            .map(supported_image_model)
+            .collect();
+        if !allowed_image_models
+            .iter()
+            .any(|model| model.id == config.image_model_id)
+        {
+            allowed_image_models.push(supported_image_model(config.image_model_id.clone()));
+        }
+        allowed_image_models.retain(|model| !is_denied(denied, &model.id));
+        let default_image_model = allowed_image_models
+            .iter()
+            .find(|model| model.id == config.image_model_id)
+            .cloned()
+            .unwrap_or_else(|| {
+                panic!(
+                    "the default image model {} is denied",
+                    config.image_model_id
+                )
+            });
+
+        Self {
+            clients: Arc::new(clients),
+            control_client,
+            default_model,
+            allowed_models: Arc::new(allowed_models),
+            aliases: Arc::new(config.aliases),
+            presets: Arc::new(config.presets),
+            limits: Arc::new(LimitsConfig::from_secrets(secrets)),
+            prompt_wrapper: Arc::new(PromptWrapper::from_secrets(secrets)),
+            streaming: Arc::new(StreamingConfig::from_secrets(secrets)),
+            chat: Arc::new(chat),
+            documents: Arc::new(DocumentsConfig::from_secrets(secrets)),
+            admin_key: secrets.get("BEDROCK_ADMIN_KEY"),
+            streams: Arc::default(),
+            db,
+            vectors,
+            knowledge_base: knowledge_base.map(Arc::new),
+            s3_sync: s3_sync.map(Arc::new),
+            http: documents::web::client(),
+            stop_sequences: Arc::new(config.stop_sequences),
+            system_prompt: config.system_prompt,
+            default_embedding_model,
+            allowed_embedding_models: Arc::new(allowed_embedding_models),
+            max_embedding_inputs: config.max_embedding_inputs,
+            embedding_cache: Arc::new(EmbeddingCache::new(config.embedding_cache_size)),
+            embedding_jobs: Arc::new(Semaphore::new(embeddings::jobs::MAX_RUNNING_JOBS)),
+            default_image_model,
+            allowed_image_models: Arc::new(allowed_image_models),
+        }
+    }
+
+    /// Panics if the default model doesn't exist or this account can't invoke it, so a typo in
+    /// Secrets.toml fails the deployment rather than every request.
+    async fn validate_default_model(&self) {
+        let model_id = models::base_model_id(&self.default_model.id);
+        // custom models have nothing to look up in the foundation model catalogue
+        if model_id.starts_with("arn:") {
+            return;
+        }
+
+        let availability = self
+            .control_client
+            .get_foundation_model_availability()
+            .model_id(model_id)
+            .send()
+            .await
+            .unwrap_or_else(|err| panic!("couldn't look up the default model {model_id}: {err}"));
+
+        assert!(
+            availability.authorization_status == AuthorizationStatus::Authorized
+                && availability.entitlement_availability == EntitlementAvailability::Available
+                && availability.region_availability == RegionAvailability::Available,
+            "the default model {model_id} isn't available to this account, has access been granted in the Bedrock console?"
+        );
     }
+
+    /// Returns `None` if the requested model isn't on the allowlist. Aliases are resolved first.
+    fn model(&self, requested: Option<&str>) -> Option<&Model> {
+        match requested {
+            Some(model_id) => {
+                let model_id = resolve_alias(&self.aliases, model_id);
+
+                self.allowed_models
+                    .iter()
+                    .find(|model| model.id == model_id)
+            }
+            None => Some(&self.default_model),
+        }
+    }
+
+    fn preset(&self, name: &str) -> Option<&GenerationParams> {
+        self.presets
+            .iter()
+            .find(|(preset, _)| preset == name)
+            .map(|(_, params)| params)
+    }
+
+    /// Whether `model_id` can be used with any endpoint. Denied models are never on an allowlist.
+    fn permits(&self, model_id: &str) -> bool {
+        self.model(Some(model_id)).is_some()
+            || self.embedding_model(Some(model_id)).is_some()
+            || self.image_model(Some(model_id)).is_some()
+    }
+
+    fn embedding_model(&self, requested: Option<&str>) -> Option<&EmbeddingModel> {
+        match requested {
+            Some(model_id) => self
+                .allowed_embedding_models
+                .iter()
+                .find(|model| model.id == model_id),
+            None => Some(&self.default_embedding_model),
+        }
+    }
+
+    fn image_model(&self, requested: Option<&str>) -> Option<&ImageModel> {
+        match requested {
+            Some(model_id) => self
+                .allowed_image_models
+                .iter()
+                .find(|model| model.id == model_id),
+            None => Some(&self.default_image_model),
+        }
+    }
+}
+
+/// Denylist entries match any model id that starts with them.
+fn is_denied(denied: &[String], model_id: &str) -> bool {
+    denied
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix.as_str()))
+}
+
+/// Returns the model id for `alias`, or `alias` itself if it isn't one.
+fn resolve_alias<'a>(aliases: &'a [(String, String)], alias: &'a str) -> &'a str {
+    aliases
+        .iter()
+        .find(|(name, _)| name == alias)
+        .map_or(alias, |(_, model_id)| model_id)
+}
+
+fn supported_model(model_id: String) -> Model {
+    Model::new(model_id.clone()).unwrap_or_else(|| panic!("{model_id} is not a supported model"))
+}
+
+fn supported_embedding_model(model_id: String) -> EmbeddingModel {
+    EmbeddingModel::new(model_id.clone())
+        .unwrap_or_else(|| panic!("{model_id} is not a supported embedding model"))
 }
 
-async fn create_client(secrets: SecretStore) -> Client {
+fn supported_image_model(model_id: String) -> ImageModel {
+    ImageModel::new(model_id.clone())
+        .unwrap_or_else(|| panic!("{model_id} is not a supported image model"))
+}
+
+async fn aws_config(secrets: &SecretStore, region: &str) -> SdkConfig {
     let access_key_id = secrets
         .get("AWS_ACCESS_KEY_ID")
         .expect("AWS_ACCESS_KEY_ID not set in Secrets.toml");
     let secret_access_key = secrets
         .get("AWS_SECRET_ACCESS_KEY")
         .expect("AWS_ACCESS_KEY_ID not set in Secrets.toml");
-    let aws_url = secrets
-        .get("AWS_URL")
-        .expect("AWS_ACCESS_KEY_ID not set in Secrets.toml");
-
     // note here that the "None" is in place of a session token
     let creds = Credentials::from_keys(access_key_id, secret_access_key, None);
 
-    let cfg = aws_config::from_env()
-        .endpoint_url(aws_url)
-        .region(Region::new("eu-west-1"))
+    aws_config::from_env()
+        .region(Region::new(region.to_string()))
         .credentials_provider(creds)
         .load()
-        .await;
+        .await
+}
+
+fn create_client(secrets: &SecretStore, cfg: &SdkConfig, region: &str) -> Client {
+    // optional, and can contain "{region}" when using more than one region
+    let aws_url = secrets
+        .get("AWS_URL")
+        .map(|aws_url| aws_url.replace("{region}", region));
+
+    // the endpoint is only set for the runtime client, as the control plane lives elsewhere
+    let mut cfg =
+        aws_sdk_bedrockruntime::config::Builder::from(cfg).region(Region::new(region.to_string()));
+    cfg.set_endpoint_url(aws_url);
+
+    Client::from_conf(cfg.build())
+}
+
+fn create_client_pool(secrets: &SecretStore, cfg: &SdkConfig, regions: &[String]) -> ClientPool {
+    let clients = regions
+        .iter()
+        .map(|region| RegionalClient {
+            region: region.clone(),
+            client: create_client(secrets, cfg, region),
+        })
+        .collect();
 
-    Client::new(&cfg)
+    ClientPool::new(clients, config::flag(secrets, "BEDROCK_ROUND_ROBIN"))
 }
 
 #[shuttle_runtime::main]
-async fn main(#[shuttle_runtime::Secrets] secrets: SecretStore) -> shuttle_axum::ShuttleAxum {
-    let client = create_client(secrets).await;
-    let appstate = AppState::new(client);
+async fn main(
+    #[shuttle_runtime::Secrets] secrets: SecretStore,
+    #[shuttle_shared_db::Postgres] db: PgPool,
+    // a container is started for this when running locally, deployments need a Qdrant Cloud cluster
+    // unless BEDROCK_VECTOR_STORE is pgvector, in which case it's never used
+    #[shuttle_qdrant::Qdrant(
+        cloud_url = "{secrets.QDRANT_URL}",
+        api_key = "{secrets.QDRANT_API_KEY}"
+    )]
+    qdrant: vectors::qdrant::Client,
+) -> shuttle_axum::ShuttleAxum {
+    sqlx::migrate!()
+        .run(&db)
+        .await
+        .expect("couldn't run the database migrations");
+    embeddings::jobs::fail_interrupted(&db).await;
+
+    // eg. "eu-west-1,eu-central-1", the first region is used for everything that isn't invoking a model
+    let mut regions = config::list(&secrets, "AWS_REGIONS");
+    if regions.is_empty() {
+        regions.push(DEFAULT_REGION.to_string());
+    }
+
+    let cfg = aws_config(&secrets, &regions[0]).await;
+    let clients = create_client_pool(&secrets, &cfg, &regions);
+    let control_client = aws_sdk_bedrock::Client::new(&cfg);
+    let collection = secrets
+        .get("BEDROCK_VECTOR_COLLECTION")
+        .unwrap_or_else(|| vectors::DEFAULT_COLLECTION.to_string());
+    let vectors: Arc<dyn VectorStore> = match secrets.get("BEDROCK_VECTOR_STORE").as_deref() {
+        None | Some("qdrant") => Arc::new(QdrantStore::new(qdrant, collection)),
+        Some("pgvector") => Arc::new(PgVectorStore::new(db.clone(), collection)),
+        Some(other) => panic!("{other} is not a supported vector store, use qdrant or pgvector"),
+    };
+    // eg. "ABCDEFGHIJ", from the Bedrock console
+    let knowledge_base = secrets
+        .get("BEDROCK_KNOWLEDGE_BASE_ID")
+        .map(|id| KnowledgeBase {
+            client: aws_sdk_bedrockagentruntime::Client::new(&cfg),
+            id,
+            region: regions[0].clone(),
+        });
+    // eg. "docs" and "handbook/", the prefix can be left out to sync the whole bucket
+    let s3_sync = secrets.get("BEDROCK_S3_SYNC_BUCKET").map(|bucket| S3Sync {
+        client: aws_sdk_s3::Client::new(&cfg),
+        bucket,
+        prefix: secrets.get("BEDROCK_S3_SYNC_PREFIX").unwrap_or_default(),
+        // the documents are for whoever has this key, or for requests without one
+        tenant: secrets
+            .get("BEDROCK_S3_SYNC_API_KEY")
+            .as_deref()
+            .map(conversations::key_hash)
+            .unwrap_or_default(),
+        interval: config::number(&secrets, "BEDROCK_S3_SYNC_INTERVAL_MINS")
+            .map(|mins: u64| Duration::from_secs(mins * 60)),
+        running: tokio::sync::Mutex::new(()),
+    });
+    let appstate = AppState::new(
+        clients,
+        control_client,
+        &secrets,
+        db,
+        vectors,
+        knowledge_base,
+        s3_sync,
+    );
+    appstate.validate_default_model().await;
+    tokio::spawn(retention::sweep(appstate.clone()));
+    tokio::spawn(streaming::sweep(appstate.streams.clone()));
+    tokio::spawn(documents::sync::schedule(appstate.clone()));
+    let admin = Router::new()
+        .route("/users/:user_id/purge", post(admin::purge))
+        .route("/documents/sync", post(documents::sync::run))
+        .route("/admin/keys", get(auth::list).post(auth::create))
+        .route("/admin/keys/:id", patch(auth::update).delete(auth::revoke))
+        .route_layer(middleware::from_fn_with_state(
+            appstate.clone(),
+            admin::require,
+        ));
     let router = Router::new()
-        .route("/", get(hello_world))
         .route("/prompt", post(prompt))
-        .route("/prompt/streamed", post(streamed_prompt))
+        .route("/prompt/streamed", post(streaming::text))
+        .route("/prompt/streamed/:id", get(streaming::resume))
+        .route("/prompt/sse", post(streaming::sse))
+        .route("/prompt/compare", post(streaming::compare))
+        .route("/chat", post(chat::chat))
+        .route(
+            "/conversations",
+            get(conversations::list).post(conversations::create),
+        )
+        .route("/conversations/search", get(conversations::search))
+        .route("/conversations/import", post(import::import))
+        .route(
+            "/conversations/:id",
+            get(conversations::get)
+                .patch(conversations::update)
+                .delete(conversations::delete),
+        )
+        .route("/conversations/:id/export", get(conversations::export))
+        .route("/conversations/:id/fork", post(conversations::fork))
+        .route(
+            "/conversations/:id/regenerate",
+            post(conversations::regenerate),
+        )
+        .route(
+            "/conversations/:id/messages/:message_id",
+            put(conversations::edit),
+        )
+        .route("/messages/:id/feedback", post(feedback::rate))
+        .route("/feedback", get(feedback::list))
+        .route(
+            "/users/:user_id/memories",
+            get(memories::list).post(memories::create),
+        )
+        .route(
+            "/users/:user_id/memories/:id",
+            put(memories::update).delete(memories::delete),
+        )
+        .route("/sessions", post(sessions::create))
+        .route(
+            "/sessions/:token",
+            post(sessions::send).delete(sessions::end),
+        )
+        .route("/models", get(list_models))
+        .route("/models/:id", get(model_details))
+        .route("/embeddings", post(embeddings::embeddings))
+        .route("/embeddings/jobs", post(embeddings::jobs::create))
+        .route("/embeddings/jobs/:id", get(embeddings::jobs::get))
+        .route("/documents", get(documents::list).post(documents::create))
+        .route("/documents/url", post(documents::web::create))
+        .route(
+            "/documents/:id",
+            get(documents::get).delete(documents::delete),
+        )
+        .route("/search", post(search::search))
+        .route("/ask", post(ask::ask))
+        .route("/eval", post(eval::evaluate))
+        .route("/knowledge-base/ask", post(knowledge_base::ask))
+        .route("/vectors", post(vectors::upsert))
+        .route("/vectors/query", post(vectors::query))
+        .route("/vectors/delete", post(vectors::delete))
+        .route("/images/generate", post(images::generate))
+        .route("/invoke/raw", post(invoke_raw))
+        .route_layer(middleware::from_fn_with_state(
+            appstate.clone(),
+            auth::require,
+        ))
+        .route("/", get(hello_world))
+        .merge(admin)
+        .layer(middleware::from_fn_with_state(
+            appstate.clone(),
+            policy::enforce,
+        ))
+        .layer(middleware::from_fn_with_state(
+            appstate.clone(),
+            limits::enforce,
+        ))
         .with_state(appstate);
 
     Ok(router.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn accepting(accept: &str) -> ResponseFormat {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+
+        ResponseFormat::from_accept(&headers)
+    }
+
+    #[test]
+    fn the_first_format_accepted_is_used() {
+        assert_eq!(accepting("application/json"), ResponseFormat::Json);
+        assert_eq!(
+            accepting("text/html, application/json;q=0.9, text/plain"),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            accepting("text/plain; charset=utf-8, application/json"),
+            ResponseFormat::Text
+        );
+    }
+
+    #[test]
+    fn anything_else_is_text() {
+        assert_eq!(accepting("*/*"), ResponseFormat::Text);
+        assert_eq!(
+            ResponseFormat::from_accept(&HeaderMap::new()),
+            ResponseFormat::Text
+        );
+    }
+}
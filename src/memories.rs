@@ -0,0 +1,184 @@
+//! Facts about a user (eg. "prefers metric units") that are added to the system prompt of every
+//! chat with them, so they don't have to be repeated in each conversation. Users belong to the
+//! API key that added their memories, so two keys can have users with the same id.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    conversations,
+    error::{ApiError, Violation},
+    AppState,
+};
+
+/// Every fact goes into every turn, so there can't be too many of them.
+const MAX_MEMORIES: i64 = 50;
+const MAX_FACT_LENGTH: usize = 1000;
+
+#[derive(Serialize, FromRow)]
+pub struct Memory {
+    id: i64,
+    fact: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct NewMemory {
+    fact: String,
+}
+
+/// Oldest first.
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let memories: Vec<Memory> = sqlx::query_as(
+        "SELECT id, fact, created_at, updated_at FROM memories \
+         WHERE user_id = $1 AND owner IS NOT DISTINCT FROM $2 ORDER BY id",
+    )
+    .bind(&user_id)
+    .bind(conversations::owner(&headers))
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(json!({ "memories": memories })))
+}
+
+pub async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(NewMemory { fact }): Json<NewMemory>,
+) -> Result<impl IntoResponse, ApiError> {
+    let fact = fact.trim();
+    if let Some(violation) = fact_violation(fact) {
+        return Err(ApiError::Invalid(vec![violation]));
+    }
+
+    let owner = conversations::owner(&headers);
+    let mut tx = state.db.begin().await?;
+    // taken so two requests can't both squeeze in under the limit
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    let count: i64 = sqlx::query_scalar(
+        "SELECT count(*) FROM memories WHERE user_id = $1 AND owner IS NOT DISTINCT FROM $2",
+    )
+    .bind(&user_id)
+    .bind(&owner)
+    .fetch_one(&mut *tx)
+    .await?;
+    if count >= MAX_MEMORIES {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "fact",
+            format!("can't be added, users can have at most {MAX_MEMORIES} memories"),
+        )]));
+    }
+    let memory: Memory = sqlx::query_as(
+        "INSERT INTO memories (user_id, fact, owner) VALUES ($1, $2, $3) \
+         RETURNING id, fact, created_at, updated_at",
+    )
+    .bind(&user_id)
+    .bind(fact)
+    .bind(&owner)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(memory)))
+}
+
+pub async fn update(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((user_id, id)): Path<(String, i64)>,
+    Json(NewMemory { fact }): Json<NewMemory>,
+) -> Result<impl IntoResponse, ApiError> {
+    let fact = fact.trim();
+    if let Some(violation) = fact_violation(fact) {
+        return Err(ApiError::Invalid(vec![violation]));
+    }
+
+    let memory: Option<Memory> = sqlx::query_as(
+        "UPDATE memories SET fact = $3, updated_at = now() \
+         WHERE id = $1 AND user_id = $2 AND owner IS NOT DISTINCT FROM $4 \
+         RETURNING id, fact, created_at, updated_at",
+    )
+    .bind(id)
+    .bind(&user_id)
+    .bind(fact)
+    .bind(conversations::owner(&headers))
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(memory) = memory else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    Ok(Json(memory))
+}
+
+pub async fn delete(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((user_id, id)): Path<(String, i64)>,
+) -> Result<StatusCode, ApiError> {
+    let deleted = sqlx::query(
+        "DELETE FROM memories WHERE id = $1 AND user_id = $2 AND owner IS NOT DISTINCT FROM $3",
+    )
+    .bind(id)
+    .bind(&user_id)
+    .bind(conversations::owner(&headers))
+    .execute(&state.db)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The user's facts written out for the system prompt, or `None` if there aren't any.
+pub async fn context(
+    db: &PgPool,
+    owner: Option<&str>,
+    user_id: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let facts: Vec<String> = sqlx::query_scalar(
+        "SELECT fact FROM memories WHERE user_id = $1 AND owner IS NOT DISTINCT FROM $2 \
+         ORDER BY id",
+    )
+    .bind(user_id)
+    .bind(owner)
+    .fetch_all(db)
+    .await?;
+    if facts.is_empty() {
+        return Ok(None);
+    }
+
+    let facts: Vec<String> = facts.into_iter().map(|fact| format!("- {fact}")).collect();
+
+    Ok(Some(format!(
+        "Things to remember about the user:\n{}",
+        facts.join("\n")
+    )))
+}
+
+fn fact_violation(fact: &str) -> Option<Violation> {
+    if fact.is_empty() {
+        return Some(Violation::new("fact", "must not be empty"));
+    }
+
+    (fact.len() > MAX_FACT_LENGTH)
+        .then(|| Violation::new("fact", format!("must be at most {MAX_FACT_LENGTH} bytes")))
+}
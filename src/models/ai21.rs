@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, with_system_prefix, ModelProvider, TokenLogprob};
+
+pub struct Jamba;
+
+impl ModelProvider for Jamba {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&JambaRequest::new(prompt, system, params)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<JambaResponse>(body).ok()?;
+        let choice = response_body.choices.into_iter().next()?;
+
+        Some(choice.message.content)
+    }
+}
+
+pub struct Jurassic;
+
+impl ModelProvider for Jurassic {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&JurassicRequest::new(
+            with_system_prefix(system, prompt),
+            params,
+        ))
+        .ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<JurassicResponse>(body).ok()?;
+        let completion = response_body.completions.into_iter().next()?;
+
+        Some(completion.data.text)
+    }
+
+    /// Jurassic always returns the tokens it generated along with their log probabilities.
+    fn supports_logprobs(&self) -> bool {
+        true
+    }
+
+    fn parse_logprobs(&self, body: &[u8]) -> Option<Vec<TokenLogprob>> {
+        let response_body = serde_json::from_slice::<JurassicResponse>(body).ok()?;
+        let completion = response_body.completions.into_iter().next()?;
+
+        Some(
+            completion
+                .data
+                .tokens
+                .into_iter()
+                .map(|token| TokenLogprob {
+                    token: token.generated_token.token,
+                    logprob: token.generated_token.logprob,
+                })
+                .collect(),
+        )
+    }
+
+    fn supports_streaming(&self) -> bool {
+        false
+    }
+
+    fn supports_converse(&self) -> bool {
+        false
+    }
+
+    fn supports_system_prompt(&self) -> bool {
+        false
+    }
+}
+
+/// Jamba models use a chat-style body similar to OpenAI's.
+#[derive(Serialize)]
+struct JambaRequest {
+    messages: Vec<JambaMessage>,
+    max_tokens: i32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+}
+
+impl JambaRequest {
+    fn new(prompt: String, system: Option<&str>, params: &GenerationParams) -> Self {
+        let system = system.map(|system| JambaMessage {
+            role: "system".to_string(),
+            content: system.to_string(),
+        });
+        let user = JambaMessage {
+            role: "user".to_string(),
+            content: prompt,
+        };
+
+        Self {
+            messages: system.into_iter().chain([user]).collect(),
+            max_tokens: params.max_tokens.unwrap_or(512),
+            temperature: params.temperature.unwrap_or(0.0),
+            top_p: params.top_p,
+            stop: params.stop_sequences.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JambaMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct JambaResponse {
+    choices: Vec<JambaChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JambaChoice {
+    message: JambaResponseMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct JambaResponseMessage {
+    content: String,
+}
+
+/// The older Jurassic-2 models take a bare prompt, and do not support streaming.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JurassicRequest {
+    prompt: String,
+    max_tokens: i32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<Penalty>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<Penalty>,
+    num_results: i32,
+}
+
+/// Jurassic wraps each penalty in an object, which can also say which tokens it applies to.
+#[derive(Serialize)]
+struct Penalty {
+    scale: f32,
+}
+
+impl JurassicRequest {
+    fn new(prompt: String, params: &GenerationParams) -> Self {
+        Self {
+            prompt,
+            max_tokens: params.max_tokens.unwrap_or(200),
+            temperature: params.temperature.unwrap_or(0.0),
+            top_p: params.top_p,
+            stop_sequences: params.stop_sequences.clone(),
+            presence_penalty: params.presence_penalty.map(|scale| Penalty { scale }),
+            frequency_penalty: params.frequency_penalty.map(|scale| Penalty { scale }),
+            num_results: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct JurassicResponse {
+    completions: Vec<JurassicCompletion>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JurassicCompletion {
+    data: JurassicCompletionData,
+}
+
+#[derive(Deserialize, Debug)]
+struct JurassicCompletionData {
+    text: String,
+    #[serde(default)]
+    tokens: Vec<JurassicToken>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JurassicToken {
+    generated_token: JurassicGeneratedToken,
+}
+
+#[derive(Deserialize, Debug)]
+struct JurassicGeneratedToken {
+    token: String,
+    logprob: f64,
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, ModelProvider};
+
+pub struct Claude;
+
+impl ModelProvider for Claude {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&ClaudeRequest::new(prompt, system, params)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<ClaudeResponse>(body).ok()?;
+
+        Some(response_body.text())
+    }
+
+    fn additional_fields(&self, params: &GenerationParams) -> Option<serde_json::Value> {
+        let top_k = params.top_k?;
+
+        Some(serde_json::json!({ "top_k": top_k }))
+    }
+
+    /// Every Claude 3 model can answer questions about images.
+    fn supports_images(&self) -> bool {
+        true
+    }
+}
+
+/// Bedrock only accepts this fixed version string for the Messages API.
+const ANTHROPIC_VERSION: &str = "bedrock-2023-05-31";
+
+#[derive(Serialize)]
+struct ClaudeRequest {
+    anthropic_version: &'static str,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    messages: Vec<ClaudeMessage>,
+}
+
+impl ClaudeRequest {
+    fn new(prompt: String, system: Option<&str>, params: &GenerationParams) -> Self {
+        Self {
+            anthropic_version: ANTHROPIC_VERSION,
+            max_tokens: params.max_tokens.unwrap_or(512),
+            system: system.map(str::to_string),
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            stop_sequences: params.stop_sequences.clone(),
+            messages: vec![ClaudeMessage {
+                role: "user".to_string(),
+                content: vec![ContentBlock::Text { text: prompt }],
+            }],
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ClaudeMessage {
+    role: String,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    /// Anything we don't handle yet (eg. tool use) is skipped rather than failing the whole response.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClaudeResponse {
+    content: Vec<ContentBlock>,
+}
+
+impl ClaudeResponse {
+    fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                ContentBlock::Other => None,
+            })
+            .collect()
+    }
+}
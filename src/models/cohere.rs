@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, ModelProvider};
+
+pub struct Cohere;
+
+impl ModelProvider for Cohere {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&CohereRequest::new(prompt, system, params)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<CohereResponse>(body).ok()?;
+        if response_body.is_error() {
+            return None;
+        }
+
+        Some(response_body.text)
+    }
+
+    fn additional_fields(&self, params: &GenerationParams) -> Option<serde_json::Value> {
+        let fields = CohereSampling::new(params);
+        if fields.top_k.is_none()
+            && fields.presence_penalty.is_none()
+            && fields.frequency_penalty.is_none()
+        {
+            return None;
+        }
+
+        serde_json::to_value(fields).ok()
+    }
+}
+
+#[derive(Serialize)]
+struct CohereRequest {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preamble: Option<String>,
+    max_tokens: i32,
+    temperature: f32,
+    /// Cohere calls this `p`.
+    #[serde(rename = "p", skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(flatten)]
+    sampling: CohereSampling,
+}
+
+/// Sampling parameters Cohere takes on top of the ones every family has.
+#[derive(Serialize)]
+struct CohereSampling {
+    /// Cohere calls this `k`.
+    #[serde(rename = "k", skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+}
+
+impl CohereSampling {
+    fn new(params: &GenerationParams) -> Self {
+        Self {
+            top_k: params.top_k,
+            presence_penalty: params.presence_penalty,
+            frequency_penalty: params.frequency_penalty,
+        }
+    }
+}
+
+impl CohereRequest {
+    fn new(prompt: String, system: Option<&str>, params: &GenerationParams) -> Self {
+        Self {
+            message: prompt,
+            preamble: system.map(str::to_string),
+            max_tokens: params.max_tokens.unwrap_or(512),
+            temperature: params.temperature.unwrap_or(0.0),
+            top_p: params.top_p,
+            stop_sequences: params.stop_sequences.clone(),
+            sampling: CohereSampling::new(params),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CohereResponse {
+    text: String,
+    finish_reason: String,
+}
+
+impl CohereResponse {
+    /// Cohere reports failures (`ERROR`, `ERROR_TOXIC`, `ERROR_LIMIT`) as a finish reason rather
+    /// than as an error response, in which case `text` is not a usable answer.
+    fn is_error(&self) -> bool {
+        self.finish_reason.starts_with("ERROR")
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, ModelProvider};
+
+pub struct Llama;
+
+impl ModelProvider for Llama {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&LlamaRequest::new(prompt, system, params)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<LlamaResponse>(body).ok()?;
+
+        Some(response_body.generation)
+    }
+}
+
+#[derive(Serialize)]
+struct LlamaRequest {
+    prompt: String,
+    max_gen_len: i32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+}
+
+impl LlamaRequest {
+    fn new(prompt: String, system: Option<&str>, params: &GenerationParams) -> Self {
+        Self {
+            prompt: chat_template(&prompt, system),
+            max_gen_len: params.max_tokens.unwrap_or(512),
+            temperature: params.temperature.unwrap_or(0.0),
+            top_p: params.top_p,
+        }
+    }
+}
+
+/// Llama 3 instruct models expect the prompt wrapped in their chat template, otherwise they just
+/// carry on writing the user's message rather than answering it.
+fn chat_template(prompt: &str, system: Option<&str>) -> String {
+    let system = system
+        .map(|system| format!("<|start_header_id|>system<|end_header_id|>\n\n{system}<|eot_id|>"))
+        .unwrap_or_default();
+
+    format!(
+        "<|begin_of_text|>{system}<|start_header_id|>user<|end_header_id|>\n\n{prompt}<|eot_id|>\
+         <|start_header_id|>assistant<|end_header_id|>\n\n"
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct LlamaResponse {
+    generation: String,
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, with_system_prefix, ModelProvider};
+
+pub struct Mistral;
+
+impl ModelProvider for Mistral {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&MistralRequest::new(
+            with_system_prefix(system, prompt),
+            params,
+        ))
+        .ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<MistralResponse>(body).ok()?;
+        let MistralOutput { text } = response_body.outputs.into_iter().next()?;
+
+        Some(text)
+    }
+
+    fn additional_fields(&self, params: &GenerationParams) -> Option<serde_json::Value> {
+        if params.top_k.is_none() && params.seed.is_none() {
+            return None;
+        }
+
+        serde_json::to_value(MistralSampling::new(params)).ok()
+    }
+
+    fn supports_seed(&self) -> bool {
+        true
+    }
+
+    /// Mistral's instruct template has no system turn, and only Mistral Large takes one through
+    /// the Converse API.
+    fn supports_system_prompt(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Serialize)]
+struct MistralRequest {
+    prompt: String,
+    max_tokens: i32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(flatten)]
+    sampling: MistralSampling,
+}
+
+/// Sampling parameters Mistral takes that the Converse API has no field for.
+#[derive(Serialize)]
+struct MistralSampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
+    #[serde(rename = "random_seed", skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
+}
+
+impl MistralSampling {
+    fn new(params: &GenerationParams) -> Self {
+        Self {
+            top_k: params.top_k,
+            seed: params.seed,
+        }
+    }
+}
+
+impl MistralRequest {
+    fn new(prompt: String, params: &GenerationParams) -> Self {
+        Self {
+            prompt: format!("<s>[INST] {prompt} [/INST]"),
+            max_tokens: params.max_tokens.unwrap_or(512),
+            temperature: params.temperature.unwrap_or(0.0),
+            top_p: params.top_p,
+            stop: params.stop_sequences.clone(),
+            sampling: MistralSampling::new(params),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct MistralResponse {
+    outputs: Vec<MistralOutput>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MistralOutput {
+    text: String,
+}
@@ -0,0 +1,315 @@
+pub mod ai21;
+pub mod claude;
+pub mod cohere;
+pub mod llama;
+pub mod mistral;
+pub mod nova;
+pub mod params;
+pub mod registry;
+pub mod titan;
+
+use std::{borrow::Cow, sync::Arc};
+
+use serde::Serialize;
+
+use params::GenerationParams;
+use titan::TitanVariant;
+
+pub const DEFAULT_MODEL_ID: &str = "amazon.titan-text-lite-v1:0:4k";
+
+/// Cross-region inference profile ids are a model id prefixed with a geography, eg. `us.`
+const INFERENCE_PROFILE_GEOGRAPHIES: &[&str] = &["us", "eu", "apac", "us-gov"];
+
+/// The model id without any inference profile geography, for working out what model it is.
+pub fn base_model_id(model_id: &str) -> &str {
+    INFERENCE_PROFILE_GEOGRAPHIES
+        .iter()
+        .find_map(|geography| {
+            model_id
+                .strip_prefix(geography)
+                .and_then(|rest| rest.strip_prefix('.'))
+        })
+        .unwrap_or(model_id)
+}
+
+/// The inference profile geography requests from `region` are routed within.
+pub fn inference_profile_geography(region: &str) -> Option<&'static str> {
+    if region.starts_with("us-gov-") {
+        Some("us-gov")
+    } else if region.starts_with("us-") {
+        Some("us")
+    } else if region.starts_with("eu-") {
+        Some("eu")
+    } else if region.starts_with("ap-") {
+        Some("apac")
+    } else {
+        None
+    }
+}
+
+/// The region of a resource ARN, eg. `eu-west-1` for `arn:aws:bedrock:eu-west-1:...`.
+fn arn_region(id: &str) -> Option<&str> {
+    id.strip_prefix("arn:")?.split(':').nth(2)
+}
+
+/// A rough token count for `text`, assuming ~4 characters per token. Bedrock doesn't expose a
+/// tokenizer, so this is only good enough for staying clear of context window limits.
+pub fn estimate_tokens(text: &str) -> i32 {
+    text.len().div_ceil(4).try_into().unwrap_or(i32::MAX)
+}
+
+/// For models with no separate system prompt, which get it at the start of the prompt instead.
+pub fn with_system_prefix(system: Option<&str>, prompt: String) -> String {
+    match system {
+        Some(system) => format!("{system}\n\n{prompt}"),
+        None => prompt,
+    }
+}
+
+/// Everything model-specific about talking to Bedrock: each family has its own JSON schema for
+/// requests and responses, and its own limits.
+pub trait ModelProvider: Send + Sync {
+    /// Returns `None` if the prompt can't be turned into a valid request for this model.
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>>;
+
+    fn parse_response(&self, body: &[u8]) -> Option<String>;
+
+    fn supports_logprobs(&self) -> bool {
+        false
+    }
+
+    /// The log probability of each generated token, for models that return them.
+    fn parse_logprobs(&self, _body: &[u8]) -> Option<Vec<TokenLogprob>> {
+        None
+    }
+
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    /// Whether the model can be used through the Converse API, which saves us building the
+    /// request body ourselves. Models that can't fall back to `invoke_model` with our own schema.
+    fn supports_converse(&self) -> bool {
+        true
+    }
+
+    /// Whether the Converse API takes a system prompt for this model. For those that don't, it's
+    /// put in front of the prompt instead.
+    fn supports_system_prompt(&self) -> bool {
+        true
+    }
+
+    /// Parameters the Converse API has no field for (eg. `top_k`), in the shape this family
+    /// expects them. Anything the family doesn't support is left out rather than failing.
+    fn additional_fields(&self, _params: &GenerationParams) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Whether the model can be given a seed to generate deterministically.
+    fn supports_seed(&self) -> bool {
+        false
+    }
+
+    /// Whether images can be attached to the prompt.
+    fn supports_images(&self) -> bool {
+        false
+    }
+
+    /// How many tokens the model can respond with, given what was `requested`. Returns `None` if
+    /// the prompt leaves no room for any output.
+    fn max_tokens(&self, _prompt: &str, requested: Option<i32>) -> Option<i32> {
+        Some(requested.unwrap_or(512))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+}
+
+/// A model id along with the provider that knows how to talk to it.
+#[derive(Clone)]
+pub struct Model {
+    /// What requests ask for the model by.
+    pub id: String,
+    pub family: ModelFamily,
+    pub provider: Arc<dyn ModelProvider>,
+    /// The deployment's defaults for this model's family, used for anything a request doesn't set.
+    pub defaults: GenerationParams,
+    /// Purchased throughput for this model, which we invoke instead of the on-demand model.
+    pub provisioned_arn: Option<String>,
+    /// Invoke the model through a cross-region inference profile where one is available.
+    pub inference_profile: bool,
+}
+
+impl Model {
+    pub fn new(id: String) -> Option<Self> {
+        let family = ModelFamily::from_model_id(&id)?;
+
+        Some(Self::with_family(id, family))
+    }
+
+    /// For ids we can't work out the family from, like custom model ARNs.
+    pub fn with_family(id: String, family: ModelFamily) -> Self {
+        Self {
+            id,
+            family,
+            provider: family.provider(),
+            defaults: GenerationParams::default(),
+            provisioned_arn: None,
+            inference_profile: false,
+        }
+    }
+
+    /// The id to send to Bedrock in `region`, which isn't always the one the model was requested
+    /// with. Returns `None` if the model is an ARN belonging to another region.
+    pub fn model_id(&self, region: &str) -> Option<Cow<'_, str>> {
+        let id = self.provisioned_arn.as_deref().unwrap_or(&self.id);
+        if arn_region(id).is_some_and(|arn_region| arn_region != region) {
+            return None;
+        }
+
+        match inference_profile_geography(region) {
+            Some(geography) if self.inference_profile => {
+                Some(Cow::Owned(format!("{geography}.{id}")))
+            }
+            _ => Some(Cow::Borrowed(id)),
+        }
+    }
+
+    /// Routes the model through its inference profile, if it has one and isn't already an
+    /// inference profile or using provisioned throughput.
+    pub fn prefer_inference_profile(&mut self) {
+        let has_profile =
+            registry::ModelInfo::lookup(&self.id).is_some_and(|info| info.cross_region);
+
+        self.inference_profile =
+            has_profile && self.provisioned_arn.is_none() && base_model_id(&self.id) == self.id;
+    }
+}
+
+/// The families of Bedrock models we have a [`ModelProvider`] for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelFamily {
+    Titan(TitanVariant),
+    Claude,
+    Llama,
+    Mistral,
+    Cohere,
+    Jamba,
+    Jurassic,
+    Nova,
+}
+
+impl ModelFamily {
+    pub fn from_model_id(model_id: &str) -> Option<Self> {
+        let model_id = base_model_id(model_id);
+
+        if let Some(variant) = TitanVariant::from_model_id(model_id) {
+            Some(Self::Titan(variant))
+        } else if model_id.starts_with("anthropic.claude-3") {
+            Some(Self::Claude)
+        } else if model_id.starts_with("meta.llama3") {
+            Some(Self::Llama)
+        } else if model_id.starts_with("mistral.") {
+            Some(Self::Mistral)
+        } else if model_id.starts_with("cohere.command-r") {
+            Some(Self::Cohere)
+        } else if model_id.starts_with("ai21.jamba") {
+            Some(Self::Jamba)
+        } else if model_id.starts_with("ai21.j2") {
+            Some(Self::Jurassic)
+        } else if model_id.starts_with("amazon.nova") {
+            Some(Self::Nova)
+        } else {
+            None
+        }
+    }
+
+    /// The names used to pick a family in config, eg. `titan-express` or `claude`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "titan-lite" => Some(Self::Titan(TitanVariant::Lite)),
+            "titan-express" => Some(Self::Titan(TitanVariant::Express)),
+            "titan-premier" => Some(Self::Titan(TitanVariant::Premier)),
+            "claude" => Some(Self::Claude),
+            "llama" => Some(Self::Llama),
+            "mistral" => Some(Self::Mistral),
+            "cohere" => Some(Self::Cohere),
+            "jamba" => Some(Self::Jamba),
+            "jurassic" => Some(Self::Jurassic),
+            "nova" => Some(Self::Nova),
+            _ => None,
+        }
+    }
+
+    pub fn provider(self) -> Arc<dyn ModelProvider> {
+        match self {
+            Self::Titan(variant) => Arc::new(titan::Titan(variant)),
+            Self::Claude => Arc::new(claude::Claude),
+            Self::Llama => Arc::new(llama::Llama),
+            Self::Mistral => Arc::new(mistral::Mistral),
+            Self::Cohere => Arc::new(cohere::Cohere),
+            Self::Jamba => Arc::new(ai21::Jamba),
+            Self::Jurassic => Arc::new(ai21::Jurassic),
+            Self::Nova => Arc::new(nova::Nova),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HAIKU: &str = "anthropic.claude-3-haiku-20240307-v1:0";
+
+    #[test]
+    fn base_model_ids_are_without_the_geography() {
+        assert_eq!(base_model_id(&format!("us.{HAIKU}")), HAIKU);
+        assert_eq!(base_model_id(&format!("us-gov.{HAIKU}")), HAIKU);
+        assert_eq!(base_model_id(&format!("apac.{HAIKU}")), HAIKU);
+        assert_eq!(base_model_id(HAIKU), HAIKU);
+        assert_eq!(base_model_id("user.model"), "user.model");
+    }
+
+    #[test]
+    fn inference_profiles_are_for_the_regions_geography() {
+        let mut model = Model::new(HAIKU.to_string()).unwrap();
+        model.prefer_inference_profile();
+
+        assert_eq!(
+            model.model_id("eu-west-1").as_deref(),
+            Some(format!("eu.{HAIKU}").as_str())
+        );
+        assert_eq!(
+            model.model_id("us-gov-west-1").as_deref(),
+            Some(format!("us-gov.{HAIKU}").as_str())
+        );
+        // there's no profile for the region
+        assert_eq!(model.model_id("ca-central-1").as_deref(), Some(HAIKU));
+    }
+
+    #[test]
+    fn models_are_sent_as_they_are_without_a_profile() {
+        let model = Model::new(HAIKU.to_string()).unwrap();
+
+        assert_eq!(model.model_id("eu-west-1").as_deref(), Some(HAIKU));
+    }
+
+    #[test]
+    fn provisioned_models_are_only_in_their_own_region() {
+        let arn = "arn:aws:bedrock:us-east-1:123456789012:provisioned-model/abc";
+        let mut model = Model::new(HAIKU.to_string()).unwrap();
+        model.provisioned_arn = Some(arn.to_string());
+        model.prefer_inference_profile();
+
+        assert_eq!(model.model_id("us-east-1").as_deref(), Some(arn));
+        assert_eq!(model.model_id("us-west-2"), None);
+    }
+}
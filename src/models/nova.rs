@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, ModelProvider};
+
+pub struct Nova;
+
+impl ModelProvider for Nova {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        serde_json::to_vec(&NovaRequest::new(prompt, system, params)).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<NovaResponse>(body).ok()?;
+
+        Some(response_body.text())
+    }
+
+    fn additional_fields(&self, params: &GenerationParams) -> Option<serde_json::Value> {
+        if params.top_k.is_none() && params.seed.is_none() {
+            return None;
+        }
+
+        Some(serde_json::json!({
+            "inferenceConfig": NovaSampling {
+                top_k: params.top_k,
+                seed: params.seed,
+            }
+        }))
+    }
+
+    fn supports_seed(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NovaRequest {
+    schema_version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<Vec<NovaContent>>,
+    messages: Vec<NovaMessage>,
+    inference_config: InferenceConfig,
+}
+
+impl NovaRequest {
+    fn new(prompt: String, system: Option<&str>, params: &GenerationParams) -> Self {
+        Self {
+            schema_version: "messages-v1",
+            system: system.map(|system| {
+                vec![NovaContent {
+                    text: system.to_string(),
+                }]
+            }),
+            messages: vec![NovaMessage {
+                role: "user".to_string(),
+                content: vec![NovaContent { text: prompt }],
+            }],
+            inference_config: InferenceConfig {
+                max_tokens: params.max_tokens.unwrap_or(512),
+                temperature: params.temperature.unwrap_or(0.0),
+                top_p: params.top_p,
+                stop_sequences: params.stop_sequences.clone(),
+                sampling: NovaSampling {
+                    top_k: params.top_k,
+                    seed: params.seed,
+                },
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct NovaMessage {
+    role: String,
+    content: Vec<NovaContent>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct NovaContent {
+    text: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InferenceConfig {
+    max_tokens: i32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(flatten)]
+    sampling: NovaSampling,
+}
+
+/// The parts of `inferenceConfig` the Converse API has no field for.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NovaSampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NovaResponse {
+    output: NovaOutput,
+}
+
+#[derive(Deserialize, Debug)]
+struct NovaOutput {
+    message: NovaMessage,
+}
+
+impl NovaResponse {
+    fn text(&self) -> String {
+        self.output
+            .message
+            .content
+            .iter()
+            .map(|content| content.text.as_str())
+            .collect()
+    }
+}
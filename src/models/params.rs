@@ -0,0 +1,288 @@
+//! Generation parameters a request can override. Anything left out falls back to the model
+//! family's own default.
+
+use std::hash::{BuildHasher, RandomState};
+
+use serde::{Deserialize, Serialize};
+
+use super::{registry::ModelInfo, Model};
+use crate::error::Violation;
+
+/// The fewest stop sequences any family accepts, so a request is valid whichever model it uses.
+const MAX_STOP_SEQUENCES: usize = 4;
+const MAX_STOP_SEQUENCE_LENGTH: usize = 100;
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct GenerationParams {
+    /// Between 0 and 1, higher is more random.
+    pub temperature: Option<f32>,
+    /// Between 0 and 1, only sample from the most likely tokens that add up to this probability.
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<i32>,
+    /// Generation stops at the first of these, which isn't included in the output.
+    pub stop_sequences: Option<Vec<String>>,
+    /// Only sample from this many of the most likely tokens.
+    pub top_k: Option<i32>,
+    /// Penalises tokens that have appeared at all, to encourage new topics.
+    pub presence_penalty: Option<f32>,
+    /// Penalises tokens by how often they have appeared, to discourage repetition.
+    pub frequency_penalty: Option<f32>,
+    /// The same seed and parameters give the same output, for models that support one.
+    pub seed: Option<u32>,
+}
+
+impl GenerationParams {
+    /// Everything out of range for `model`, or that it doesn't support.
+    pub fn violations(&self, model: &Model) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let info = ModelInfo::lookup(&model.id);
+
+        for (field, value) in [("temperature", self.temperature), ("top_p", self.top_p)] {
+            let Some(value) = value else {
+                continue;
+            };
+
+            if !(0.0..=1.0).contains(&value) {
+                violations.push(Violation::new(field, "must be between 0 and 1"));
+            }
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens < 1 {
+                violations.push(Violation::new("max_tokens", "must be at least 1"));
+            }
+            if let Some(info) = info.filter(|info| max_tokens > info.max_output_tokens) {
+                violations.push(Violation::new(
+                    "max_tokens",
+                    format!(
+                        "{} can't respond with more than {} tokens",
+                        model.id, info.max_output_tokens
+                    ),
+                ));
+            }
+        }
+        if self.top_k.is_some_and(|top_k| top_k < 1) {
+            violations.push(Violation::new("top_k", "must be at least 1"));
+        }
+        for (field, value) in [
+            ("presence_penalty", self.presence_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+        ] {
+            if value.is_some_and(|value| value < 0.0) {
+                violations.push(Violation::new(field, "must not be negative"));
+            }
+        }
+        if self
+            .stop_sequences
+            .as_ref()
+            .is_some_and(|stop_sequences| !valid_stop_sequences(stop_sequences))
+        {
+            violations.push(Violation::new(
+                "stop_sequences",
+                format!(
+                    "can have at most {MAX_STOP_SEQUENCES} sequences, each of 1 to \
+                     {MAX_STOP_SEQUENCE_LENGTH} characters"
+                ),
+            ));
+        }
+
+        if self.seed.is_some() && !model.provider.supports_seed() {
+            violations.push(Violation::new(
+                "seed",
+                format!("{} can't generate deterministically", model.id),
+            ));
+        }
+
+        // top_k and the penalties are only sent to families that take them, so they're never
+        // a violation
+        let set = [
+            ("temperature", self.temperature.is_some()),
+            ("top_p", self.top_p.is_some()),
+            ("stop_sequences", self.stop_sequences.is_some()),
+        ];
+        for (field, _) in set.into_iter().filter(|(_, set)| *set) {
+            if !supports(model, field) {
+                violations.push(Violation::new(
+                    field,
+                    format!("{} doesn't support this parameter", model.id),
+                ));
+            }
+        }
+
+        violations
+    }
+
+    /// Whether these are in range for `model`, and only include parameters it supports.
+    pub fn is_valid_for(&self, model: &Model) -> bool {
+        self.violations(model).is_empty()
+    }
+
+    /// Fills in anything not set here from `fallback`, eg. a preset or the family's defaults.
+    pub fn or(self, fallback: &GenerationParams) -> Self {
+        Self {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            stop_sequences: self
+                .stop_sequences
+                .or_else(|| fallback.stop_sequences.clone()),
+            top_k: self.top_k.or(fallback.top_k),
+            presence_penalty: self.presence_penalty.or(fallback.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            seed: self.seed.or(fallback.seed),
+        }
+    }
+
+    /// Picks a seed for models that support one if the request didn't, so any output can be
+    /// reproduced with the seed we send back.
+    pub fn with_effective_seed(mut self, model: &Model) -> Self {
+        if self.seed.is_none() && model.provider.supports_seed() {
+            // RandomState is randomly keyed, which is all the randomness we need
+            self.seed = Some(RandomState::new().hash_one(0) as u32);
+        }
+
+        self
+    }
+
+    /// Uses the deployment's stop sequences if the request didn't give any, for models that
+    /// support them.
+    pub fn with_default_stop_sequences(mut self, model: &Model, defaults: &[String]) -> Self {
+        if self.stop_sequences.is_none()
+            && !defaults.is_empty()
+            && supports(model, "stop_sequences")
+        {
+            self.stop_sequences = Some(defaults.to_vec());
+        }
+
+        self
+    }
+}
+
+/// Presets every deployment has, unless it configures its own with the same name.
+pub fn default_presets() -> Vec<(String, GenerationParams)> {
+    let preset = |temperature, top_p, max_tokens| GenerationParams {
+        temperature: Some(temperature),
+        top_p: Some(top_p),
+        max_tokens: Some(max_tokens),
+        ..GenerationParams::default()
+    };
+
+    vec![
+        ("creative".to_string(), preset(0.9, 0.95, 1024)),
+        ("balanced".to_string(), preset(0.5, 0.9, 512)),
+        ("precise".to_string(), preset(0.0, 0.5, 256)),
+    ]
+}
+
+pub fn valid_stop_sequences(stop_sequences: &[String]) -> bool {
+    stop_sequences.len() <= MAX_STOP_SEQUENCES
+        && stop_sequences.iter().all(|stop_sequence| {
+            !stop_sequence.is_empty() && stop_sequence.len() <= MAX_STOP_SEQUENCE_LENGTH
+        })
+}
+
+/// We don't know what custom models support, so Bedrock will have to be the judge.
+fn supports(model: &Model, parameter: &str) -> bool {
+    ModelInfo::lookup(&model.id).is_none_or(|info| info.parameters.contains(&parameter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(params: GenerationParams, model_id: &str) -> Vec<(&'static str, String)> {
+        let model = Model::new(model_id.to_string()).unwrap();
+
+        params
+            .violations(&model)
+            .into_iter()
+            .map(|violation| (violation.field, violation.message))
+            .collect()
+    }
+
+    const HAIKU: &str = "anthropic.claude-3-haiku-20240307-v1:0";
+    const LLAMA: &str = "meta.llama3-8b-instruct-v1:0";
+
+    #[test]
+    fn params_in_range_are_fine() {
+        let params = GenerationParams {
+            temperature: Some(0.5),
+            top_p: Some(1.0),
+            max_tokens: Some(4_096),
+            top_k: Some(50),
+            stop_sequences: Some(vec!["\n\nHuman:".to_string()]),
+            ..Default::default()
+        };
+
+        assert!(violations(params, HAIKU).is_empty());
+    }
+
+    #[test]
+    fn params_out_of_range_are_violations() {
+        let params = GenerationParams {
+            temperature: Some(1.5),
+            top_p: Some(-0.1),
+            top_k: Some(0),
+            presence_penalty: Some(-1.0),
+            ..Default::default()
+        };
+
+        let fields: Vec<&str> = violations(params, HAIKU)
+            .into_iter()
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(
+            fields,
+            ["temperature", "top_p", "top_k", "presence_penalty"]
+        );
+    }
+
+    #[test]
+    fn max_tokens_is_limited_by_the_model() {
+        let params = GenerationParams {
+            max_tokens: Some(10_000),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            violations(params, HAIKU),
+            [(
+                "max_tokens",
+                format!("{HAIKU} can't respond with more than 4096 tokens")
+            )]
+        );
+    }
+
+    #[test]
+    fn unsupported_params_are_violations() {
+        let params = GenerationParams {
+            stop_sequences: Some(vec!["end".to_string()]),
+            seed: Some(42),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            violations(params, LLAMA),
+            [
+                ("seed", format!("{LLAMA} can't generate deterministically")),
+                (
+                    "stop_sequences",
+                    format!("{LLAMA} doesn't support this parameter")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_stop_sequences_are_a_violation() {
+        let params = GenerationParams {
+            stop_sequences: Some(vec!["end".to_string(); MAX_STOP_SEQUENCES + 1]),
+            ..Default::default()
+        };
+
+        let fields: Vec<&str> = violations(params, HAIKU)
+            .into_iter()
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(fields, ["stop_sequences"]);
+    }
+}
@@ -0,0 +1,317 @@
+//! What we know about each model we support, for clients that want to check their requests
+//! before sending them. Bedrock doesn't expose most of this through its API.
+
+use serde::Serialize;
+
+use super::{base_model_id, titan::TitanVariant};
+
+#[derive(Serialize, Debug)]
+pub struct ModelInfo {
+    /// Matched against the start of a model id, so it covers every version/context suffix.
+    #[serde(skip)]
+    id_prefix: &'static str,
+    pub context_window: i32,
+    pub max_output_tokens: i32,
+    pub parameters: &'static [&'static str],
+    pub streaming: bool,
+    /// Whether the model can be invoked through a cross-region inference profile.
+    pub cross_region: bool,
+    pub pricing: Pricing,
+}
+
+/// On-demand pricing in USD per 1,000 tokens.
+#[derive(Serialize, Debug)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelInfo {
+    pub fn lookup(model_id: &str) -> Option<&'static Self> {
+        let model_id = base_model_id(model_id);
+
+        REGISTRY
+            .iter()
+            .find(|info| model_id.starts_with(info.id_prefix))
+    }
+}
+
+const TITAN_PARAMETERS: &[&str] = &["temperature", "top_p", "max_tokens", "stop_sequences"];
+const CLAUDE_PARAMETERS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "max_tokens",
+    "stop_sequences",
+];
+const LLAMA_PARAMETERS: &[&str] = &["temperature", "top_p", "max_tokens"];
+const MISTRAL_PARAMETERS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "max_tokens",
+    "stop_sequences",
+    "seed",
+];
+const COHERE_PARAMETERS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "max_tokens",
+    "stop_sequences",
+    "presence_penalty",
+    "frequency_penalty",
+];
+const AI21_PARAMETERS: &[&str] = &["temperature", "top_p", "max_tokens", "stop_sequences"];
+const NOVA_PARAMETERS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "max_tokens",
+    "stop_sequences",
+    "seed",
+];
+
+const fn titan(id_prefix: &'static str, variant: TitanVariant, pricing: Pricing) -> ModelInfo {
+    ModelInfo {
+        id_prefix,
+        context_window: variant.context_window(),
+        max_output_tokens: variant.max_output_tokens(),
+        parameters: TITAN_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing,
+    }
+}
+
+static REGISTRY: &[ModelInfo] = &[
+    titan(
+        "amazon.titan-text-lite",
+        TitanVariant::Lite,
+        Pricing {
+            input: 0.00015,
+            output: 0.0002,
+        },
+    ),
+    titan(
+        "amazon.titan-text-express",
+        TitanVariant::Express,
+        Pricing {
+            input: 0.0002,
+            output: 0.0006,
+        },
+    ),
+    titan(
+        "amazon.titan-text-premier",
+        TitanVariant::Premier,
+        Pricing {
+            input: 0.0005,
+            output: 0.0015,
+        },
+    ),
+    ModelInfo {
+        id_prefix: "anthropic.claude-3-haiku",
+        context_window: 200_000,
+        max_output_tokens: 4_096,
+        parameters: CLAUDE_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.00025,
+            output: 0.00125,
+        },
+    },
+    ModelInfo {
+        id_prefix: "anthropic.claude-3-sonnet",
+        context_window: 200_000,
+        max_output_tokens: 4_096,
+        parameters: CLAUDE_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.003,
+            output: 0.015,
+        },
+    },
+    ModelInfo {
+        id_prefix: "anthropic.claude-3-5-sonnet",
+        context_window: 200_000,
+        max_output_tokens: 8_192,
+        parameters: CLAUDE_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.003,
+            output: 0.015,
+        },
+    },
+    ModelInfo {
+        id_prefix: "anthropic.claude-3-opus",
+        context_window: 200_000,
+        max_output_tokens: 4_096,
+        parameters: CLAUDE_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.015,
+            output: 0.075,
+        },
+    },
+    ModelInfo {
+        id_prefix: "meta.llama3-8b-instruct",
+        context_window: 8_192,
+        max_output_tokens: 2_048,
+        parameters: LLAMA_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.0003,
+            output: 0.0006,
+        },
+    },
+    ModelInfo {
+        id_prefix: "meta.llama3-70b-instruct",
+        context_window: 8_192,
+        max_output_tokens: 2_048,
+        parameters: LLAMA_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.00265,
+            output: 0.0035,
+        },
+    },
+    ModelInfo {
+        id_prefix: "mistral.mistral-7b-instruct",
+        context_window: 32_000,
+        max_output_tokens: 8_192,
+        parameters: MISTRAL_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.00015,
+            output: 0.0002,
+        },
+    },
+    ModelInfo {
+        id_prefix: "mistral.mixtral-8x7b-instruct",
+        context_window: 32_000,
+        max_output_tokens: 4_096,
+        parameters: MISTRAL_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.00045,
+            output: 0.0007,
+        },
+    },
+    ModelInfo {
+        id_prefix: "mistral.mistral-large",
+        context_window: 32_000,
+        max_output_tokens: 8_192,
+        parameters: MISTRAL_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.004,
+            output: 0.012,
+        },
+    },
+    ModelInfo {
+        id_prefix: "cohere.command-r-plus",
+        context_window: 128_000,
+        max_output_tokens: 4_000,
+        parameters: COHERE_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.003,
+            output: 0.015,
+        },
+    },
+    // after Command R+, as this prefix matches both
+    ModelInfo {
+        id_prefix: "cohere.command-r",
+        context_window: 128_000,
+        max_output_tokens: 4_000,
+        parameters: COHERE_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.0005,
+            output: 0.0015,
+        },
+    },
+    ModelInfo {
+        id_prefix: "ai21.jamba-instruct",
+        context_window: 256_000,
+        max_output_tokens: 4_096,
+        parameters: AI21_PARAMETERS,
+        streaming: true,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.0005,
+            output: 0.0007,
+        },
+    },
+    ModelInfo {
+        id_prefix: "ai21.j2-mid",
+        context_window: 8_191,
+        max_output_tokens: 8_191,
+        parameters: AI21_PARAMETERS,
+        streaming: false,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.0125,
+            output: 0.0125,
+        },
+    },
+    ModelInfo {
+        id_prefix: "ai21.j2-ultra",
+        context_window: 8_191,
+        max_output_tokens: 8_191,
+        parameters: AI21_PARAMETERS,
+        streaming: false,
+        cross_region: false,
+        pricing: Pricing {
+            input: 0.0188,
+            output: 0.0188,
+        },
+    },
+    ModelInfo {
+        id_prefix: "amazon.nova-micro",
+        context_window: 128_000,
+        max_output_tokens: 5_000,
+        parameters: NOVA_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.000035,
+            output: 0.00014,
+        },
+    },
+    ModelInfo {
+        id_prefix: "amazon.nova-lite",
+        context_window: 300_000,
+        max_output_tokens: 5_000,
+        parameters: NOVA_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.00006,
+            output: 0.00024,
+        },
+    },
+    ModelInfo {
+        id_prefix: "amazon.nova-pro",
+        context_window: 300_000,
+        max_output_tokens: 5_000,
+        parameters: NOVA_PARAMETERS,
+        streaming: true,
+        cross_region: true,
+        pricing: Pricing {
+            input: 0.0008,
+            output: 0.0032,
+        },
+    },
+];
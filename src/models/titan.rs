@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+
+use super::{params::GenerationParams, with_system_prefix, ModelProvider};
+
+pub struct Titan(pub TitanVariant);
+
+impl ModelProvider for Titan {
+    fn build_request_body(
+        &self,
+        prompt: String,
+        system: Option<&str>,
+        params: &GenerationParams,
+    ) -> Option<Vec<u8>> {
+        let prompt = with_system_prefix(system, prompt);
+
+        serde_json::to_vec(&TitanRequest::new(prompt, self.0, params)?).ok()
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Option<String> {
+        let response_body = serde_json::from_slice::<TitanResponse>(body).ok()?;
+        let TitanTextResult { output_text, .. } = response_body.results.first()?;
+
+        Some(output_text.to_owned())
+    }
+
+    fn supports_system_prompt(&self) -> bool {
+        false
+    }
+
+    fn max_tokens(&self, prompt: &str, requested: Option<i32>) -> Option<i32> {
+        self.0.max_token_count(prompt, requested)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanRequest {
+    input_text: String,
+    text_generation_config: TextGenConfig,
+}
+
+impl TitanRequest {
+    /// Returns `None` if the prompt leaves no room in the variant's context window for any output.
+    fn new(prompt: String, variant: TitanVariant, params: &GenerationParams) -> Option<Self> {
+        let max_token_count = variant.max_token_count(&prompt, params.max_tokens)?;
+
+        Some(Self {
+            input_text: prompt,
+            text_generation_config: TextGenConfig {
+                temperature: params.temperature.unwrap_or(0.0),
+                top_p: params.top_p,
+                max_token_count,
+                stop_sequences: params.stop_sequences.clone(),
+            },
+        })
+    }
+}
+
+/// The Titan Text models all share a schema, but have different limits on how much they can take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TitanVariant {
+    Lite,
+    Express,
+    Premier,
+}
+
+impl TitanVariant {
+    pub fn from_model_id(model_id: &str) -> Option<Self> {
+        if model_id.starts_with("amazon.titan-text-lite") {
+            Some(Self::Lite)
+        } else if model_id.starts_with("amazon.titan-text-express") {
+            Some(Self::Express)
+        } else if model_id.starts_with("amazon.titan-text-premier") {
+            Some(Self::Premier)
+        } else {
+            None
+        }
+    }
+
+    /// Total tokens (prompt + output) the model can handle.
+    pub const fn context_window(self) -> i32 {
+        match self {
+            Self::Lite => 4_096,
+            Self::Express => 8_192,
+            Self::Premier => 32_000,
+        }
+    }
+
+    /// The largest `maxTokenCount` Bedrock accepts for this variant.
+    pub const fn max_output_tokens(self) -> i32 {
+        match self {
+            Self::Lite => 4_096,
+            Self::Express => 8_192,
+            Self::Premier => 3_072,
+        }
+    }
+
+    pub fn default_max_token_count(self) -> i32 {
+        match self {
+            Self::Lite => 100,
+            Self::Express => 512,
+            Self::Premier => 1_024,
+        }
+    }
+
+    /// Bedrock rejects requests where the prompt plus `maxTokenCount` is larger than the context
+    /// window, so shrink the output budget to fit rather than sending a request we know will fail.
+    fn max_token_count(self, prompt: &str, requested: Option<i32>) -> Option<i32> {
+        let remaining = self.context_window() - super::estimate_tokens(prompt);
+        let max_token_count = requested
+            .unwrap_or(self.default_max_token_count())
+            .min(self.max_output_tokens())
+            .min(remaining);
+
+        (max_token_count > 0).then_some(max_token_count)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TextGenConfig {
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    max_token_count: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TitanResponse {
+    results: Vec<TitanTextResult>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TitanTextResult {
+    output_text: String,
+}
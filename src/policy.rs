@@ -0,0 +1,52 @@
+//! Makes sure a deployment can only be used to invoke models its owner meant to expose.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+use crate::AppState;
+
+/// Same as the limit on `Json` bodies, which is what handlers would enforce anyway.
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct RequestedModel {
+    model: Option<String>,
+}
+
+/// Rejects JSON requests for models that are denied or aren't allowed with `403 Forbidden`,
+/// before they get anywhere near Bedrock. Multipart bodies are left to the handlers, which only
+/// ever look up models through the same allowlists.
+pub async fn enforce(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !is_json {
+        return Ok(next.run(req).await);
+    }
+
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY_SIZE).await else {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    };
+
+    // anything that doesn't parse is left for the handler to reject
+    if let Ok(RequestedModel { model: Some(model) }) = serde_json::from_slice(&body) {
+        if !state.permits(&model) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
@@ -0,0 +1,136 @@
+//! Bedrock runtime clients for each configured region, so throttling or an outage in one region
+//! doesn't take the whole service down with it.
+
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use aws_sdk_bedrockruntime::{error::SdkError, Client};
+use axum::http::StatusCode;
+
+use crate::models::Model;
+
+pub struct RegionalClient {
+    pub region: String,
+    pub client: Client,
+}
+
+pub struct ClientPool {
+    clients: Vec<RegionalClient>,
+    /// Spread requests across regions rather than always starting with the first.
+    round_robin: bool,
+    next: AtomicUsize,
+}
+
+impl ClientPool {
+    pub fn new(clients: Vec<RegionalClient>, round_robin: bool) -> Self {
+        assert!(
+            !clients.is_empty(),
+            "at least one region must be configured"
+        );
+
+        Self {
+            clients,
+            round_robin,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The order to try regions in for a single request.
+    fn rotation(&self) -> impl Iterator<Item = &RegionalClient> {
+        let start = if self.round_robin {
+            self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len()
+        } else {
+            0
+        };
+
+        self.clients
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.clients.len())
+    }
+
+    /// Sends a request for `model` with `op`, moving on to the next region if it is throttled or
+    /// fails on Bedrock's side. Regions the model can't be invoked in are skipped.
+    pub async fn send<T, E, F, Fut>(&self, model: &Model, op: F) -> Result<T, SdkError<E>>
+    where
+        F: Fn(&Client, String) -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E>>>,
+    {
+        self.send_with(&model.id, |region| model.model_id(region), op)
+            .await
+    }
+
+    /// Like [`ClientPool::send`], for models with the same id in every region (eg. embeddings).
+    pub async fn send_to<T, E, F, Fut>(&self, model_id: &str, op: F) -> Result<T, SdkError<E>>
+    where
+        F: Fn(&Client, String) -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E>>>,
+    {
+        self.send_with(model_id, |_| Some(model_id.into()), op)
+            .await
+    }
+
+    async fn send_with<'a, T, E, F, Fut>(
+        &self,
+        name: &str,
+        model_id: impl Fn(&str) -> Option<Cow<'a, str>>,
+        op: F,
+    ) -> Result<T, SdkError<E>>
+    where
+        F: Fn(&Client, String) -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E>>>,
+    {
+        let mut last_error = None;
+
+        for RegionalClient { region, client } in self.rotation() {
+            let Some(model_id) = model_id(region) else {
+                continue;
+            };
+
+            match op(client, model_id.into_owned()).await {
+                Err(err) if is_retryable(&err) => {
+                    println!("Request to {region} failed, trying the next region");
+                    last_error = Some(err);
+                }
+                res => return res,
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            SdkError::construction_failure(format!(
+                "{name} can't be invoked in any configured region"
+            ))
+        }))
+    }
+}
+
+/// What to tell the caller about a request Bedrock failed: its own status when it didn't like
+/// the request (eg. a validation error), 503 when every region was throttled or down or none of
+/// them serve the model, otherwise a 502.
+pub fn error_status<E>(err: &SdkError<E>) -> StatusCode {
+    if is_retryable(err) || matches!(err, SdkError::ConstructionFailure(_)) {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    err.raw_response()
+        .and_then(|res| StatusCode::from_u16(res.status().as_u16()).ok())
+        .filter(StatusCode::is_client_error)
+        .unwrap_or(StatusCode::BAD_GATEWAY)
+}
+
+/// Throttling, server errors and failing to reach the region at all are worth another region's
+/// time. Anything else (eg. a bad request) would fail the same way everywhere.
+fn is_retryable<E>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        _ => err.raw_response().is_some_and(|res| {
+            let status = res.status().as_u16();
+
+            status == 429 || status >= 500
+        }),
+    }
+}
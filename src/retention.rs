@@ -0,0 +1,33 @@
+//! Deleting conversations that have gone past their retention period, so nothing is kept for
+//! longer than it has to be.
+
+use std::time::Duration;
+
+use crate::AppState;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Runs for as long as the service does. A conversation's own retention period takes priority
+/// over the deployment's, and its messages, sessions and feedback go along with it.
+pub async fn sweep(state: AppState) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let swept = sqlx::query(
+            "DELETE FROM conversations \
+             WHERE updated_at < now() - make_interval(hours => COALESCE(retention_hours, $1))",
+        )
+        .bind(state.chat.retention_hours)
+        .execute(&state.db)
+        .await;
+        match swept {
+            Ok(swept) if swept.rows_affected() > 0 => {
+                println!("Deleted {} expired conversations", swept.rows_affected());
+            }
+            Ok(_) => {}
+            Err(err) => println!("Couldn't delete expired conversations: {err}"),
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Finding the chunks of ingested documents that are about a query, by how close their
+//! embeddings are to the query's.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{types::Json as JsonColumn, QueryBuilder};
+use uuid::Uuid;
+
+use crate::{
+    chat::ChatMessage,
+    embeddings::{self, EmbeddingOptions, EmbeddingType, Vector},
+    error::{ApiError, Violation},
+    vectors::{self, pgvector, Filter, TENANT_FIELD},
+    AppState,
+};
+
+pub mod multi_query;
+pub mod rerank;
+pub mod rewrite;
+
+const DEFAULT_TOP_K: usize = 5;
+const MAX_TOP_K: usize = 100;
+/// How many more chunks than `top_k` are retrieved for the rerank model to choose from.
+const RERANK_CANDIDATES: usize = 4;
+/// Reciprocal rank fusion's `k`, which stops the top few results of either search from
+/// counting for much more than the ones just after them. 60 is what the paper used.
+const RRF_K: f32 = 60.0;
+
+#[derive(Deserialize)]
+pub struct SearchRequest {
+    query: String,
+    /// The conversation the query is a follow-up in, oldest first, see [`rewrite`].
+    #[serde(default)]
+    history: Vec<ChatMessage>,
+    /// Rewrites the query from `history` into one that makes sense on its own before searching.
+    #[serde(default)]
+    rewrite: bool,
+    #[serde(flatten)]
+    options: SearchOptions,
+}
+
+/// How to search, shared with everything else that searches documents.
+#[derive(Deserialize, Default)]
+pub struct SearchOptions {
+    /// How many chunks to return at most.
+    pub top_k: Option<usize>,
+    /// Chunks scoring less than this are left out, even if there's room for them.
+    pub score_threshold: Option<f32>,
+    /// Only search chunks whose metadata matches, eg. `{"team": "support"}`, and see
+    /// [`vectors::Condition`] for the other conditions there can be.
+    #[serde(default)]
+    pub filter: Filter,
+    /// Has to be the embedding model the documents were ingested with. Falls back to the
+    /// deployment's default one, like ingestion does.
+    pub model: Option<String>,
+    /// Reorders what the vector store found with the rerank model, see [`rerank`].
+    #[serde(default)]
+    pub rerank: bool,
+    /// Searches the chunks' text for the query's words too, and fuses the results with the
+    /// vector search's. This finds exact identifiers and rare words that embeddings miss.
+    #[serde(default)]
+    pub hybrid: bool,
+    /// Searches for a few rewordings of the query too and fuses the results, see
+    /// [`multi_query`]. Slower, but finds more of what's relevant.
+    #[serde(default)]
+    pub multi_query: bool,
+}
+
+/// A chunk of a document that matched a query.
+#[derive(Serialize)]
+pub struct Chunk {
+    pub id: Uuid,
+    /// Higher is closer. For hybrid and multi-query searches it's the fused score from every
+    /// search's ranks rather than the similarity.
+    pub score: f32,
+    /// How relevant the rerank model thinks the chunk is, when it was asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f32>,
+    pub document_id: Uuid,
+    pub text: String,
+    /// Everything else in the chunk's payload, eg. its document's title and metadata.
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    /// What was searched for, when the query was rewritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rewritten_query: Option<String>,
+    results: Vec<Chunk>,
+}
+
+pub async fn search(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(SearchRequest {
+        query,
+        history,
+        rewrite,
+        options,
+    }): Json<SearchRequest>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let rewritten_query = if rewrite {
+        Some(rewrite::rewrite(&state, &history, &query).await)
+    } else {
+        None
+    };
+    let tenant = vectors::tenant(&headers);
+    let query = rewritten_query.as_deref().unwrap_or(&query);
+    let results = retrieve(&state, &tenant, query, &options).await?;
+
+    Ok(Json(SearchResponse {
+        rewritten_query,
+        results,
+    }))
+}
+
+/// The tenant's chunks closest to the query, closest first.
+pub async fn retrieve(
+    state: &AppState,
+    tenant: &str,
+    query: &str,
+    options: &SearchOptions,
+) -> Result<Vec<Chunk>, ApiError> {
+    let top_k = options.top_k.unwrap_or(DEFAULT_TOP_K);
+    let mut violations = Vec::new();
+    if query.trim().is_empty() {
+        violations.push(Violation::new("query", "must not be empty"));
+    }
+    if top_k == 0 || top_k > MAX_TOP_K {
+        violations.push(Violation::new(
+            "top_k",
+            format!("must be between 1 and {MAX_TOP_K}"),
+        ));
+    }
+    violations.extend(vectors::filter_violations(&options.filter));
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+    let Some(model) = state.embedding_model(options.model.as_deref()) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let embedding_options = EmbeddingOptions {
+        input_type: Some("search_query".to_string()),
+        embedding_type: EmbeddingType::Float,
+    };
+    let queries = if options.multi_query {
+        multi_query::variants(state, query).await
+    } else {
+        vec![query.to_string()]
+    };
+    let embeddings = embeddings::embed(state, model, &queries, &embedding_options).await?;
+    let mut query_vectors = Vec::new();
+    for embedding in embeddings {
+        let Vector::Float(vector) = embedding.embedding else {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        };
+        if let Some(dimensions) = state.vectors.dimensions() {
+            if vector.len() != dimensions {
+                return Err(ApiError::Invalid(vec![Violation::new(
+                    "model",
+                    format!("must make vectors with {dimensions} dimensions for this vector store"),
+                )]));
+            }
+        }
+        query_vectors.push(vector);
+    }
+
+    let candidates = if options.rerank {
+        (top_k * RERANK_CANDIDATES).min(MAX_TOP_K)
+    } else {
+        top_k
+    };
+    let filter = vectors::scoped(options.filter.clone(), tenant);
+    // one list of results for each search
+    let mut results = Vec::new();
+    for (query, vector) in queries.iter().zip(query_vectors) {
+        let matches = state.vectors.query(vector, candidates, &filter).await?;
+        results.push(
+            matches
+                .into_iter()
+                .filter(|point| {
+                    options
+                        .score_threshold
+                        .is_none_or(|threshold| point.score >= threshold)
+                })
+                .filter_map(|point| Chunk::from_payload(point.id, point.score, point.payload))
+                .collect(),
+        );
+        if options.hybrid {
+            results.push(keyword_search(state, query, candidates, &filter).await?);
+        }
+    }
+    let mut chunks = if results.len() == 1 {
+        results.remove(0)
+    } else {
+        fused(results)
+    };
+    if !options.rerank || chunks.is_empty() {
+        chunks.truncate(top_k);
+
+        return Ok(chunks);
+    }
+
+    let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+    let ranked = rerank::rerank(state, query, &texts, top_k).await?;
+    let mut chunks: Vec<Option<Chunk>> = chunks.into_iter().map(Some).collect();
+
+    Ok(ranked
+        .into_iter()
+        .filter_map(|(index, relevance)| {
+            let mut chunk = chunks[index].take()?;
+            chunk.rerank_score = Some(relevance);
+
+            Some(chunk)
+        })
+        .collect())
+}
+
+impl Chunk {
+    /// Returns `None` for points that aren't chunks of a document, eg. ones upserted straight
+    /// to `/vectors`.
+    fn from_payload(id: Uuid, score: f32, mut metadata: Map<String, Value>) -> Option<Self> {
+        let document_id = metadata.remove("document_id")?.as_str()?.parse().ok()?;
+        let Some(Value::String(text)) = metadata.remove("text") else {
+            return None;
+        };
+        metadata.remove(TENANT_FIELD);
+
+        Some(Self {
+            id,
+            score,
+            rerank_score: None,
+            document_id,
+            text,
+            metadata,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct KeywordMatch {
+    id: Uuid,
+    payload: JsonColumn<Map<String, Value>>,
+    rank: f32,
+}
+
+/// Postgres full-text search over the chunks' text, best match first.
+async fn keyword_search(
+    state: &AppState,
+    query: &str,
+    limit: usize,
+    filter: &Filter,
+) -> Result<Vec<Chunk>, ApiError> {
+    let mut search = QueryBuilder::new(
+        "SELECT id, payload, ts_rank_cd(search, query) AS rank \
+         FROM chunks, websearch_to_tsquery('english', ",
+    );
+    search
+        .push_bind(query)
+        .push(") AS query WHERE search @@ query");
+    pgvector::push_filter(&mut search, filter);
+    search
+        .push(" ORDER BY rank DESC LIMIT ")
+        .push_bind(limit as i64);
+    let matches: Vec<KeywordMatch> = search.build_query_as().fetch_all(&state.db).await?;
+
+    Ok(matches
+        .into_iter()
+        .filter_map(|found| Chunk::from_payload(found.id, found.rank, found.payload.0))
+        .collect())
+}
+
+/// Reciprocal rank fusion: each chunk scores `1 / (k + rank)` for each search it was found by,
+/// so ones several searches found come out on top.
+fn fused(results: Vec<Vec<Chunk>>) -> Vec<Chunk> {
+    let mut fused: HashMap<Uuid, Chunk> = HashMap::new();
+    for results in results {
+        for (rank, mut chunk) in results.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            match fused.get_mut(&chunk.id) {
+                Some(found) => found.score += score,
+                None => {
+                    chunk.score = score;
+                    fused.insert(chunk.id, chunk);
+                }
+            }
+        }
+    }
+
+    let mut fused: Vec<Chunk> = fused.into_values().collect();
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: Uuid) -> Chunk {
+        Chunk {
+            id,
+            score: 0.0,
+            rerank_score: None,
+            document_id: Uuid::nil(),
+            text: String::new(),
+            metadata: Map::new(),
+        }
+    }
+
+    #[test]
+    fn chunks_found_by_several_searches_come_first() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let fused = fused(vec![vec![chunk(a), chunk(b)], vec![chunk(b), chunk(c)]]);
+
+        let ids: Vec<Uuid> = fused.iter().map(|chunk| chunk.id).collect();
+        assert_eq!(ids, [b, a, c]);
+        assert_eq!(fused[0].score, 1.0 / 62.0 + 1.0 / 61.0);
+        assert_eq!(fused[1].score, 1.0 / 61.0);
+    }
+
+    #[test]
+    fn nothing_fuses_to_nothing() {
+        assert!(fused(Vec::new()).is_empty());
+    }
+}
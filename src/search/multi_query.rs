@@ -0,0 +1,65 @@
+//! Searching for a few rewordings of the query as well as the query itself, since chunks that
+//! answer it in other words can be further from the query's embedding than ones that don't. It
+//! finds more of what's relevant for an extra model call and a few more searches.
+
+use std::collections::HashSet;
+
+use crate::{complete, AppState, PreparedPrompt};
+
+const INSTRUCTIONS: &str = "Write different versions of the user's search query that ask for \
+the same thing in other words, eg. with synonyms or from another angle, so a search finds \
+documents the original wording would miss. Reply with one query per line and nothing else.";
+/// How many rewordings to search for along with the query.
+pub const QUERY_VARIANTS: usize = 3;
+const MAX_VARIANT_TOKENS: i32 = 256;
+const MAX_QUERY_LENGTH: usize = 1000;
+
+/// The query, then up to [`QUERY_VARIANTS`] rewordings of it. If the model fails, it's just the
+/// query.
+pub async fn variants(state: &AppState, query: &str) -> Vec<String> {
+    let mut queries = vec![query.to_string()];
+    let Some(model) = state.model(state.chat.summary_model_id.as_deref()) else {
+        println!("The summary model is denied, only searching for the query itself");
+        return queries;
+    };
+
+    let mut params = model.defaults.clone();
+    params.max_tokens = Some(MAX_VARIANT_TOKENS);
+    let prepared = PreparedPrompt {
+        model,
+        logprobs: false,
+        prompt: format!("Write {QUERY_VARIANTS} versions of this query: {query}"),
+        system: Some(INSTRUCTIONS.to_string()),
+        images: Vec::new(),
+        params,
+        history: Vec::new(),
+    };
+    let completion = match complete(state, &prepared, &prepared.params).await {
+        Ok(completion) => completion,
+        Err(err) => {
+            println!("Couldn't reword the query: {err:?}");
+            return queries;
+        }
+    };
+
+    let mut seen: HashSet<String> = HashSet::from([query.trim().to_lowercase()]);
+    for line in completion.text.lines() {
+        // models like to number or bullet their lists
+        let variant = line
+            .trim()
+            .trim_start_matches(|c: char| c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*'))
+            .trim()
+            .trim_matches(['"', '\'']);
+        if variant.is_empty() || variant.len() > MAX_QUERY_LENGTH {
+            continue;
+        }
+        if seen.insert(variant.to_lowercase()) {
+            queries.push(variant.to_string());
+        }
+        if queries.len() > QUERY_VARIANTS {
+            break;
+        }
+    }
+
+    queries
+}
@@ -0,0 +1,74 @@
+//! Reordering retrieved chunks with a rerank model, which reads the query and each chunk
+//! together so it can tell what's relevant better than comparing embeddings can.
+
+use aws_sdk_bedrockruntime::primitives::Blob;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+pub const DEFAULT_RERANK_MODEL_ID: &str = "cohere.rerank-v3-5:0";
+
+#[derive(Serialize)]
+struct RerankRequest<'a> {
+    query: &'a str,
+    documents: &'a [&'a str],
+    top_n: usize,
+    api_version: u32,
+}
+
+#[derive(Deserialize)]
+struct RerankResponse {
+    results: Vec<RerankResult>,
+}
+
+#[derive(Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+}
+
+/// The indexes of the `top_n` most relevant documents and how relevant each is, most relevant
+/// first.
+pub async fn rerank(
+    state: &AppState,
+    query: &str,
+    documents: &[&str],
+    top_n: usize,
+) -> Result<Vec<(usize, f32)>, StatusCode> {
+    let model_id = &state.documents.rerank_model_id;
+    let Ok(body) = serde_json::to_vec(&RerankRequest {
+        query,
+        documents,
+        top_n: top_n.min(documents.len()),
+        api_version: 2,
+    }) else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let blob = Blob::new(body);
+
+    let Ok(res) = state
+        .clients
+        .send_to(model_id, |client, model_id| {
+            client
+                .invoke_model()
+                .body(blob.clone())
+                .model_id(model_id)
+                .send()
+        })
+        .await
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let Ok(response) = serde_json::from_slice::<RerankResponse>(&res.body.into_inner()) else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    Ok(response
+        .results
+        .into_iter()
+        .filter(|result| result.index < documents.len())
+        .map(|result| (result.index, result.relevance_score))
+        .collect())
+}
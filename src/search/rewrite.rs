@@ -0,0 +1,70 @@
+//! Turning a follow-up question into one that makes sense on its own, eg. "what about the second
+//! one?" into "pricing of the Pro plan", since the chunks that answer it won't mention "the second
+//! one". A cheap model does the rewriting, from the conversation so far.
+
+use crate::{
+    chat::{self, ChatMessage, Role},
+    complete, AppState, PreparedPrompt,
+};
+
+const INSTRUCTIONS: &str = "Rewrite the user's latest question as a standalone search query, \
+using the conversation above it to fill in whatever the question refers to. Keep the names and \
+terms the conversation uses. Only reply with the query, without quotes. If the question already \
+makes sense on its own, reply with it as it is.";
+const MAX_QUERY_TOKENS: i32 = 128;
+/// Follow-ups are nearly always about the last few turns.
+const MAX_HISTORY_MESSAGES: usize = 10;
+const MAX_QUERY_LENGTH: usize = 1000;
+
+/// The query to search for instead of `query`. Rewriting is only worth it for better results,
+/// so if the model fails the query is searched for as it is.
+pub async fn rewrite(state: &AppState, history: &[ChatMessage], query: &str) -> String {
+    let history: Vec<ChatMessage> = history
+        .iter()
+        .filter(|message| message.role != Role::System)
+        .cloned()
+        .collect();
+    if history.is_empty() || query.trim().is_empty() {
+        return query.to_string();
+    }
+    let Some(model) = state.model(state.chat.summary_model_id.as_deref()) else {
+        println!("The summary model is denied, not rewriting the query");
+        return query.to_string();
+    };
+
+    let recent = &history[history.len().saturating_sub(MAX_HISTORY_MESSAGES)..];
+    let mut params = model.defaults.clone();
+    params.temperature = Some(0.0);
+    params.max_tokens = Some(MAX_QUERY_TOKENS);
+    let prepared = PreparedPrompt {
+        model,
+        logprobs: false,
+        prompt: format!("{}Latest question: {query}", chat::written_out(recent)),
+        system: Some(INSTRUCTIONS.to_string()),
+        images: Vec::new(),
+        params,
+        history: Vec::new(),
+    };
+    let completion = match complete(state, &prepared, &prepared.params).await {
+        Ok(completion) => completion,
+        Err(err) => {
+            println!("Couldn't rewrite the query: {err:?}");
+            return query.to_string();
+        }
+    };
+
+    let rewritten: String = completion
+        .text
+        .lines()
+        .map(|line| line.trim().trim_matches(['"', '\'']))
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .chars()
+        .take(MAX_QUERY_LENGTH)
+        .collect();
+    if rewritten.is_empty() {
+        query.to_string()
+    } else {
+        rewritten
+    }
+}
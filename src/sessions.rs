@@ -0,0 +1,104 @@
+//! Sessions for clients that can't keep track of a conversation themselves (eg. curl, or a
+//! webhook), which only have to send their session's token with each message.
+
+use axum::{
+    extract::{MatchedPath, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    chat::{self, ChatMessage, Role, Turn, TurnOptions},
+    conversations,
+    error::ApiError,
+    limits::MaxTokensLimit,
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct SessionMessage {
+    message: String,
+    #[serde(flatten)]
+    options: TurnOptions,
+}
+
+/// Starts a session with a new conversation, returning the token to send messages with.
+pub async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let conversation_id: Uuid =
+        sqlx::query_scalar("INSERT INTO conversations (owner) VALUES ($1) RETURNING id")
+            .bind(conversations::owner(&headers))
+            .fetch_one(&mut *tx)
+            .await?;
+    let token: String =
+        sqlx::query_scalar("INSERT INTO sessions (conversation_id) VALUES ($1) RETURNING token")
+            .bind(conversation_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "token": token, "conversation_id": conversation_id })),
+    ))
+}
+
+/// Replies to the next message in the session, like `/chat` does.
+pub async fn send(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
+    Json(SessionMessage { message, options }): Json<SessionMessage>,
+) -> Result<Response, ApiError> {
+    let session: Option<(Uuid, bool)> = sqlx::query_as(
+        "SELECT conversation_id, ended_at IS NOT NULL FROM sessions WHERE token = $1",
+    )
+    .bind(&token)
+    .fetch_optional(&state.db)
+    .await?;
+    let Some((conversation_id, ended)) = session else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    if ended {
+        return Err(StatusCode::GONE.into());
+    }
+
+    let turn = Turn {
+        conversation_id: Some(conversation_id),
+        user_id: None,
+        messages: vec![ChatMessage {
+            role: Role::User,
+            content: message,
+        }],
+        replacing: None,
+        options,
+    };
+
+    chat::respond(&state, &route, max_tokens_limit, &headers, turn).await
+}
+
+/// Ends the session, after which its token can't be used any more. The conversation is kept.
+pub async fn end(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let ended =
+        sqlx::query("UPDATE sessions SET ended_at = now() WHERE token = $1 AND ended_at IS NULL")
+            .bind(&token)
+            .execute(&state.db)
+            .await?;
+    if ended.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,1096 @@
+//! Streamed completions, either as raw text or as server-sent events.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_bedrockruntime::{
+    primitives::event_stream::EventReceiver,
+    types::{error::ConverseStreamOutputError, ConverseStreamOutput, TokenUsage},
+};
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use axum_streams::StreamBodyAs;
+use futures::{
+    future::{select, try_join_all, Either},
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{timeout, timeout_at, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    attachments::PromptBody,
+    config::StreamingConfig,
+    conversations, converse,
+    error::{ApiError, Violation},
+    limits::MaxTokensLimit,
+    prepare_prompt, seed_header, AppState, PreparedPrompt, Prompt,
+};
+
+type Events = EventReceiver<ConverseStreamOutput, ConverseStreamOutputError>;
+
+/// Streaming from any more models at once would be hard to follow anyway.
+const MAX_COMPARED_MODELS: usize = 4;
+
+/// How long a stream is kept for after its client stops reading, so it can reconnect.
+const RESUMABLE_FOR: Duration = Duration::from_secs(5 * 60);
+/// Once a stream is over there's only its end left to resume, which a client that missed it
+/// comes back for soon if it's going to.
+const FINISHED_RESUMABLE_FOR: Duration = Duration::from_secs(30);
+/// How often streams that can't be resumed any more are dropped, even if nothing else is
+/// streamed in the meantime.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+/// The most streams kept for resuming at once, as each one holds all of its text.
+const MAX_STREAMS: usize = 1000;
+
+/// The header a streamed prompt's id is sent in, for resuming it.
+const STREAM_ID_HEADER: &str = "x-stream-id";
+
+/// A Converse stream in progress. Only a few events are read from Bedrock ahead of a client
+/// reading from us, and once a stream can't be resumed any more this is dropped along with the
+/// connection to Bedrock, so we stop paying for tokens nobody will see.
+struct Generation {
+    events: Events,
+    model_id: String,
+    /// Bedrock sends this before the usage, which is the last thing in the stream.
+    stop_reason: Option<String>,
+    usage: Option<TokenUsage>,
+    started: Instant,
+    /// How long to give the model, see [`StreamingConfig::timeout`].
+    timeout: Option<Duration>,
+    /// See [`StreamingConfig::buffer`].
+    buffer: usize,
+    stop_sequences: StopSequences,
+    finished: bool,
+}
+
+impl Generation {
+    fn summary(&mut self) -> Summary {
+        Summary {
+            model: self.model_id.clone(),
+            input_tokens: self.usage.as_ref().map(|usage| usage.input_tokens),
+            output_tokens: self.usage.as_ref().map(|usage| usage.output_tokens),
+            completion_reason: self.stop_reason.take(),
+        }
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        if !self.finished {
+            println!(
+                "Nobody is reading, abandoning the stream from {}",
+                self.model_id
+            );
+        }
+    }
+}
+
+#[derive(Clone)]
+enum StreamEvent {
+    Delta(String),
+    Done(Summary),
+    Error(String),
+    /// The stream took longer than [`StreamingConfig::timeout`].
+    TimedOut(Duration),
+}
+
+/// The same accounting a blocking [`crate::Completion`] has, sent once the stream is over.
+#[derive(Serialize, Clone)]
+struct Summary {
+    model: String,
+    input_tokens: Option<i32>,
+    output_tokens: Option<i32>,
+    completion_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    /// The generated text as is.
+    #[default]
+    Text,
+    /// One [`Chunk`] per line, so clients can tell where each one starts and ends.
+    Ndjson,
+}
+
+/// What each delta holds, set with `?chunking=` on either streaming endpoint.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Chunking {
+    /// Whatever Bedrock sent, usually a token or so.
+    #[default]
+    Token,
+    /// Whole sentences, eg. for text-to-speech.
+    Sentence,
+    Paragraph,
+}
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    #[serde(default)]
+    format: StreamFormat,
+    #[serde(default)]
+    chunking: Chunking,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SseFormat {
+    /// Named `delta`, `done`, `error` and `timeout` events.
+    #[default]
+    Events,
+    /// Unnamed events with OpenAI's `chat.completion.chunk`s, ending with `[DONE]`, for frontend
+    /// libraries that already know how to read those.
+    OpenAi,
+}
+
+#[derive(Deserialize)]
+pub struct SseQuery {
+    #[serde(default)]
+    format: SseFormat,
+    #[serde(default)]
+    chunking: Chunking,
+}
+
+#[derive(Deserialize)]
+pub struct ResumeQuery {
+    /// How many bytes of the text the client already has.
+    #[serde(default)]
+    from: usize,
+    #[serde(default)]
+    format: StreamFormat,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Chunk {
+    Delta {
+        delta: String,
+        index: usize,
+    },
+    Done {
+        done: Summary,
+        index: usize,
+    },
+    /// Always the last chunk when there is one.
+    Error {
+        error: String,
+        index: usize,
+    },
+    /// Sent while the model is quiet, so proxies don't drop the connection for being idle. It
+    /// isn't counted in the indexes.
+    Heartbeat {
+        heartbeat: bool,
+    },
+}
+
+/// Streams `text/plain; charset=utf-8` by default, whatever the `Accept` header says, as there's
+/// no JSON envelope until the stream is over. `?format=ndjson` streams `application/x-ndjson`.
+///
+/// If Bedrock fails part way through or the stream times out, text streams are aborted rather than
+/// ended cleanly, so clients can tell they didn't get everything, and NDJSON streams end with an
+/// error chunk. Otherwise NDJSON streams end with a `done` chunk with the token usage.
+///
+/// NDJSON streams get `{"heartbeat": true}` lines while the model is quiet, if heartbeats are
+/// configured. Text streams don't, as there's nowhere to put them that isn't the text.
+///
+/// The stream's id is sent in the `x-stream-id` header, so clients that lose their connection can
+/// pick up where they left off with [`resume`], with the same key.
+pub async fn text(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    Query(StreamQuery { format, chunking }): Query<StreamQuery>,
+    PromptBody(prompt): PromptBody,
+) -> Result<Response, ApiError> {
+    let prepared = prepare_prompt(&state, &route, max_tokens_limit, prompt)?;
+    let seed = prepared.params.seed;
+    let receiver = open(&state, prepared).await?;
+
+    let events = coalesce(split(events(receiver), chunking), &state.streaming);
+    let inserted = state
+        .streams
+        .insert(conversations::owner(&headers), events.boxed())
+        .await;
+    let Some((id, stream)) = inserted else {
+        return Err(StatusCode::SERVICE_UNAVAILABLE.into());
+    };
+
+    Ok((
+        seed_header(seed),
+        [(STREAM_ID_HEADER, id)],
+        text_response(replay(stream, 0), format, state.streaming.heartbeat),
+    )
+        .into_response())
+}
+
+/// Carries on a stream from [`text`] from `?from=`, the number of bytes of text the client has
+/// already got.
+pub async fn resume(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(ResumeQuery { from, format }): Query<ResumeQuery>,
+) -> Result<Response, ApiError> {
+    let owner = conversations::owner(&headers);
+    let Some(stream) = state.streams.get(&id, owner.as_deref()).await else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    {
+        let buffered = stream.lock().await;
+        if !buffered.text.is_char_boundary(from) {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    }
+
+    Ok(text_response(
+        replay(stream, from),
+        format,
+        state.streaming.heartbeat,
+    ))
+}
+
+fn text_response(
+    events: impl Stream<Item = StreamEvent> + Send + 'static,
+    format: StreamFormat,
+    heartbeat: Option<Duration>,
+) -> Response {
+    match format {
+        StreamFormat::Text => {
+            let text = events.filter_map(|event| async move {
+                match event {
+                    StreamEvent::Delta(text) => Some(Ok(text)),
+                    StreamEvent::Error(err) => Some(Err(io::Error::other(err))),
+                    StreamEvent::TimedOut(_) => Some(Err(io::ErrorKind::TimedOut.into())),
+                    StreamEvent::Done(_) => None,
+                }
+            });
+
+            (
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                Body::from_stream(text),
+            )
+                .into_response()
+        }
+        StreamFormat::Ndjson => {
+            let chunks = events.enumerate().map(|(index, event)| match event {
+                StreamEvent::Delta(delta) => Chunk::Delta { delta, index },
+                StreamEvent::Done(done) => Chunk::Done { done, index },
+                StreamEvent::Error(error) => Chunk::Error { error, index },
+                StreamEvent::TimedOut(timeout) => Chunk::Error {
+                    error: timed_out(timeout),
+                    index,
+                },
+            });
+
+            StreamBodyAs::json_nl(with_heartbeats(chunks, heartbeat)).into_response()
+        }
+    }
+}
+
+/// What `KeepAlive` does for SSE, for NDJSON: a heartbeat chunk whenever there's been nothing to
+/// send for `interval`.
+fn with_heartbeats(
+    chunks: impl Stream<Item = Chunk> + Send + 'static,
+    interval: Option<Duration>,
+) -> BoxStream<'static, Chunk> {
+    let Some(interval) = interval else {
+        return chunks.boxed();
+    };
+
+    stream::unfold(Some(chunks.boxed()), move |chunks| async move {
+        let mut chunks = chunks?;
+        // the chunks stream keeps its place if this gives up waiting on it
+        match timeout(interval, chunks.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(chunks))),
+            Ok(None) => None,
+            Err(_) => Some((Chunk::Heartbeat { heartbeat: true }, Some(chunks))),
+        }
+    })
+    .boxed()
+}
+
+/// A `text/event-stream` for browser `EventSource` clients: a `delta` event for each new piece of
+/// text, then `done` with the token usage and stop reason, `error` or `timeout`. Heartbeat
+/// comments are sent while the model is quiet, if they're configured. `?format=openai` sends
+/// OpenAI style chunks instead.
+pub async fn sse(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    Query(SseQuery { format, chunking }): Query<SseQuery>,
+    PromptBody(prompt): PromptBody,
+) -> Result<impl IntoResponse, ApiError> {
+    let prepared = prepare_prompt(&state, &route, max_tokens_limit, prompt)?;
+    let seed = prepared.params.seed;
+    let model_id = prepared.model.id.clone();
+    let receiver = open(&state, prepared).await?;
+
+    let events = coalesce(split(events(receiver), chunking), &state.streaming);
+    let stream = match format {
+        SseFormat::Events => events.map(sse_event).boxed(),
+        SseFormat::OpenAi => {
+            let chunks = OpenAiChunks::new(model_id);
+            events
+                .flat_map(move |event| stream::iter(chunks.events(event)))
+                .boxed()
+        }
+    }
+    .map(Ok::<_, Infallible>);
+
+    let mut sse = Sse::new(stream);
+    if let Some(heartbeat) = state.streaming.heartbeat {
+        sse = sse.keep_alive(KeepAlive::new().interval(heartbeat));
+    }
+
+    Ok((seed_header(seed), sse))
+}
+
+/// Streams an already prepared prompt like [`sse`] does, with `first` sent before any of the
+/// model's events, eg. the sources `/ask` answers from.
+pub async fn sse_after(
+    state: &AppState,
+    prepared: PreparedPrompt<'_>,
+    first: Event,
+) -> Result<Response, ApiError> {
+    let seed = prepared.params.seed;
+    let receiver = open(state, prepared).await?;
+
+    let events = coalesce(events(receiver), &state.streaming).map(sse_event);
+    let stream = stream::once(async { first })
+        .chain(events)
+        .map(Ok::<_, Infallible>);
+
+    let mut sse = Sse::new(stream);
+    if let Some(heartbeat) = state.streaming.heartbeat {
+        sse = sse.keep_alive(KeepAlive::new().interval(heartbeat));
+    }
+
+    Ok((seed_header(seed), sse).into_response())
+}
+
+fn sse_event(event: StreamEvent) -> Event {
+    let (name, data) = sse_parts(event);
+
+    Event::default().event(name).json_data(data).unwrap()
+}
+
+/// An event's name and data.
+fn sse_parts(event: StreamEvent) -> (&'static str, serde_json::Value) {
+    match event {
+        StreamEvent::Delta(text) => ("delta", json!({ "text": text })),
+        StreamEvent::Done(summary) => ("done", json!(summary)),
+        StreamEvent::Error(message) => ("error", json!({ "message": message })),
+        StreamEvent::TimedOut(timeout) => ("timeout", json!({ "message": timed_out(timeout) })),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Comparison {
+    models: Vec<String>,
+    /// Everything but the model is the same for each of them.
+    #[serde(flatten)]
+    prompt: Prompt,
+}
+
+/// Streams the same prompt from several models at once, as SSE events like [`sse`]'s with the
+/// `model` each one is from in its data.
+pub async fn compare(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    Extension(max_tokens_limit): Extension<MaxTokensLimit>,
+    Json(Comparison { models, prompt }): Json<Comparison>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !(2..=MAX_COMPARED_MODELS).contains(&models.len()) {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "models",
+            format!("must have between 2 and {MAX_COMPARED_MODELS} models"),
+        )]));
+    }
+    if !models.iter().all(|model| state.permits(model)) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let mut prepared = Vec::new();
+    for model in models {
+        let prompt = Prompt {
+            model: Some(model),
+            ..prompt.clone()
+        };
+
+        prepared.push(prepare_prompt(&state, &route, max_tokens_limit, prompt)?);
+    }
+    let model_ids: Vec<String> = prepared
+        .iter()
+        .map(|prepared| prepared.model.id.clone())
+        .collect();
+    let generations =
+        try_join_all(prepared.into_iter().map(|prepared| open(&state, prepared))).await?;
+
+    let streams = generations
+        .into_iter()
+        .zip(model_ids)
+        .map(|(generation, model_id)| {
+            coalesce(events(generation), &state.streaming).map(move |event| {
+                let (name, mut data) = sse_parts(event);
+                data["model"] = json!(model_id);
+
+                Ok::<_, Infallible>(Event::default().event(name).json_data(data).unwrap())
+            })
+        });
+    let streams: Vec<_> = streams.map(StreamExt::boxed).collect();
+
+    let mut sse = Sse::new(stream::select_all(streams));
+    if let Some(heartbeat) = state.streaming.heartbeat {
+        sse = sse.keep_alive(KeepAlive::new().interval(heartbeat));
+    }
+
+    Ok(sse)
+}
+
+/// Turns events into `chat.completion.chunk`s, which all share an id and creation time.
+struct OpenAiChunks {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl OpenAiChunks {
+    fn new(model: String) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            id: format!("chatcmpl-{}", stream_id()),
+            created,
+            model,
+        }
+    }
+
+    fn events(&self, event: StreamEvent) -> Vec<Event> {
+        let (chunk, last) = match event {
+            StreamEvent::Delta(text) => (self.chunk(json!({ "content": text }), None, None), false),
+            StreamEvent::Done(summary) => {
+                let finish_reason = summary.completion_reason.as_deref().map(finish_reason);
+                let usage = json!({
+                    "prompt_tokens": summary.input_tokens,
+                    "completion_tokens": summary.output_tokens,
+                    "total_tokens": summary
+                        .input_tokens
+                        .zip(summary.output_tokens)
+                        .map(|(input, output)| input + output),
+                });
+
+                (self.chunk(json!({}), finish_reason, Some(usage)), true)
+            }
+            StreamEvent::Error(message) => (json!({ "error": { "message": message } }), true),
+            StreamEvent::TimedOut(timeout) => {
+                (json!({ "error": { "message": timed_out(timeout) } }), true)
+            }
+        };
+
+        let chunk = Event::default().json_data(chunk).unwrap();
+        if !last {
+            return vec![chunk];
+        }
+
+        vec![chunk, Event::default().data("[DONE]")]
+    }
+
+    fn chunk(
+        &self,
+        delta: serde_json::Value,
+        finish_reason: Option<&str>,
+        usage: Option<serde_json::Value>,
+    ) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            "usage": usage,
+        })
+    }
+}
+
+/// Bedrock's stop reasons in OpenAI's terms.
+fn finish_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "max_tokens" => "length",
+        "content_filtered" | "guardrail_intervened" => "content_filter",
+        "tool_use" => "tool_calls",
+        _ => "stop",
+    }
+}
+
+fn timed_out(timeout: Duration) -> String {
+    format!("the model didn't finish within {}s", timeout.as_secs())
+}
+
+/// Starts a Converse stream for the prompt.
+async fn open(state: &AppState, prepared: PreparedPrompt<'_>) -> Result<Generation, ApiError> {
+    let PreparedPrompt {
+        model,
+        prompt,
+        system,
+        images,
+        params,
+        history,
+        ..
+    } = prepared;
+
+    if !model.provider.supports_streaming() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let (prompt, system) = converse::system_prompt(model, system, prompt);
+    let Some(inference_config) = converse::inference_config(model, &prompt, &params) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    let additional_fields = converse::additional_fields(model, &params);
+    let Some(messages) = converse::messages(&history, prompt, &images) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let res = state
+        .clients
+        .send(model, |client, model_id| {
+            client
+                .converse_stream()
+                .model_id(model_id)
+                .set_messages(Some(messages.clone()))
+                .set_system(system.clone())
+                .inference_config(inference_config.clone())
+                .set_additional_model_request_fields(additional_fields.clone())
+                .send()
+        })
+        .await;
+
+    match res {
+        Ok(res) => Ok(Generation {
+            events: res.stream,
+            model_id: model.id.clone(),
+            stop_reason: None,
+            usage: None,
+            started: Instant::now(),
+            timeout: state.streaming.timeout,
+            buffer: state.streaming.buffer,
+            stop_sequences: StopSequences::new(params.stop_sequences.clone().unwrap_or_default()),
+            finished: false,
+        }),
+        Err(err) => {
+            println!("Couldn't start a stream for {}: {err}", model.id);
+            Err(StatusCode::BAD_GATEWAY.into())
+        }
+    }
+}
+
+/// The events we care about, ending after the first error (which is logged).
+///
+/// They're read from Bedrock in their own task, but only [`StreamingConfig::buffer`] of them can
+/// be waiting to be sent at once, so a slow client slows down how fast we read from Bedrock
+/// rather than us holding on to everything it hasn't read yet.
+fn events(generation: Generation) -> impl Stream<Item = StreamEvent> {
+    let (tx, rx) = mpsc::channel(generation.buffer);
+    tokio::spawn(async move {
+        let mut events = pin!(read(generation));
+        // once the client is gone, dropping the generation here is what stops it, even if the
+        // model is in the middle of something
+        loop {
+            let next = match select(pin!(tx.closed()), events.next()).await {
+                Either::Left(_) => break,
+                Either::Right((next, _)) => next,
+            };
+            let Some(event) = next else {
+                break;
+            };
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+
+        Some((event, rx))
+    })
+}
+
+fn read(generation: Generation) -> impl Stream<Item = StreamEvent> {
+    stream::unfold(Some(generation), |generation| async move {
+        let mut generation = generation?;
+
+        // only content deltas carry anything until the end, so keep reading until one does
+        loop {
+            if generation.finished {
+                return Some((StreamEvent::Done(generation.summary()), None));
+            }
+
+            let next = match generation.timeout {
+                Some(timeout) => {
+                    let deadline = generation.started + timeout;
+                    match timeout_at(deadline, generation.events.recv()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            println!("Stream from {} timed out", generation.model_id);
+                            // dropping the generation is what aborts the request to Bedrock
+                            generation.finished = true;
+
+                            return Some((StreamEvent::TimedOut(timeout), None));
+                        }
+                    }
+                }
+                None => generation.events.recv().await,
+            };
+
+            match next {
+                Ok(Some(ConverseStreamOutput::MessageStop(stop))) => {
+                    generation.stop_reason = Some(stop.stop_reason.as_str().to_string());
+                }
+                Ok(Some(ConverseStreamOutput::Metadata(metadata))) => {
+                    generation.usage = metadata.usage;
+                }
+                Ok(Some(event)) => {
+                    let Some(text) = converse::delta_text(event) else {
+                        continue;
+                    };
+
+                    let (text, stopped) = generation.stop_sequences.scan(&text);
+                    if stopped {
+                        // some families ignore stop sequences when streaming, so we stop them
+                        generation.stop_reason = Some("stop_sequence".to_string());
+                        generation.finished = true;
+                    }
+                    if !text.is_empty() {
+                        return Some((StreamEvent::Delta(text), Some(generation)));
+                    }
+                }
+                Ok(None) => {
+                    generation.finished = true;
+                    let text = generation.stop_sequences.flush();
+                    if !text.is_empty() {
+                        return Some((StreamEvent::Delta(text), Some(generation)));
+                    }
+                }
+                Err(err) => {
+                    println!("Stream failed part way through: {err}");
+                    generation.finished = true;
+
+                    return Some((StreamEvent::Error(err.to_string()), None));
+                }
+            }
+        }
+    })
+}
+
+/// Looks for stop sequences in the streamed text, including ones split across deltas.
+struct StopSequences {
+    stop_sequences: Vec<String>,
+    /// The end of the text so far, held back as it could be the start of a stop sequence.
+    held: String,
+}
+
+impl StopSequences {
+    fn new(stop_sequences: Vec<String>) -> Self {
+        Self {
+            stop_sequences,
+            held: String::new(),
+        }
+    }
+
+    /// The text that's safe to send, and whether a stop sequence was found (in which case
+    /// nothing after it is sent).
+    fn scan(&mut self, delta: &str) -> (String, bool) {
+        let mut text = std::mem::take(&mut self.held);
+        text.push_str(delta);
+
+        let stop = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| text.find(stop.as_str()))
+            .min();
+        if let Some(stop) = stop {
+            text.truncate(stop);
+
+            return (text, true);
+        }
+
+        let partial = text.char_indices().map(|(i, _)| i).find(|&i| {
+            self.stop_sequences
+                .iter()
+                .any(|stop| stop.starts_with(&text[i..]))
+        });
+        if let Some(partial) = partial {
+            self.held = text.split_off(partial);
+        }
+
+        (text, false)
+    }
+
+    /// Whatever was held back, once the stream is over.
+    fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+impl Chunking {
+    /// Where the last complete sentence or paragraph in `text` ends, if there is one.
+    fn boundary(self, text: &str) -> Option<usize> {
+        match self {
+            Chunking::Token => Some(text.len()),
+            Chunking::Sentence => {
+                let mut chars = text.char_indices().peekable();
+                let mut boundary = None;
+                while let Some((i, c)) = chars.next() {
+                    let ends_sentence = match chars.peek() {
+                        Some((_, next)) => matches!(c, '.' | '!' | '?') && next.is_whitespace(),
+                        None => false,
+                    };
+                    if c == '\n' || ends_sentence {
+                        boundary = Some(i + c.len_utf8());
+                    }
+                }
+
+                boundary
+            }
+            Chunking::Paragraph => text.rfind("\n\n").map(|i| i + 2),
+        }
+    }
+}
+
+/// Holds deltas back until they make up a whole sentence or paragraph. Whatever's left over is
+/// sent when the stream ends.
+fn split(
+    events: impl Stream<Item = StreamEvent> + Send + 'static,
+    chunking: Chunking,
+) -> BoxStream<'static, StreamEvent> {
+    if let Chunking::Token = chunking {
+        return events.boxed();
+    }
+
+    let state = (events.boxed(), String::new(), None::<StreamEvent>);
+    stream::unfold(Some(state), move |state| async move {
+        let (mut events, mut pending, held) = state?;
+        if let Some(event) = held {
+            return Some((event, None));
+        }
+
+        loop {
+            match events.next().await {
+                Some(StreamEvent::Delta(text)) => {
+                    pending.push_str(&text);
+
+                    if let Some(boundary) = chunking.boundary(&pending) {
+                        let rest = pending.split_off(boundary);
+                        let chunk = std::mem::replace(&mut pending, rest);
+
+                        return Some((StreamEvent::Delta(chunk), Some((events, pending, None))));
+                    }
+                }
+                Some(event) if pending.is_empty() => return Some((event, None)),
+                Some(event) => {
+                    return Some((
+                        StreamEvent::Delta(pending),
+                        Some((events, String::new(), Some(event))),
+                    ))
+                }
+                None if pending.is_empty() => return None,
+                None => return Some((StreamEvent::Delta(pending), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Deltas waiting to be sent as one, see [`StreamingConfig::flush_bytes`].
+struct Coalesced {
+    events: BoxStream<'static, StreamEvent>,
+    pending: String,
+    /// When the first of the pending deltas arrived.
+    since: Option<Instant>,
+    /// The end of the stream, held back until the pending deltas are sent.
+    held: Option<StreamEvent>,
+}
+
+impl Coalesced {
+    fn flush(&mut self) -> StreamEvent {
+        self.since = None;
+
+        StreamEvent::Delta(std::mem::take(&mut self.pending))
+    }
+}
+
+/// For clients that choke on a chunk per token.
+fn coalesce(
+    events: impl Stream<Item = StreamEvent> + Send + 'static,
+    config: &StreamingConfig,
+) -> impl Stream<Item = StreamEvent> {
+    let (flush_bytes, flush_interval) = (config.flush_bytes, config.flush_interval);
+    let coalesced = Coalesced {
+        events: events.boxed(),
+        pending: String::new(),
+        since: None,
+        held: None,
+    };
+
+    stream::unfold(Some(coalesced), move |coalesced| async move {
+        let mut coalesced = coalesced?;
+        if let Some(event) = coalesced.held.take() {
+            return Some((event, None));
+        }
+
+        loop {
+            let next = match (coalesced.since, flush_interval) {
+                (Some(since), Some(interval)) => {
+                    // the events stream keeps its place if this gives up waiting on it
+                    match timeout_at(since + interval, coalesced.events.next()).await {
+                        Ok(next) => next,
+                        Err(_) => return Some((coalesced.flush(), Some(coalesced))),
+                    }
+                }
+                _ => coalesced.events.next().await,
+            };
+
+            match next {
+                Some(StreamEvent::Delta(text)) => {
+                    coalesced.pending.push_str(&text);
+                    coalesced.since.get_or_insert_with(Instant::now);
+
+                    if coalesced.pending.len() >= flush_bytes {
+                        return Some((coalesced.flush(), Some(coalesced)));
+                    }
+                }
+                // anything else ends the stream
+                Some(event) if coalesced.pending.is_empty() => return Some((event, None)),
+                Some(event) => {
+                    coalesced.held = Some(event);
+
+                    return Some((coalesced.flush(), Some(coalesced)));
+                }
+                None if coalesced.pending.is_empty() => return None,
+                None => return Some((coalesced.flush(), None)),
+            }
+        }
+    })
+}
+
+/// Streams from [`text`] that can still be resumed, by id.
+#[derive(Default)]
+pub struct Streams {
+    streams: Mutex<HashMap<String, Resumable>>,
+}
+
+struct Resumable {
+    /// Whose it is, like a conversation's owner, as only they can resume it.
+    owner: Option<String>,
+    stream: Arc<Mutex<Buffered>>,
+}
+
+/// Everything a stream has sent so far, so it can be sent again.
+struct Buffered {
+    text: String,
+    /// The last event, once the stream is over.
+    end: Option<StreamEvent>,
+    /// Only read from when a client has caught up with what's already been sent.
+    source: BoxStream<'static, StreamEvent>,
+    last_read: Instant,
+}
+
+impl Streams {
+    /// `None` if there are already [`MAX_STREAMS`] being read from.
+    async fn insert(
+        &self,
+        owner: Option<String>,
+        source: BoxStream<'static, StreamEvent>,
+    ) -> Option<(String, Arc<Mutex<Buffered>>)> {
+        let mut streams = self.streams.lock().await;
+        expire(&mut streams);
+        if streams.len() >= MAX_STREAMS {
+            // the one that's gone the longest without being read is the least likely to be missed
+            let idlest = streams
+                .iter()
+                .filter_map(|(id, resumable)| {
+                    let last_read = resumable.stream.try_lock().ok()?.last_read;
+
+                    Some((id.clone(), last_read))
+                })
+                .min_by_key(|(_, last_read)| *last_read);
+            let (id, _) = idlest?;
+            streams.remove(&id);
+        }
+
+        let id = stream_id();
+        let stream = Arc::new(Mutex::new(Buffered {
+            text: String::new(),
+            end: None,
+            source,
+            last_read: Instant::now(),
+        }));
+        let resumable = Resumable {
+            owner,
+            stream: stream.clone(),
+        };
+        streams.insert(id.clone(), resumable);
+
+        Some((id, stream))
+    }
+
+    async fn get(&self, id: &str, owner: Option<&str>) -> Option<Arc<Mutex<Buffered>>> {
+        let mut streams = self.streams.lock().await;
+        expire(&mut streams);
+
+        streams
+            .get(id)
+            .filter(|resumable| resumable.owner.as_deref() == owner)
+            .map(|resumable| resumable.stream.clone())
+    }
+}
+
+/// Runs for as long as the service does, so abandoned streams stop generating whether or not
+/// anything else is streamed.
+pub async fn sweep(streams: Arc<Streams>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        expire(&mut *streams.streams.lock().await);
+    }
+}
+
+/// Drops the streams nobody has read from for [`RESUMABLE_FOR`], or [`FINISHED_RESUMABLE_FOR`]
+/// once they're over, so they can't be resumed any more and Bedrock's connection goes with them.
+fn expire(streams: &mut HashMap<String, Resumable>) {
+    // anything that's being read from right now is clearly still wanted
+    streams.retain(|_, resumable| {
+        resumable.stream.try_lock().map_or(true, |stream| {
+            let resumable_for = match stream.end {
+                Some(_) => FINISHED_RESUMABLE_FOR,
+                None => RESUMABLE_FOR,
+            };
+
+            stream.last_read.elapsed() < resumable_for
+        })
+    });
+}
+
+/// Hard to guess, as the id is all it takes to find the stream.
+fn stream_id() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// A stream's events from `from` bytes into its text, reading more from Bedrock once what has
+/// already been sent runs out.
+fn replay(stream: Arc<Mutex<Buffered>>, from: usize) -> impl Stream<Item = StreamEvent> {
+    stream::unfold(Some((stream, from)), |state| async move {
+        let (stream, sent) = state?;
+        let mut buffered = stream.lock().await;
+        buffered.last_read = Instant::now();
+
+        if sent < buffered.text.len() {
+            let text = buffered.text[sent..].to_string();
+            let sent = buffered.text.len();
+            drop(buffered);
+
+            return Some((StreamEvent::Delta(text), Some((stream, sent))));
+        }
+        if let Some(end) = buffered.end.clone() {
+            return Some((end, None));
+        }
+
+        match buffered.source.next().await? {
+            StreamEvent::Delta(text) => {
+                buffered.text.push_str(&text);
+                let sent = buffered.text.len();
+                drop(buffered);
+
+                Some((StreamEvent::Delta(text), Some((stream, sent))))
+            }
+            end => {
+                buffered.end = Some(end.clone());
+
+                Some((end, None))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_after_a_stop_sequence_isnt_sent() {
+        let mut stop_sequences = StopSequences::new(vec!["STOP".to_string()]);
+
+        assert_eq!(
+            stop_sequences.scan("one STOP two"),
+            ("one ".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn stop_sequences_split_across_deltas_are_found() {
+        let mut stop_sequences = StopSequences::new(vec!["STOP".to_string()]);
+
+        assert_eq!(stop_sequences.scan("one ST"), ("one ".to_string(), false));
+        assert_eq!(stop_sequences.scan("OP two"), (String::new(), true));
+    }
+
+    #[test]
+    fn held_back_text_is_sent_once_it_isnt_a_stop_sequence() {
+        let mut stop_sequences = StopSequences::new(vec!["STOP".to_string()]);
+
+        assert_eq!(stop_sequences.scan("ST"), (String::new(), false));
+        assert_eq!(stop_sequences.scan("AR"), ("STAR".to_string(), false));
+        assert_eq!(stop_sequences.scan("S"), (String::new(), false));
+        assert_eq!(stop_sequences.flush(), "S");
+    }
+
+    #[test]
+    fn sentences_end_at_punctuation_before_whitespace() {
+        assert_eq!(Chunking::Sentence.boundary("One. Two! Three"), Some(9));
+        assert_eq!(Chunking::Sentence.boundary("version 1.5 is"), None);
+        // it might carry on, eg. "..."
+        assert_eq!(Chunking::Sentence.boundary("The end."), None);
+        assert_eq!(Chunking::Sentence.boundary("a list\n- item"), Some(7));
+    }
+
+    #[test]
+    fn paragraphs_end_at_blank_lines() {
+        assert_eq!(
+            Chunking::Paragraph.boundary("one\n\ntwo\n\nthree"),
+            Some(10)
+        );
+        assert_eq!(Chunking::Paragraph.boundary("one\ntwo"), None);
+    }
+
+    #[test]
+    fn tokens_are_sent_as_they_are() {
+        assert_eq!(Chunking::Token.boundary("anything"), Some(8));
+    }
+}
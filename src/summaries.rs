@@ -0,0 +1,181 @@
+//! Rolling summaries of long conversations. Once enough turns have built up, the older ones are
+//! folded into a summary by a cheap model, and only the summary is sent to the model after that.
+
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    chat::{self, ChatMessage, Role},
+    complete,
+    error::ApiError,
+    AppState, PreparedPrompt,
+};
+
+const INSTRUCTIONS: &str = "Summarize the conversation below in a few short paragraphs. Keep \
+every fact, name, number, preference and decision that could come up again, and leave out \
+pleasantries. Only reply with the summary.";
+
+#[derive(FromRow)]
+struct StoredMessage {
+    id: i64,
+    #[sqlx(flatten)]
+    message: ChatMessage,
+}
+
+/// Summarizes the conversation in the background if it needs it, so the reply isn't held up.
+pub fn spawn(state: AppState, id: Uuid) {
+    if state.chat.summarize_after.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = summarize(&state, id).await {
+            println!("Couldn't summarize conversation {id}: {err:?}");
+        }
+    });
+}
+
+async fn summarize(state: &AppState, id: Uuid) -> Result<(), ApiError> {
+    let Some(summarize_after) = state.chat.summarize_after else {
+        return Ok(());
+    };
+    let (summary, summarized_through): (Option<String>, i64) =
+        sqlx::query_as("SELECT summary, summarized_through FROM conversations WHERE id = $1")
+            .bind(id)
+            .fetch_one(&state.db)
+            .await?;
+    let messages: Vec<StoredMessage> = sqlx::query_as(
+        "SELECT id, role, content FROM messages WHERE conversation_id = $1 AND id > $2 \
+         ORDER BY id",
+    )
+    .bind(id)
+    .bind(summarized_through)
+    .fetch_all(&state.db)
+    .await?;
+    // each turn is a message from the user and the reply to it
+    let turns = messages
+        .iter()
+        .filter(|stored| stored.message.role != Role::System)
+        .count();
+    if turns < summarize_after * 2 {
+        return Ok(());
+    }
+
+    let folded = folded(&messages, state.chat.summary_keep_turns);
+    let Some(last) = folded.last() else {
+        return Ok(());
+    };
+    let Some(model) = state.model(state.chat.summary_model_id.as_deref()) else {
+        println!("The summary model is denied, not summarizing conversation {id}");
+        return Ok(());
+    };
+
+    let mut prompt = String::new();
+    if let Some(summary) = summary {
+        prompt.push_str(&format!(
+            "Summary of the conversation before this: {summary}\n\n"
+        ));
+    }
+    let folded: Vec<ChatMessage> = folded.iter().map(|stored| stored.message.clone()).collect();
+    prompt.push_str(&chat::written_out(&folded));
+    let prepared = PreparedPrompt {
+        model,
+        logprobs: false,
+        prompt,
+        system: Some(INSTRUCTIONS.to_string()),
+        images: Vec::new(),
+        params: model.defaults.clone(),
+        history: Vec::new(),
+    };
+    let completion = complete(state, &prepared, &prepared.params).await?;
+
+    // another reply might have summarized the conversation in the meantime
+    sqlx::query(
+        "UPDATE conversations SET summary = $2, summarized_through = $3 \
+         WHERE id = $1 AND summarized_through = $4",
+    )
+    .bind(id)
+    .bind(completion.text.trim())
+    .bind(last.id)
+    .bind(summarized_through)
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+/// The messages before the last `keep_turns` turns, ending with a reply so that what's left
+/// still starts with the user like the Converse API wants.
+fn folded(messages: &[StoredMessage], keep_turns: usize) -> &[StoredMessage] {
+    let mut kept = 0;
+    let mut end = messages.len();
+    while kept < keep_turns * 2 && end > 0 {
+        end -= 1;
+        if messages[end].message.role != Role::System {
+            kept += 1;
+        }
+    }
+    let end = messages[..end]
+        .iter()
+        .rposition(|stored| stored.message.role == Role::Assistant)
+        .map_or(0, |last| last + 1);
+
+    &messages[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(roles: &[Role]) -> Vec<StoredMessage> {
+        roles
+            .iter()
+            .enumerate()
+            .map(|(id, role)| StoredMessage {
+                id: id as i64 + 1,
+                message: ChatMessage {
+                    role: *role,
+                    content: String::new(),
+                },
+            })
+            .collect()
+    }
+
+    fn ids(messages: &[StoredMessage]) -> Vec<i64> {
+        messages.iter().map(|stored| stored.id).collect()
+    }
+
+    #[test]
+    fn the_last_turns_are_kept() {
+        use Role::*;
+        let messages = stored(&[User, Assistant, User, Assistant, User, Assistant]);
+
+        assert_eq!(ids(folded(&messages, 2)), [1, 2]);
+    }
+
+    #[test]
+    fn system_messages_arent_counted_as_turns() {
+        use Role::*;
+        let messages = stored(&[User, Assistant, User, System, Assistant, User, Assistant]);
+
+        assert_eq!(ids(folded(&messages, 1)), [1, 2, 3, 4, 5]);
+        assert_eq!(ids(folded(&messages, 2)), [1, 2]);
+    }
+
+    #[test]
+    fn the_fold_ends_on_a_reply() {
+        use Role::*;
+        // the user's unanswered message would otherwise be folded on its own
+        let messages = stored(&[User, Assistant, User, Assistant, User]);
+
+        assert_eq!(ids(folded(&messages, 1)), [1, 2]);
+    }
+
+    #[test]
+    fn nothing_is_folded_without_a_reply() {
+        use Role::*;
+        let messages = stored(&[System, User]);
+
+        assert!(folded(&messages, 0).is_empty());
+    }
+}
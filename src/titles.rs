@@ -0,0 +1,87 @@
+//! Titles for conversations that weren't given one, written by a cheap model after the first
+//! reply so a history sidebar has something better to show than an id.
+
+use uuid::Uuid;
+
+use crate::{
+    chat::{self, ChatMessage},
+    complete,
+    error::ApiError,
+    AppState, PreparedPrompt,
+};
+
+const INSTRUCTIONS: &str = "Write a title of at most six words for the conversation below. \
+Only reply with the title, without quotes or punctuation at the end.";
+const MAX_TITLE_TOKENS: i32 = 32;
+const MAX_TITLE_LENGTH: usize = 100;
+
+/// Titles the conversation in the background if it doesn't have a title yet.
+pub fn spawn(state: AppState, id: Uuid) {
+    if !state.chat.auto_titles {
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = title(&state, id).await {
+            println!("Couldn't title conversation {id}: {err:?}");
+        }
+    });
+}
+
+async fn title(state: &AppState, id: Uuid) -> Result<(), ApiError> {
+    let untitled: bool =
+        sqlx::query_scalar("SELECT title IS NULL FROM conversations WHERE id = $1")
+            .bind(id)
+            .fetch_one(&state.db)
+            .await?;
+    if !untitled {
+        return Ok(());
+    }
+
+    // the first exchange says what the conversation is about well enough
+    let messages: Vec<ChatMessage> = sqlx::query_as(
+        "SELECT role, content FROM messages WHERE conversation_id = $1 AND role <> 'system' \
+         ORDER BY id LIMIT 2",
+    )
+    .bind(id)
+    .fetch_all(&state.db)
+    .await?;
+    let Some(model) = state.model(state.chat.summary_model_id.as_deref()) else {
+        println!("The summary model is denied, not titling conversation {id}");
+        return Ok(());
+    };
+
+    let mut params = model.defaults.clone();
+    params.max_tokens = Some(MAX_TITLE_TOKENS);
+    let prepared = PreparedPrompt {
+        model,
+        logprobs: false,
+        prompt: chat::written_out(&messages),
+        system: Some(INSTRUCTIONS.to_string()),
+        images: Vec::new(),
+        params,
+        history: Vec::new(),
+    };
+    let completion = complete(state, &prepared, &prepared.params).await?;
+    let title: String = completion
+        .text
+        .lines()
+        .map(|line| line.trim().trim_matches(['"', '\'', '.']))
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .chars()
+        .take(MAX_TITLE_LENGTH)
+        .collect();
+    if title.is_empty() {
+        return Ok(());
+    }
+
+    // someone might have renamed it in the meantime
+    sqlx::query("UPDATE conversations SET title = $2 WHERE id = $1 AND title IS NULL")
+        .bind(id)
+        .bind(title)
+        .execute(&state.db)
+        .await?;
+
+    Ok(())
+}
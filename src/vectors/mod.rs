@@ -0,0 +1,447 @@
+//! Somewhere to keep embeddings so they can be searched later, rather than handing them back to
+//! the client and forgetting about them.
+
+use std::{collections::HashMap, fmt::Display};
+
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use crate::{
+    conversations,
+    error::{ApiError, Violation},
+    AppState,
+};
+
+pub mod pgvector;
+pub mod qdrant;
+
+pub const DEFAULT_COLLECTION: &str = "embeddings";
+/// Every point's payload says whose it is, so one deployment can keep several customers'
+/// embeddings apart. See [`tenant`].
+pub const TENANT_FIELD: &str = "tenant";
+/// The id the client upserted a point with, as it's stored under one of its tenant's own. See
+/// [`stored_id`].
+const POINT_ID_FIELD: &str = "point_id";
+
+/// The most points one request can upsert, or one query can return.
+const MAX_POINTS: usize = 1000;
+const DEFAULT_QUERY_LIMIT: usize = 10;
+
+/// Like [`crate::embeddings::EmbeddingProvider`], for each database embeddings can be kept in.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// How long every vector has to be, if the store can't take whatever it's given.
+    fn dimensions(&self) -> Option<usize> {
+        None
+    }
+
+    /// Replaces any points that already have the same ids.
+    async fn upsert(&self, points: Vec<Point>) -> Result<(), StoreError>;
+
+    /// The closest points to `vector` whose payloads match the filter, closest first.
+    async fn query(
+        &self,
+        vector: Vec<f32>,
+        limit: usize,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredPoint>, StoreError>;
+
+    async fn delete(&self, filter: &Filter) -> Result<(), StoreError>;
+
+    /// The vectors of whichever of these points exist, so they can be used again without
+    /// embedding their text again.
+    async fn vectors(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<f32>>, StoreError>;
+}
+
+/// Payload fields and what they must be, eg. `{"user_id": "alice"}`. A point has to match every
+/// condition, and an empty filter matches everything.
+pub type Filter = HashMap<String, Condition>;
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Condition {
+    /// Exactly this value.
+    Equals(String),
+    /// Any one of these, eg. `["faq", "guide"]` for a document type.
+    AnyOf(Vec<String>),
+    /// eg. `{"gte": "2024-01-01", "lt": "2025-01-01"}` or `{"gt": 3}`.
+    Range(Range),
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Range {
+    pub gt: Option<Bound>,
+    pub gte: Option<Bound>,
+    pub lt: Option<Bound>,
+    pub lte: Option<Bound>,
+}
+
+/// Numbers compare as numbers, and strings as dates, which can be RFC 3339 timestamps or just
+/// `YYYY-MM-DD`. Dates are compared as they're written in Postgres, so they should be written
+/// the same way as they are in the metadata.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Bound {
+    Number(f64),
+    Date(String),
+}
+
+impl Range {
+    /// Each bound along with its operator, eg. `(">=", 2020)` for `gte`.
+    pub fn bounds(&self) -> Vec<(&'static str, &Bound)> {
+        [
+            (">", &self.gt),
+            (">=", &self.gte),
+            ("<", &self.lt),
+            ("<=", &self.lte),
+        ]
+        .into_iter()
+        .filter_map(|(operator, bound)| Some((operator, bound.as_ref()?)))
+        .collect()
+    }
+
+    pub fn is_dates(&self) -> bool {
+        self.bounds()
+            .iter()
+            .all(|(_, bound)| matches!(bound, Bound::Date(_)))
+    }
+}
+
+impl Bound {
+    pub fn number(&self) -> Option<f64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            Self::Date(_) => None,
+        }
+    }
+
+    /// Midnight UTC for a date without a time.
+    pub fn date(&self) -> Option<DateTime<Utc>> {
+        let Self::Date(date) = self else {
+            return None;
+        };
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(date) {
+            return Some(timestamp.to_utc());
+        }
+
+        Some(
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)?
+                .and_utc(),
+        )
+    }
+}
+
+/// For the `filter` of a request, before it's sent anywhere.
+pub fn filter_violations(filter: &Filter) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for (field, condition) in filter {
+        match condition {
+            Condition::Equals(_) => {}
+            Condition::AnyOf(values) if values.is_empty() => {
+                violations.push(Violation::new(
+                    "filter",
+                    format!("{field} must have at least one value"),
+                ));
+            }
+            Condition::AnyOf(_) => {}
+            Condition::Range(range) => {
+                let bounds = range.bounds();
+                if bounds.is_empty() {
+                    violations.push(Violation::new(
+                        "filter",
+                        format!("{field} must have at least one of gt, gte, lt and lte"),
+                    ));
+                } else if !range.is_dates()
+                    && !bounds.iter().all(|(_, bound)| bound.number().is_some())
+                {
+                    violations.push(Violation::new(
+                        "filter",
+                        format!("{field} can't compare numbers and dates at once"),
+                    ));
+                } else if range.is_dates() && bounds.iter().any(|(_, bound)| bound.date().is_none())
+                {
+                    violations.push(Violation::new(
+                        "filter",
+                        format!("{field} must have dates in RFC 3339 or YYYY-MM-DD"),
+                    ));
+                }
+            }
+        }
+    }
+
+    violations
+}
+
+#[derive(Deserialize)]
+pub struct Point {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    pub vector: Vec<f32>,
+    /// Whatever should come back with the point, eg. the text it's an embedding of.
+    #[serde(default)]
+    pub payload: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+pub struct ScoredPoint {
+    pub id: Uuid,
+    /// Higher is closer.
+    pub score: f32,
+    pub payload: Map<String, Value>,
+}
+
+/// Whatever went wrong talking to the store, which is only logged.
+#[derive(Debug)]
+pub struct StoreError(pub String);
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The caller's namespace, which is a hash of their API key like a conversation's owner. It's
+/// empty for requests without a key, which share one namespace.
+pub fn tenant(headers: &HeaderMap) -> String {
+    conversations::owner(headers).unwrap_or_default()
+}
+
+/// The id a client's point is kept under, which is only the same for the same tenant, so nobody
+/// can replace someone else's point by upserting one with its id.
+fn stored_id(tenant: &str, id: Uuid) -> Uuid {
+    Uuid::new_v5(&id, tenant.as_bytes())
+}
+
+/// Narrows a filter down to the tenant's own points, whichever tenant the filter asked for.
+pub fn scoped(mut filter: Filter, tenant: &str) -> Filter {
+    filter.insert(
+        TENANT_FIELD.to_string(),
+        Condition::Equals(tenant.to_string()),
+    );
+    filter
+}
+
+#[derive(Deserialize)]
+pub struct UpsertRequest {
+    points: Vec<Point>,
+}
+
+/// Stores embeddings the client already has, eg. from `/embeddings`, in the caller's namespace.
+pub async fn upsert(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(UpsertRequest { mut points }): Json<UpsertRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut violations = Vec::new();
+    if points.is_empty() {
+        violations.push(Violation::new("points", "must not be empty"));
+    }
+    if points.len() > MAX_POINTS {
+        violations.push(Violation::new(
+            "points",
+            format!("must have at most {MAX_POINTS} points"),
+        ));
+    }
+    // the collection is made for the first vector's dimensions, which the rest have to match
+    if points
+        .windows(2)
+        .any(|pair| pair[0].vector.len() != pair[1].vector.len())
+    {
+        violations.push(Violation::new(
+            "points",
+            "must all have vectors of the same length",
+        ));
+    }
+    if points.iter().any(|point| point.vector.is_empty()) {
+        violations.push(Violation::new("points", "must not have empty vectors"));
+    }
+    if let Some(dimensions) = state.vectors.dimensions() {
+        if points.iter().any(|point| point.vector.len() != dimensions) {
+            violations.push(Violation::new(
+                "points",
+                format!("must have vectors with {dimensions} dimensions"),
+            ));
+        }
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let tenant = tenant(&headers);
+    for point in &mut points {
+        point
+            .payload
+            .insert(TENANT_FIELD.to_string(), tenant.as_str().into());
+        point
+            .payload
+            .insert(POINT_ID_FIELD.to_string(), point.id.to_string().into());
+        point.id = stored_id(&tenant, point.id);
+    }
+    let upserted = points.len();
+    state.vectors.upsert(points).await?;
+
+    Ok(Json(json!({ "upserted": upserted })))
+}
+
+#[derive(Deserialize)]
+pub struct QueryRequest {
+    vector: Vec<f32>,
+    limit: Option<usize>,
+    #[serde(default)]
+    filter: Filter,
+}
+
+/// The stored points closest to a vector, out of the caller's own.
+pub async fn query(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(QueryRequest {
+        vector,
+        limit,
+        filter,
+    }): Json<QueryRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    let mut violations = Vec::new();
+    if vector.is_empty() {
+        violations.push(Violation::new("vector", "must not be empty"));
+    }
+    if limit == 0 || limit > MAX_POINTS {
+        violations.push(Violation::new(
+            "limit",
+            format!("must be between 1 and {MAX_POINTS}"),
+        ));
+    }
+    violations.extend(filter_violations(&filter));
+    if let Some(dimensions) = state.vectors.dimensions() {
+        if vector.len() != dimensions {
+            violations.push(Violation::new(
+                "vector",
+                format!("must have {dimensions} dimensions"),
+            ));
+        }
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let filter = scoped(filter, &tenant(&headers));
+    let mut matches = state.vectors.query(vector, limit, &filter).await?;
+    for point in &mut matches {
+        point.payload.remove(TENANT_FIELD);
+        // document chunks are stored under their own ids
+        let id = point.payload.remove(POINT_ID_FIELD);
+        if let Some(id) = id.as_ref().and_then(Value::as_str) {
+            point.id = Uuid::parse_str(id).unwrap_or(point.id);
+        }
+    }
+
+    Ok(Json(json!({ "matches": matches })))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    filter: Filter,
+}
+
+/// Deletes every one of the caller's points matching the filter, which can't be empty so nobody
+/// deletes the lot by accident.
+pub async fn delete(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(DeleteRequest { filter }): Json<DeleteRequest>,
+) -> Result<StatusCode, ApiError> {
+    if filter.is_empty() {
+        return Err(ApiError::Invalid(vec![Violation::new(
+            "filter",
+            "must not be empty",
+        )]));
+    }
+    let violations = filter_violations(&filter);
+    if !violations.is_empty() {
+        return Err(ApiError::Invalid(violations));
+    }
+
+    let filter = scoped(filter, &tenant(&headers));
+    state.vectors.delete(&filter).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(filter: Value) -> Vec<String> {
+        let filter: Filter = serde_json::from_value(filter).unwrap();
+
+        filter_violations(&filter)
+            .into_iter()
+            .map(|violation| violation.message)
+            .collect()
+    }
+
+    #[test]
+    fn valid_filters_are_fine() {
+        let filter = json!({
+            "type": "faq",
+            "tags": ["billing", "plans"],
+            "year": {"gte": 2020, "lt": 2025},
+            "published": {"gte": "2024-01-01", "lt": "2024-06-01T00:00:00Z"},
+        });
+
+        assert!(violations(filter).is_empty());
+    }
+
+    #[test]
+    fn any_of_needs_a_value() {
+        assert_eq!(
+            violations(json!({"tags": []})),
+            ["tags must have at least one value"]
+        );
+    }
+
+    #[test]
+    fn ranges_need_a_bound() {
+        assert_eq!(
+            violations(json!({"year": {}})),
+            ["year must have at least one of gt, gte, lt and lte"]
+        );
+    }
+
+    #[test]
+    fn ranges_cant_mix_numbers_and_dates() {
+        assert_eq!(
+            violations(json!({"year": {"gte": 2020, "lt": "2025-01-01"}})),
+            ["year can't compare numbers and dates at once"]
+        );
+    }
+
+    #[test]
+    fn dates_have_to_be_dates() {
+        assert_eq!(
+            violations(json!({"published": {"gte": "yesterday"}})),
+            ["published must have dates in RFC 3339 or YYYY-MM-DD"]
+        );
+    }
+
+    #[test]
+    fn stored_ids_are_different_for_each_tenant() {
+        let id = Uuid::new_v4();
+
+        assert_eq!(stored_id("a", id), stored_id("a", id));
+        assert_ne!(stored_id("a", id), stored_id("b", id));
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use sqlx::{types::Json, PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use super::{Bound, Condition, Filter, Point, ScoredPoint, StoreError, VectorStore};
+
+/// Fixed by the column type in the migration, as the HNSW index needs to know.
+pub const DIMENSIONS: usize = 1024;
+
+/// Keeps points in the `vectors` table of the database we already have.
+pub struct PgVectorStore {
+    db: PgPool,
+    collection: String,
+}
+
+impl PgVectorStore {
+    pub fn new(db: PgPool, collection: String) -> Self {
+        Self { db, collection }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Row {
+    id: Uuid,
+    score: f64,
+    payload: Json<Map<String, Value>>,
+}
+
+#[async_trait]
+impl VectorStore for PgVectorStore {
+    fn dimensions(&self) -> Option<usize> {
+        Some(DIMENSIONS)
+    }
+
+    async fn upsert(&self, points: Vec<Point>) -> Result<(), StoreError> {
+        let mut tx = self.db.begin().await?;
+        for point in points {
+            // another tenant's point with the same id is left alone
+            sqlx::query(
+                "INSERT INTO vectors (collection, id, embedding, payload) \
+                 VALUES ($1, $2, $3::vector, $4) \
+                 ON CONFLICT (collection, id) \
+                 DO UPDATE SET embedding = EXCLUDED.embedding, payload = EXCLUDED.payload \
+                 WHERE COALESCE(vectors.payload->>'tenant', '') = EXCLUDED.payload->>'tenant'",
+            )
+            .bind(&self.collection)
+            .bind(point.id)
+            .bind(literal(&point.vector))
+            .bind(Json(point.payload))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        vector: Vec<f32>,
+        limit: usize,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredPoint>, StoreError> {
+        // cosine distance is 0 for the same direction, scores are the other way round
+        let mut query = QueryBuilder::new("SELECT id, 1 - (embedding <=> ");
+        query
+            .push_bind(literal(&vector))
+            .push("::vector) AS score, payload FROM vectors WHERE collection = ")
+            .push_bind(&self.collection);
+        push_filter(&mut query, filter);
+        query
+            .push(" ORDER BY embedding <=> ")
+            .push_bind(literal(&vector))
+            .push("::vector LIMIT ")
+            .push_bind(limit as i64);
+        let rows: Vec<Row> = query.build_query_as().fetch_all(&self.db).await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ScoredPoint {
+                id: row.id,
+                score: row.score as f32,
+                payload: row.payload.0,
+            })
+            .collect())
+    }
+
+    async fn delete(&self, filter: &Filter) -> Result<(), StoreError> {
+        let mut query = QueryBuilder::new("DELETE FROM vectors WHERE collection = ");
+        query.push_bind(&self.collection);
+        push_filter(&mut query, filter);
+        query.build().execute(&self.db).await?;
+
+        Ok(())
+    }
+
+    async fn vectors(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<f32>>, StoreError> {
+        let rows: Vec<(Uuid, String)> = sqlx::query_as(
+            "SELECT id, embedding::text FROM vectors WHERE collection = $1 AND id = ANY($2)",
+        )
+        .bind(&self.collection)
+        .bind(ids)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(id, embedding)| Some((id, parse(&embedding)?)))
+            .collect())
+    }
+}
+
+/// Adds an ` AND ...` to the query for each condition on its `payload` column, which the chunks
+/// table has too.
+pub fn push_filter<'a>(query: &mut QueryBuilder<'a, Postgres>, filter: &'a Filter) {
+    // exact matches can all use the payload's GIN index
+    let equals: Map<String, Value> = filter
+        .iter()
+        .filter_map(|(field, condition)| match condition {
+            Condition::Equals(value) => Some((field.clone(), value.as_str().into())),
+            _ => None,
+        })
+        .collect();
+    if !equals.is_empty() {
+        query.push(" AND payload @> ").push_bind(Json(equals));
+    }
+
+    for (field, condition) in filter {
+        match condition {
+            Condition::Equals(_) => {}
+            Condition::AnyOf(values) => {
+                query
+                    .push(" AND payload ->> ")
+                    .push_bind(field)
+                    .push(" = ANY(")
+                    .push_bind(values)
+                    .push(")");
+            }
+            Condition::Range(range) => {
+                for (operator, bound) in range.bounds() {
+                    match bound {
+                        // anything that isn't a number is NULL, so it never matches
+                        Bound::Number(number) => query
+                            .push(" AND CASE WHEN jsonb_typeof(payload -> ")
+                            .push_bind(field)
+                            .push(") = 'number' THEN (payload ->> ")
+                            .push_bind(field)
+                            .push(")::float8 END ")
+                            .push(operator)
+                            .push_bind(*number),
+                        // byte by byte, so dates written the same way sort by when they are
+                        Bound::Date(date) => query
+                            .push(" AND (payload ->> ")
+                            .push_bind(field)
+                            .push(") COLLATE \"C\" ")
+                            .push(operator)
+                            .push_bind(date),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// pgvector parses vectors from text like `[1,2,3]`, which saves pulling in its crate.
+fn literal(vector: &[f32]) -> String {
+    let values: Vec<String> = vector.iter().map(f32::to_string).collect();
+
+    format!("[{}]", values.join(","))
+}
+
+/// The other way round from [`literal`].
+fn parse(literal: &str) -> Option<Vec<f32>> {
+    literal
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(|value| value.trim().parse().ok())
+        .collect()
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(err: sqlx::Error) -> Self {
+        Self(format!("pgvector: {err}"))
+    }
+}
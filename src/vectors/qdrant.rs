@@ -0,0 +1,223 @@
+// shuttle-qdrant only hands out the older client, which is deprecated but still works fine
+#![allow(deprecated)]
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use qdrant_client::{
+    prelude::QdrantClient,
+    qdrant::{
+        point_id::PointIdOptions, vectors::VectorsOptions, Condition as QdrantCondition,
+        CreateCollection, CreateCollectionBuilder, DatetimeRange, Distance, Filter as QdrantFilter,
+        PointId, PointStruct, PointsSelector, Range as QdrantRange, SearchPoints,
+        SearchPointsBuilder, VectorParamsBuilder,
+    },
+    Payload,
+};
+use serde_json::Map;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use super::{Bound, Condition, Filter, Point, Range, ScoredPoint, StoreError, VectorStore};
+
+/// The client `#[shuttle_qdrant::Qdrant]` gives `main`, named here so the deprecation only has
+/// to be allowed in this module.
+pub type Client = QdrantClient;
+
+/// Keeps every point in one collection, which is made the first time something is upserted as
+/// that's when we find out how long the vectors are.
+pub struct QdrantStore {
+    client: Client,
+    collection: String,
+    created: OnceCell<()>,
+}
+
+impl QdrantStore {
+    pub fn new(client: Client, collection: String) -> Self {
+        Self {
+            client,
+            collection,
+            created: OnceCell::new(),
+        }
+    }
+
+    /// Makes the collection if it doesn't exist yet, for vectors with `dimensions` dimensions.
+    async fn create_collection(&self, dimensions: usize) -> Result<(), StoreError> {
+        self.created
+            .get_or_try_init(|| async {
+                if self.exists().await? {
+                    return Ok(());
+                }
+                let collection: CreateCollection = CreateCollectionBuilder::new(&self.collection)
+                    .vectors_config(VectorParamsBuilder::new(
+                        dimensions as u64,
+                        Distance::Cosine,
+                    ))
+                    .into();
+                self.client
+                    .create_collection(&collection)
+                    .await
+                    .map_err(store_error)?;
+                println!("Created the Qdrant collection {}", self.collection);
+
+                Ok(())
+            })
+            .await
+            .copied()
+    }
+
+    /// Until the collection exists there's nothing in it to query or delete.
+    async fn exists(&self) -> Result<bool, StoreError> {
+        if self.created.initialized() {
+            return Ok(true);
+        }
+
+        self.client
+            .collection_exists(&self.collection)
+            .await
+            .map_err(store_error)
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantStore {
+    async fn upsert(&self, points: Vec<Point>) -> Result<(), StoreError> {
+        let Some(dimensions) = points.first().map(|point| point.vector.len()) else {
+            return Ok(());
+        };
+        self.create_collection(dimensions).await?;
+
+        let points = points
+            .into_iter()
+            .map(|point| PointStruct::new(point.id.to_string(), point.vector, point.payload))
+            .collect();
+        self.client
+            .upsert_points_blocking(&self.collection, None, points, None)
+            .await
+            .map_err(store_error)?;
+
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        vector: Vec<f32>,
+        limit: usize,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredPoint>, StoreError> {
+        if !self.exists().await? {
+            return Ok(Vec::new());
+        }
+
+        let search: SearchPoints = SearchPointsBuilder::new(&self.collection, vector, limit as u64)
+            .filter(qdrant_filter(filter))
+            .with_payload(true)
+            .into();
+        let response = self
+            .client
+            .search_points(&search)
+            .await
+            .map_err(store_error)?;
+
+        Ok(response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                Some(ScoredPoint {
+                    id: uuid(point.id?)?,
+                    score: point.score,
+                    payload: Map::from(Payload::from(point.payload)),
+                })
+            })
+            .collect())
+    }
+
+    async fn delete(&self, filter: &Filter) -> Result<(), StoreError> {
+        if !self.exists().await? {
+            return Ok(());
+        }
+
+        let selector = PointsSelector::from(qdrant_filter(filter));
+        self.client
+            .delete_points_blocking(&self.collection, None, &selector, None)
+            .await
+            .map_err(store_error)?;
+
+        Ok(())
+    }
+
+    async fn vectors(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<f32>>, StoreError> {
+        if ids.is_empty() || !self.exists().await? {
+            return Ok(HashMap::new());
+        }
+
+        let ids: Vec<PointId> = ids.iter().map(|id| id.to_string().into()).collect();
+        let response = self
+            .client
+            .get_points(&self.collection, None, &ids, Some(true), Some(false), None)
+            .await
+            .map_err(store_error)?;
+
+        Ok(response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                let Some(VectorsOptions::Vector(vector)) = point.vectors?.vectors_options else {
+                    return None;
+                };
+
+                Some((uuid(point.id?)?, vector.data))
+            })
+            .collect())
+    }
+}
+
+// we only ever upsert uuids
+fn uuid(id: PointId) -> Option<Uuid> {
+    let Some(PointIdOptions::Uuid(id)) = id.point_id_options else {
+        return None;
+    };
+
+    Uuid::parse_str(&id).ok()
+}
+
+fn qdrant_filter(filter: &Filter) -> QdrantFilter {
+    QdrantFilter::must(filter.iter().map(|(field, condition)| match condition {
+        Condition::Equals(value) => QdrantCondition::matches(field, value.clone()),
+        Condition::AnyOf(values) => QdrantCondition::matches(field, values.clone()),
+        Condition::Range(range) if range.is_dates() => {
+            QdrantCondition::datetime_range(field, datetime_range(range))
+        }
+        Condition::Range(range) => QdrantCondition::range(
+            field,
+            QdrantRange {
+                gt: range.gt.as_ref().and_then(Bound::number),
+                gte: range.gte.as_ref().and_then(Bound::number),
+                lt: range.lt.as_ref().and_then(Bound::number),
+                lte: range.lte.as_ref().and_then(Bound::number),
+            },
+        ),
+    }))
+}
+
+fn datetime_range(range: &Range) -> DatetimeRange {
+    let timestamp = |bound: &Option<Bound>| {
+        let date = bound.as_ref()?.date()?;
+
+        Some(prost_types::Timestamp {
+            seconds: date.timestamp(),
+            nanos: date.timestamp_subsec_nanos() as i32,
+        })
+    };
+
+    DatetimeRange {
+        gt: timestamp(&range.gt),
+        gte: timestamp(&range.gte),
+        lt: timestamp(&range.lt),
+        lte: timestamp(&range.lte),
+    }
+}
+
+fn store_error(err: impl std::fmt::Display) -> StoreError {
+    StoreError(format!("Qdrant: {err}"))
+}